@@ -0,0 +1,512 @@
+use rand::{SeedableRng, StdRng};
+use scalable_cuckoo_filter::{DefaultHasher, ScalableCuckooFilter, ScalableCuckooFilterBuilder};
+use std;
+use std::borrow::Cow;
+use std::hash::Hash;
+use std::time::SystemTime;
+
+use super::summary::FileSummary;
+use super::{IndexOptions, SearchOptions};
+use query::{Filter, Query};
+use tokenize::{self, WordTokenizer};
+use watch::fs::{FileContent, FileType};
+use Error;
+
+/// The maximum length of a line.
+///
+/// Longer lines are indexed as if they were split into multiple lines
+/// (see `first_line_len`).
+pub const MAX_LINE_LEN: usize = 1024 * 1024;
+
+/// The indexing state of a file.
+///
+/// The contents of the file are indexed by the filters of blocks,
+/// each of which covers consecutive lines appended within a limited size and period
+/// (see `IndexOptions::block_size` and `IndexOptions::block_duration`).
+#[derive(Debug)]
+pub struct FileState {
+    options: IndexOptions,
+
+    // The type of the file which the contents are read from (i.e., the archive for its members)
+    file_type: FileType,
+
+    blocks: Vec<Block>,
+    summary: FileSummary,
+    is_summary_changed: bool,
+
+    // The last (incomplete) line and its position
+    buf: Vec<u8>,
+    buf_offset: u64,
+    line_number: u64,
+
+    // The position where the file was first read to the end.
+    // The lines before it existed before the file was watched, so they are not notified as new.
+    live_offset: Option<u64>,
+
+    // The reason why the rest of the file could not be read
+    error: Option<Error>,
+
+    is_binary: bool,
+}
+impl FileState {
+    pub fn new(options: IndexOptions, file_type: FileType) -> Self {
+        FileState {
+            options,
+            file_type,
+            blocks: Vec::new(),
+            summary: FileSummary::new(),
+            is_summary_changed: false,
+            buf: Vec::new(),
+            buf_offset: 0,
+            line_number: 1,
+            live_offset: None,
+            error: None,
+            is_binary: false,
+        }
+    }
+    pub fn options(&self) -> &IndexOptions {
+        &self.options
+    }
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Returns the number of bytes read from the file so far.
+    pub fn indexed_bytes(&self) -> u64 {
+        self.buf_offset + self.buf.len() as u64
+    }
+
+    pub fn summary(&self) -> &FileSummary {
+        &self.summary
+    }
+
+    /// Returns `true` if the file has been read to the end at least once.
+    pub fn is_eof_reached(&self) -> bool {
+        self.live_offset.is_some()
+    }
+
+    /// Returns the reason why the rest of the file could not be read (see `FileContent::error`).
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
+    }
+
+    /// Returns `true` if the summary has been changed since the last call of this method.
+    pub fn take_summary_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.is_summary_changed, false)
+    }
+
+    /// Returns the approximate memory usage of the filters in bytes.
+    pub fn filter_bytes(&self) -> u64 {
+        let bits: u64 = self.blocks
+            .iter()
+            .map(|b| b.words.bits() + b.trigrams.as_ref().map_or(0, |t| t.bits()))
+            .sum();
+        bits / 8
+    }
+    /// Indexes the given content.
+    ///
+    /// If the content is marked as `reset`, the index built so far is discarded first.
+    ///
+    /// `on_line` is invoked with the offset, line number and bytes of each complete line
+    /// appended after the file was first read to the end.
+    /// The lines read again after a reset (e.g., a truncation) are regarded as appended.
+    pub fn update<F>(&mut self, content: FileContent, on_line: F)
+    where
+        F: FnMut(u64, u64, &[u8]),
+    {
+        self.update_at(content, SystemTime::now(), on_line)
+    }
+    fn update_at<F>(&mut self, content: FileContent, now: SystemTime, mut on_line: F)
+    where
+        F: FnMut(u64, u64, &[u8]),
+    {
+        if content.reset {
+            let is_summary_changed = !self.summary.is_empty();
+            let live_offset = self.live_offset.map(|_| 0);
+            *self = FileState::new(self.options.clone(), self.file_type);
+            self.is_summary_changed = is_summary_changed;
+            self.live_offset = live_offset;
+        }
+        // Compressed files are read again when they are modified (e.g., still being written),
+        // and later contents without errors mean that the broken parts have become readable
+        self.error = content.error;
+        if self.is_binary {
+            return;
+        }
+        let live_offset = self.live_offset;
+        let is_appended = |offset: u64, line: &[u8]| {
+            live_offset.is_some_and(|live_offset| offset + line.len() as u64 > live_offset)
+        };
+
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.extend(content.data);
+
+        let mut line_start = 0;
+        while let Some(n) = first_line_len(&buf[line_start..]) {
+            let line_end = line_start + n;
+            let offset = self.buf_offset + line_start as u64;
+            let line = &buf[line_start..line_end];
+            self.index_line(offset, line, now);
+            if is_appended(offset, line) {
+                on_line(offset, self.line_number, line);
+            }
+            self.line_number += 1;
+            line_start = line_end;
+        }
+        for _ in buf.drain(..line_start) {}
+        self.buf_offset += line_start as u64;
+
+        if content.eof && !buf.is_empty() {
+            // The last line may be continued by later appends, so it will be indexed again then
+            self.index_line(self.buf_offset, &buf, now);
+        }
+
+        if self.is_binary {
+            self.buf = Vec::new();
+        } else {
+            self.buf = buf;
+        }
+        if content.eof && self.live_offset.is_none() {
+            self.live_offset = Some(self.indexed_bytes());
+        }
+    }
+
+    /// Returns the regions of the file which may contain lines satisfying `query`.
+    ///
+    /// If the file is indexed ignoring case, `query` must be case-folded.
+    ///
+    /// Adjacent regions are merged.
+    pub fn candidate_regions(&self, query: &Query, options: &SearchOptions) -> Vec<Region> {
+        // The filters of case-sensitive indices can not be used for case-insensitive searches
+        let is_prunable = self.options.case_insensitive || !options.ignore_case;
+
+        let mut regions: Vec<Region> = Vec::new();
+        for (i, block) in self.blocks.iter().enumerate() {
+            if options.since.is_some_and(|since| block.last_arrival < since)
+                || options.until.is_some_and(|until| block.first_arrival > until)
+            {
+                continue;
+            }
+            let view = BlockView {
+                options: &self.options,
+                block,
+            };
+            if is_prunable && !query.may_match(&view) {
+                continue;
+            }
+
+            let end = self.blocks.get(i + 1).map(|b| b.start);
+            if let Some(last) = regions.last_mut() {
+                if last.end == Some(block.start) {
+                    last.end = end;
+                    continue;
+                }
+            }
+            regions.push(Region {
+                start: block.start,
+                end,
+                start_line: block.start_line,
+            });
+        }
+        regions
+    }
+
+    fn index_line(&mut self, offset: u64, line: &[u8], now: SystemTime) {
+        let needs_new_block = self.blocks.last().is_none_or(|b| {
+            let elapsed = now.duration_since(b.first_arrival).unwrap_or_default();
+            b.start != offset
+                && (offset - b.start >= self.options.block_size
+                    || elapsed >= self.options.block_duration)
+        });
+        if needs_new_block {
+            let block = Block::new(offset, self.line_number, now, &self.options);
+            self.blocks.push(block);
+        }
+
+        let options = &self.options;
+        let block = self.blocks.last_mut().expect("Never fails");
+        block.last_arrival = now;
+        let mut prev_word: Option<Cow<str>> = None;
+        for w in WordTokenizer::new(line) {
+            let w = match w {
+                Err(_) => {
+                    self.is_binary = true;
+                    return;
+                }
+                Ok((_, w)) => w,
+            };
+            let folded = tokenize::fold_case(w);
+            self.is_summary_changed |= self.summary.insert(&folded);
+            let w = if options.case_insensitive {
+                Cow::Owned(folded)
+            } else {
+                Cow::Borrowed(w)
+            };
+            block.words.insert(&w);
+            if options.bigrams {
+                if let Some(prev) = prev_word.take() {
+                    block.words.insert(&bigram(&prev, &w));
+                }
+                prev_word = Some(w);
+            }
+        }
+        if let Some(ref mut trigrams) = block.trigrams {
+            let line = if options.case_insensitive {
+                Cow::Owned(tokenize::fold_case(&String::from_utf8_lossy(line)).into_bytes())
+            } else {
+                Cow::Borrowed(line)
+            };
+            for trigram in line.windows(3) {
+                if !trigram.contains(&b'\n') {
+                    trigrams.insert(trigram);
+                }
+            }
+        }
+    }
+}
+
+/// A region of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    /// The offset of the first line in the region.
+    pub start: u64,
+
+    /// The offset of the end of the region (`None` means the end of the file).
+    ///
+    /// The last line in the region may extend beyond this position.
+    pub end: Option<u64>,
+
+    /// The line number of the first line in the region.
+    pub start_line: u64,
+}
+
+#[derive(Debug)]
+struct Block {
+    start: u64,
+    start_line: u64,
+    first_arrival: SystemTime,
+    last_arrival: SystemTime,
+    words: ScalableCuckooFilter<str, DefaultHasher, StdRng>,
+    trigrams: Option<ScalableCuckooFilter<[u8], DefaultHasher, StdRng>>,
+}
+impl Block {
+    fn new(start: u64, start_line: u64, now: SystemTime, options: &IndexOptions) -> Self {
+        let trigrams = if options.trigrams {
+            Some(new_cuckoo_filter())
+        } else {
+            None
+        };
+        Block {
+            start,
+            start_line,
+            first_arrival: now,
+            last_arrival: now,
+            words: new_cuckoo_filter(),
+            trigrams,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BlockView<'a> {
+    options: &'a IndexOptions,
+    block: &'a Block,
+}
+impl<'a> Filter for BlockView<'a> {
+    fn contains_word(&self, word: &str) -> bool {
+        self.block.words.contains(word)
+    }
+    fn contains_bigram(&self, first: &str, second: &str) -> bool {
+        !self.options.bigrams || self.block.words.contains(&bigram(first, second))
+    }
+    fn contains_trigram(&self, trigram: &[u8]) -> bool {
+        self.block
+            .trigrams
+            .as_ref()
+            .is_none_or(|trigrams| trigrams.contains(trigram))
+    }
+}
+
+/// Returns the length of the first line in `buf` (including the newline) if it is complete.
+///
+/// A line longer than `MAX_LINE_LEN` is split into pieces of that length,
+/// each of which is regarded as a line. The files are read in the same way by `verify`,
+/// so that the line numbers agree.
+pub fn first_line_len(buf: &[u8]) -> Option<usize> {
+    let head = &buf[..buf.len().min(MAX_LINE_LEN)];
+    match head.iter().position(|&b| b == b'\n') {
+        Some(n) => Some(n + 1),
+        None if head.len() == MAX_LINE_LEN => Some(MAX_LINE_LEN),
+        None => None,
+    }
+}
+
+fn new_cuckoo_filter<T: Hash + ?Sized>() -> ScalableCuckooFilter<T, DefaultHasher, StdRng> {
+    ScalableCuckooFilterBuilder::new()
+        .initial_capacity(10_000)
+        .false_positive_probability(0.001)
+        .rng(StdRng::from_seed(Default::default()))
+        .finish()
+}
+
+fn bigram(first: &str, second: &str) -> String {
+    // Words never contain whitespaces, so bigrams do not collide with single words.
+    format!("{} {}", first, second)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn content(offset: u64, data: &[u8], eof: bool) -> FileContent {
+        FileContent {
+            path: PathBuf::from("test.log"),
+            offset,
+            data: data.to_vec(),
+            eof,
+            reset: false,
+            error: None,
+        }
+    }
+
+    fn regions(state: &FileState, query: &str, options: &SearchOptions) -> Vec<Region> {
+        let query = query.parse::<Query>().expect("Never fails");
+        state.candidate_regions(&query, options)
+    }
+
+    #[test]
+    fn appended_lines() {
+        let mut state = FileState::new(IndexOptions::default(), FileType::Text);
+        let mut lines = Vec::new();
+        state.update(content(0, b"foo\nbar", false), |o, n, l| lines.push((o, n, l.to_vec())));
+        state.update(content(7, b" baz\n", true), |o, n, l| lines.push((o, n, l.to_vec())));
+        assert!(lines.is_empty());
+        assert!(state.is_eof_reached());
+        assert_eq!(state.indexed_bytes(), 12);
+
+        state.update(content(12, b"qux\nquu", false), |o, n, l| lines.push((o, n, l.to_vec())));
+        state.update(content(19, b"x\n", false), |o, n, l| lines.push((o, n, l.to_vec())));
+        assert_eq!(
+            lines,
+            vec![(12, 3, b"qux\n".to_vec()), (16, 4, b"quux\n".to_vec())]
+        );
+    }
+
+    #[test]
+    fn long_lines_are_split_regardless_of_reads() {
+        let mut data = vec![b'x'; MAX_LINE_LEN * 2 + 10];
+        data.extend_from_slice(b"\nfoo\n");
+        let expected = vec![
+            (0, 1, MAX_LINE_LEN),
+            (MAX_LINE_LEN as u64, 2, MAX_LINE_LEN),
+            (MAX_LINE_LEN as u64 * 2, 3, 11),
+            (MAX_LINE_LEN as u64 * 2 + 11, 4, 4),
+        ];
+
+        for &chunk_size in &[data.len(), 100_000, MAX_LINE_LEN - 1, MAX_LINE_LEN + 1] {
+            let mut state = FileState::new(IndexOptions::default(), FileType::Text);
+            state.update(content(0, b"", true), |_, _, _| {});
+            let mut lines = Vec::new();
+            let mut offset = 0;
+            for chunk in data.chunks(chunk_size) {
+                state.update(content(offset, chunk, false), |o, n, l| {
+                    lines.push((o, n, l.len()))
+                });
+                offset += chunk.len() as u64;
+            }
+            assert_eq!(lines, expected, "chunk_size={}", chunk_size);
+        }
+
+        assert_eq!(first_line_len(b"foo"), None);
+        assert_eq!(first_line_len(b"foo\nbar"), Some(4));
+        assert_eq!(first_line_len(&vec![b'x'; MAX_LINE_LEN - 1]), None);
+        assert_eq!(first_line_len(&vec![b'x'; MAX_LINE_LEN + 1]), Some(MAX_LINE_LEN));
+    }
+
+    #[test]
+    fn blocks() {
+        let options = IndexOptions {
+            block_size: 8,
+            ..IndexOptions::default()
+        };
+        let mut state = FileState::new(options, FileType::Text);
+        state.update(content(0, b"aaa\nbbb\nccc\nddd\neee\n", true), |_, _, _| {});
+        assert_eq!(state.block_count(), 3);
+
+        let options = SearchOptions::default();
+        let region = |start, end, start_line| Region {
+            start,
+            end,
+            start_line,
+        };
+        assert_eq!(regions(&state, "aaa", &options), vec![region(0, Some(8), 1)]);
+        assert_eq!(regions(&state, "ccc", &options), vec![region(8, Some(16), 3)]);
+        assert_eq!(regions(&state, "eee", &options), vec![region(16, None, 5)]);
+        assert_eq!(
+            regions(&state, "aaa OR eee", &options),
+            vec![region(0, Some(8), 1), region(16, None, 5)]
+        );
+
+        // Adjacent regions are merged
+        assert_eq!(
+            regions(&state, "bbb OR ccc OR eee", &options),
+            vec![region(0, None, 1)]
+        );
+        assert!(regions(&state, "fff", &options).is_empty());
+    }
+
+    #[test]
+    fn arrival_windows() {
+        let options = IndexOptions {
+            block_duration: Duration::from_secs(5),
+            ..IndexOptions::default()
+        };
+        let mut state = FileState::new(options, FileType::Text);
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let at = |secs| t0 + Duration::from_secs(secs);
+        state.update_at(content(0, b"foo 1\n", false), at(0), |_, _, _| {});
+        state.update_at(content(6, b"foo 2\n", false), at(3), |_, _, _| {});
+        state.update_at(content(12, b"foo 3\n", false), at(10), |_, _, _| {});
+        state.update_at(content(18, b"foo 4\n", false), at(20), |_, _, _| {});
+        assert_eq!(state.block_count(), 3);
+
+        let starts = |since: Option<u64>, until: Option<u64>| {
+            let options = SearchOptions {
+                since: since.map(at),
+                until: until.map(at),
+                ..SearchOptions::default()
+            };
+            let regions = regions(&state, "foo", &options);
+            regions.iter().map(|r| r.start_line).collect::<Vec<_>>()
+        };
+        assert_eq!(starts(None, None), vec![1]);
+        assert_eq!(starts(Some(3), None), vec![1]);
+        assert_eq!(starts(Some(4), None), vec![3]);
+        assert_eq!(starts(Some(15), None), vec![4]);
+        assert_eq!(starts(None, Some(9)), vec![1]);
+        assert_eq!(starts(Some(5), Some(15)), vec![3]);
+        assert!(starts(Some(21), None).is_empty());
+    }
+
+    #[test]
+    fn reset() {
+        let mut state = FileState::new(IndexOptions::default(), FileType::Text);
+        state.update(content(0, b"foo\nbar\n", true), |_, _, _| {});
+        let mut reset = content(0, b"baz\n", false);
+        reset.reset = true;
+        let mut lines = Vec::new();
+        state.update(reset, |o, n, l| lines.push((o, n, l.to_vec())));
+        assert_eq!(lines, vec![(0, 1, b"baz\n".to_vec())]);
+        assert_eq!(state.indexed_bytes(), 4);
+
+        let options = SearchOptions::default();
+        assert!(regions(&state, "foo", &options).is_empty());
+        assert_eq!(regions(&state, "baz", &options).len(), 1);
+    }
+}
@@ -1,22 +1,62 @@
-use fibers::sync::mpsc;
+use fibers::sync::{mpsc, oneshot};
 use fibers::{BoxSpawn, Spawn};
+use fibers_tasque::{DefaultIoTaskQueue, TaskQueueExt};
+use rand;
 use futures::{Async, Future, Poll, Stream};
-use rand::{SeedableRng, StdRng};
-use scalable_cuckoo_filter::{DefaultHasher, ScalableCuckooFilter, ScalableCuckooFilterBuilder};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
-use tokenize::WordTokenizer;
-use watch::fs::{FileContent, FileSystemWatcher};
-use Error;
+use self::file_state::{FileState, Region};
+use self::subscription::{LiveQuery, Subscriber};
+use query::Query;
+use rpc;
+use watch::fs::{tar_gzip, FileContent, FileSystemEvent, FileSystemWatcher, FileType, WatchOptions};
+use {Error, Result};
+
+pub use self::subscription::{Subscription, SubscriptionEvent};
+pub use self::summary::Summary;
+pub use self::verify::Match;
+
+mod file_state;
+mod subscription;
+mod summary;
+mod verify;
+
+const DEFAULT_BLOCK_SIZE: u64 = 16 * 1024 * 1024;
+const DEFAULT_BLOCK_DURATION_SECS: u64 = 60 * 60;
 
 #[derive(Debug)]
 pub struct Agent {
     spawner: BoxSpawn,
     fs_watcher: FileSystemWatcher,
+    roots: Vec<(PathBuf, IndexOptions)>,
     files: HashMap<PathBuf, FileState>,
+
+    // The identifiers of the `FileWatcher`s of the files and their current paths
+    // (events from the old watchers of reindexed files are ignored by these)
+    watcher_ids: HashMap<PathBuf, u64>,
+    watcher_paths: HashMap<u64, PathBuf>,
+    next_watcher_id: u64,
+
+    // The archives being watched (their members are in `files`)
+    archives: HashSet<PathBuf>,
+
+    live_queries: Vec<LiveQuery>,
+    summary: Summary,
+    summary_version: u64,
+    is_summary_dirty: bool,
+
+    // The progress of the scan by a one-shot `FileSystemWatcher`
+    scanning_files: usize,
+    is_scan_done: bool,
+    scan_waiters: Vec<oneshot::Monitored<(), Error>>,
+
     file_event_tx: mpsc::Sender<FileEvent>,
     file_event_rx: mpsc::Receiver<FileEvent>,
+    command_tx: mpsc::Sender<Command>,
+    command_rx: mpsc::Receiver<Command>,
 }
 impl Agent {
     pub fn new<S>(spawner: S, fs_watcher: FileSystemWatcher) -> Self
@@ -24,26 +64,324 @@ impl Agent {
         S: Spawn + Send + 'static,
     {
         let (file_event_tx, file_event_rx) = mpsc::channel();
+        let (command_tx, command_rx) = mpsc::channel();
         Agent {
             spawner: spawner.boxed(),
             fs_watcher,
+            roots: Vec::new(),
             files: HashMap::new(),
+            watcher_ids: HashMap::new(),
+            watcher_paths: HashMap::new(),
+            next_watcher_id: 0,
+            archives: HashSet::new(),
+            live_queries: Vec::new(),
+            summary: Summary::new(),
+
+            // Starts from a random version so that the summaries of different runs are not confused
+            summary_version: rand::random(),
+
+            is_summary_dirty: false,
+            scanning_files: 0,
+            is_scan_done: false,
+            scan_waiters: Vec::new(),
             file_event_tx,
             file_event_rx,
+            command_tx,
+            command_rx,
+        }
+    }
+
+    /// Starts watching the files under `root_dir` and indexing them with `options`.
+    pub fn watch<P: AsRef<Path>>(&mut self, root_dir: P, options: IndexOptions) -> Result<()> {
+        track!(self.watch_with_options(root_dir, options, &WatchOptions::default()))
+    }
+
+    /// Starts watching the files under `root_dir` selected by `watch_options`,
+    /// and indexing them with `index_options`.
+    pub fn watch_with_options<P: AsRef<Path>>(
+        &mut self,
+        root_dir: P,
+        index_options: IndexOptions,
+        watch_options: &WatchOptions,
+    ) -> Result<()> {
+        track!(self.fs_watcher.watch_with_options(&root_dir, watch_options))?;
+        self.roots
+            .push((root_dir.as_ref().to_path_buf(), index_options));
+        Ok(())
+    }
+
+    /// Returns a handle for querying the agent.
+    ///
+    /// The handle is usable while the agent is being polled.
+    pub fn handle(&self) -> AgentHandle {
+        AgentHandle {
+            command_tx: self.command_tx.clone(),
+        }
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Lookup {
+                query,
+                options,
+                reply,
+            } => {
+                let paths = self.candidates(&query, &options)
+                    .into_iter()
+                    .map(|(path, _, _)| path)
+                    .collect();
+                reply.exit(Ok(paths));
+            }
+            Command::Search {
+                query,
+                options,
+                reply,
+            } => {
+                let candidates = self.candidates(&query, &options);
+                let scanned_files = candidates.len();
+                let total_files = self.files.len();
+                let failed_files = self.files.values().filter(|f| f.error().is_some()).count();
+                let full_scan = self.roots
+                    .iter()
+                    .any(|(_, o)| query.needs_full_scan(o.bigrams, o.trigrams));
+                let query = if options.ignore_case {
+                    query.to_folded()
+                } else {
+                    query
+                };
+                let limit = verify::Limit {
+                    max_results: options.max_results,
+                    max_bytes: rpc::MAX_SEARCH_RESPONSE_BYTES,
+                };
+                let verify =
+                    verify::VerifyFiles::new(candidates, query, options.ignore_case, limit);
+                let future = verify.then(move |result| {
+                    reply.exit(result.map(|matches| {
+                        SearchResult {
+                            matches,
+                            scanned_files,
+                            total_files,
+                            failed_files,
+                            full_scan,
+                        }
+                    }));
+                    Ok(())
+                });
+                self.spawner.spawn(future);
+            }
+            Command::Subscribe {
+                query,
+                options,
+                subscriber,
+            } => {
+                self.live_queries
+                    .push(LiveQuery::new(query, options, subscriber));
+            }
+            Command::Stats { reply } => {
+                let stats = AgentStats {
+                    roots: self.roots.iter().map(|(root, _)| root.clone()).collect(),
+                    files: self.files.len(),
+                    blocks: self.files.values().map(|f| f.block_count()).sum(),
+                    indexed_bytes: self.files.values().map(|f| f.indexed_bytes()).sum(),
+                    filter_bytes: self.files.values().map(|f| f.filter_bytes()).sum(),
+                    rescans: self.fs_watcher.rescans(),
+                    overflows: self.fs_watcher.overflows(),
+                };
+                reply.exit(Ok(stats));
+            }
+            Command::Files { reply } => {
+                let mut files = self.files
+                    .iter()
+                    .map(|(path, file)| {
+                        // The sizes of decompressed contents are unknown until they are read
+                        let is_compressed = file.file_type() != FileType::Text;
+                        let is_complete = file.is_eof_reached();
+                        let status = FileStatus {
+                            path: path.clone(),
+                            size: if is_compressed && is_complete {
+                                Some(file.indexed_bytes())
+                            } else {
+                                None
+                            },
+                            indexed_bytes: file.indexed_bytes(),
+                            blocks: file.block_count(),
+                            is_pending: is_compressed && !is_complete,
+                            error: file.error().cloned(),
+                        };
+                        (status, is_compressed)
+                    })
+                    .collect::<Vec<_>>();
+                files.sort_by(|a, b| a.0.path.cmp(&b.0.path));
+
+                // The sizes are obtained on the I/O threads so that indexing is not blocked
+                let future = DefaultIoTaskQueue.async_call(move || {
+                    files
+                        .into_iter()
+                        .map(|(mut file, is_compressed)| {
+                            if !is_compressed {
+                                file.size = fs::metadata(&file.path).map(|m| m.len()).ok();
+                                file.is_pending =
+                                    file.size.is_some_and(|size| file.indexed_bytes < size);
+                            }
+                            file
+                        })
+                        .collect()
+                });
+                self.spawner.spawn(future.then(move |result| {
+                    reply.exit(result.map_err(Error::from));
+                    Ok(())
+                }));
+            }
+            Command::Reindex { path, reply } => {
+                reply.exit(Ok(self.fs_watcher.reindex(path)));
+            }
+            Command::WaitScanned { reply } => {
+                self.scan_waiters.push(reply);
+            }
+            Command::Summary {
+                known_version,
+                reply,
+            } => {
+                self.refresh_summary();
+                let summary = if known_version == Some(self.summary_version) {
+                    None
+                } else {
+                    Some(self.summary.clone())
+                };
+                reply.exit(Ok((self.summary_version, summary)));
+            }
+        }
+    }
+    fn refresh_summary(&mut self) {
+        if !self.is_summary_dirty {
+            return;
         }
+        let mut summary = Summary::new();
+        for file in self.files.values() {
+            summary.merge(file.summary());
+        }
+        if summary != self.summary {
+            self.summary = summary;
+            self.summary_version = self.summary_version.wrapping_add(1);
+        }
+        self.is_summary_dirty = false;
+    }
+    fn candidates(
+        &self,
+        query: &Query,
+        options: &SearchOptions,
+    ) -> Vec<(PathBuf, FileType, Vec<Region>)> {
+        let folded_query = query.to_folded();
+        let mut candidates = self.files
+            .iter()
+            .map(|(path, file)| {
+                let query = if file.options().case_insensitive {
+                    &folded_query
+                } else {
+                    query
+                };
+                let regions = file.candidate_regions(query, options);
+                (path.clone(), file.file_type(), regions)
+            })
+            .filter(|(_, _, regions)| !regions.is_empty())
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| a.0.cmp(&b.0));
+        candidates
+    }
+    fn index_options(&self, path: &Path) -> IndexOptions {
+        self.roots
+            .iter()
+            .filter(|&(root, _)| path.starts_with(root))
+            .max_by_key(|&(root, _)| root.components().count())
+            .map(|(_, options)| options.clone())
+            .unwrap_or_default()
     }
     fn handle_file_event(&mut self, event: FileEvent) {
         match event {
-            FileEvent::Updated { path, content } => self.handle_file_updated(path, content),
-            FileEvent::Deleted { path } => self.handle_file_deleted(path),
+            FileEvent::Updated {
+                watcher_id,
+                content,
+            } => {
+                if let Some(path) = self.watcher_paths.get(&watcher_id).cloned() {
+                    self.handle_file_updated(&path, content);
+                }
+            }
+            FileEvent::Deleted { watcher_id } => {
+                if let Some(path) = self.watcher_paths.remove(&watcher_id) {
+                    self.watcher_ids.remove(&path);
+                    self.remove_files(&path);
+                }
+            }
+            FileEvent::Scanned => self.scanning_files -= 1,
         }
     }
-    fn handle_file_deleted(&mut self, path: PathBuf) {
-        self.files.remove(&path);
+    fn handle_file_renamed(&mut self, from: PathBuf, to: PathBuf) {
+        // The file previously at `to` (if any) has been replaced
+        if let Some(watcher_id) = self.watcher_ids.remove(&to) {
+            self.watcher_paths.remove(&watcher_id);
+        }
+        self.remove_files(&to);
+
+        let watcher_id = match self.watcher_ids.remove(&from) {
+            None => return,
+            Some(watcher_id) => watcher_id,
+        };
+        if self.archives.contains(&from) {
+            // The virtual paths of the members are changed, so the archive is read again
+            self.watcher_paths.remove(&watcher_id);
+            self.remove_files(&from);
+            self.fs_watcher.reindex(&to);
+            return;
+        }
+        self.watcher_ids.insert(to.clone(), watcher_id);
+        self.watcher_paths.insert(watcher_id, to.clone());
+        if let Some(file) = self.files.remove(&from) {
+            self.files.insert(to, file);
+        }
     }
-    fn handle_file_updated(&mut self, path: PathBuf, content: FileContent) {
+
+    /// Removes the file at `path`, and the members of it if it is an archive.
+    fn remove_files(&mut self, path: &Path) {
+        if let Some(file) = self.files.remove(path) {
+            self.is_summary_dirty |= !file.summary().is_empty();
+        }
+        if !self.archives.remove(path) {
+            return;
+        }
+        let is_summary_dirty = &mut self.is_summary_dirty;
+        self.files.retain(|p, file| {
+            let is_member = tar_gzip::is_member_path(p, path);
+            if is_member {
+                *is_summary_dirty |= !file.summary().is_empty();
+            }
+            !is_member
+        });
+    }
+    fn handle_file_updated(&mut self, watcher_path: &Path, content: FileContent) {
+        let path = if self.archives.contains(watcher_path) {
+            if !self.files.contains_key(&content.path) {
+                // The first content of a member of the archive
+                let options = self.index_options(&content.path);
+                let file_state = FileState::new(options, FileType::TarGzip);
+                self.files.insert(content.path.clone(), file_state);
+            }
+            content.path.clone()
+        } else {
+            // Contents read before a rename have the old path
+            watcher_path.to_path_buf()
+        };
+        if let Some(ref e) = content.error {
+            eprintln!("dg: {}: {}", path.display(), rpc::error_message(e));
+        }
         if let Some(file) = self.files.get_mut(&path) {
-            file.update_cuckoo_filter(content);
+            let live_queries = &mut self.live_queries;
+            live_queries.retain(|q| !q.is_cancelled());
+            file.update(content, |offset, line_number, line| {
+                for q in live_queries.iter_mut() {
+                    q.handle_line(&path, offset, line_number, line);
+                }
+            });
+            self.is_summary_dirty |= file.take_summary_changed();
         }
     }
 }
@@ -51,25 +389,64 @@ impl Future for Agent {
     type Item = ();
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        while let Async::Ready(Some(file_watcher)) = track!(self.fs_watcher.poll())? {
-            self.files
-                .insert(file_watcher.path().to_path_buf(), FileState::new());
+        while !self.is_scan_done {
+            let file_watcher = match track!(self.fs_watcher.poll())? {
+                Async::NotReady => break,
+                Async::Ready(None) => {
+                    self.is_scan_done = true;
+                    break;
+                }
+                Async::Ready(Some(FileSystemEvent::Renamed { from, to })) => {
+                    self.handle_file_renamed(from, to);
+                    continue;
+                }
+                Async::Ready(Some(FileSystemEvent::Watch(file_watcher))) => file_watcher,
+            };
+            // Discards the old index of the file (if it is being reindexed)
+            self.remove_files(file_watcher.path());
+            // Binary files are not indexed, and the states of archive members are made
+            // on their first contents (see `handle_file_updated`)
+            let file_type = file_watcher.file_type();
+            if file_watcher.is_archive() {
+                self.archives.insert(file_watcher.path().to_path_buf());
+            } else if !file_type.is_binary() {
+                let options = self.index_options(file_watcher.path());
+                self.files.insert(
+                    file_watcher.path().to_path_buf(),
+                    FileState::new(options, file_type),
+                );
+            }
+            let watcher_id = self.next_watcher_id;
+            self.next_watcher_id += 1;
+            let path = file_watcher.path().to_path_buf();
+            if let Some(old_watcher_id) = self.watcher_ids.insert(path.clone(), watcher_id) {
+                self.watcher_paths.remove(&old_watcher_id);
+            }
+            self.watcher_paths.insert(watcher_id, path);
 
-            let path0 = file_watcher.path().to_path_buf();
-            let path1 = path0.clone();
             let file_event_tx0 = self.file_event_tx.clone();
             let file_event_tx1 = self.file_event_tx.clone();
+            let is_oneshot = self.fs_watcher.is_oneshot();
+            if is_oneshot {
+                self.scanning_files += 1;
+            }
             self.spawner.spawn(
                 file_watcher
                     .for_each(move |content| {
                         let result = file_event_tx0.send(FileEvent::Updated {
-                            path: path0.clone(),
+                            watcher_id,
                             content,
                         });
                         track!(result.map_err(Error::from))
                     })
                     .then(move |_result| {
-                        let _ = file_event_tx1.send(FileEvent::Deleted { path: path1 });
+                        // One-shot file watchers terminate at the end of the files
+                        let event = if is_oneshot {
+                            FileEvent::Scanned
+                        } else {
+                            FileEvent::Deleted { watcher_id }
+                        };
+                        let _ = file_event_tx1.send(event);
                         Ok(())
                     }),
             );
@@ -77,59 +454,313 @@ impl Future for Agent {
         while let Async::Ready(Some(file_event)) = self.file_event_rx.poll().expect("Never fails") {
             self.handle_file_event(file_event);
         }
+        while let Async::Ready(Some(command)) = self.command_rx.poll().expect("Never fails") {
+            self.handle_command(command);
+        }
+        if self.is_scan_done && self.scanning_files == 0 {
+            for reply in self.scan_waiters.drain(..) {
+                reply.exit(Ok(()));
+            }
+        }
         Ok(Async::NotReady)
     }
 }
 
-#[derive(Debug)]
-enum FileEvent {
-    Updated { path: PathBuf, content: FileContent },
-    Deleted { path: PathBuf },
+/// A handle of an `Agent`.
+#[derive(Debug, Clone)]
+pub struct AgentHandle {
+    command_tx: mpsc::Sender<Command>,
+}
+impl AgentHandle {
+    /// Returns the paths of the files which may satisfy `query`.
+    ///
+    /// Note that the result is based on the cuckoo filters of the files,
+    /// so it may include false positives.
+    pub fn lookup(&self, query: Query, options: SearchOptions) -> AsyncReply<Vec<PathBuf>> {
+        let (reply, monitor) = oneshot::monitor();
+        let command = Command::Lookup {
+            query,
+            options,
+            reply,
+        };
+        let _ = self.command_tx.send(command);
+        AsyncReply(monitor)
+    }
+
+    /// Returns the lines which satisfy `query`.
+    ///
+    /// The candidate files are narrowed down by their cuckoo filters,
+    /// and then verified by scanning the actual contents.
+    pub fn search(&self, query: Query, options: SearchOptions) -> AsyncReply<SearchResult> {
+        let (reply, monitor) = oneshot::monitor();
+        let command = Command::Search {
+            query,
+            options,
+            reply,
+        };
+        let _ = self.command_tx.send(command);
+        AsyncReply(monitor)
+    }
+
+    /// Registers a live query which is notified of every newly appended line satisfying `query`.
+    ///
+    /// `SearchOptions::since` and `SearchOptions::until` are ignored.
+    pub fn subscribe(&self, query: Query, options: SearchOptions) -> Subscription {
+        let (subscription, subscriber) = Subscription::new();
+        let command = Command::Subscribe {
+            query,
+            options,
+            subscriber,
+        };
+        let _ = self.command_tx.send(command);
+        subscription
+    }
+
+    /// Returns the summary of the words in all the files indexed by the agent, and its version.
+    ///
+    /// The version changes whenever the summary changes.
+    /// If it is equal to `known_version`, the summary is omitted from the result.
+    pub fn summary(&self, known_version: Option<u64>) -> AsyncReply<(u64, Option<Summary>)> {
+        let (reply, monitor) = oneshot::monitor();
+        let command = Command::Summary {
+            known_version,
+            reply,
+        };
+        let _ = self.command_tx.send(command);
+        AsyncReply(monitor)
+    }
+
+    /// Returns the indexing status of every watched file, in the order of their paths.
+    pub fn files(&self) -> AsyncReply<Vec<FileStatus>> {
+        let (reply, monitor) = oneshot::monitor();
+        let _ = self.command_tx.send(Command::Files { reply });
+        AsyncReply(monitor)
+    }
+
+    /// Discards the indices of the files under `path`, and reads them again from the beginning.
+    ///
+    /// Returns the number of the files to be reindexed.
+    pub fn reindex<P: AsRef<Path>>(&self, path: P) -> AsyncReply<usize> {
+        let (reply, monitor) = oneshot::monitor();
+        let command = Command::Reindex {
+            path: path.as_ref().to_path_buf(),
+            reply,
+        };
+        let _ = self.command_tx.send(command);
+        AsyncReply(monitor)
+    }
+
+    /// Returns a future which completes after all the files have been indexed.
+    ///
+    /// This is meaningful only for an agent made with `FileSystemWatcher::oneshot`;
+    /// otherwise the directories are watched forever, and the future never completes.
+    pub fn wait_scanned(&self) -> AsyncReply<()> {
+        let (reply, monitor) = oneshot::monitor();
+        let _ = self.command_tx.send(Command::WaitScanned { reply });
+        AsyncReply(monitor)
+    }
+
+    /// Returns the statistics of the agent.
+    pub fn stats(&self) -> AsyncReply<AgentStats> {
+        let (reply, monitor) = oneshot::monitor();
+        let _ = self.command_tx.send(Command::Stats { reply });
+        AsyncReply(monitor)
+    }
 }
 
+/// A future which represents a reply from an `Agent`.
 #[derive(Debug)]
-struct FileState {
-    cuckoo_filter: ScalableCuckooFilter<str, DefaultHasher, StdRng>,
-    buf: Vec<u8>,
-    is_binary: bool,
+pub struct AsyncReply<T>(oneshot::Monitor<T, Error>);
+impl<T> Future for AsyncReply<T> {
+    type Item = T;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        track!(self.0.poll().map_err(Error::from))
+    }
 }
-impl FileState {
-    fn new() -> Self {
-        let cuckoo_filter = ScalableCuckooFilterBuilder::new()
-            .initial_capacity(100_000)
-            .false_positive_probability(0.001)
-            .rng(StdRng::from_seed(Default::default()))
-            .finish();
-        FileState {
-            cuckoo_filter,
-            buf: Vec::new(),
-            is_binary: false,
-        }
-    }
-    fn update_cuckoo_filter(&mut self, content: FileContent) {
-        if self.is_binary {
-            return;
-        }
 
-        self.buf.extend(content.data);
-        let mut end = 0;
-        for w in WordTokenizer::new(&self.buf) {
-            match w {
-                Err(_) => {
-                    self.is_binary = true;
-                    end = self.buf.len();
-                    break;
-                }
-                Ok((start, w)) => {
-                    self.cuckoo_filter.insert(w);
-                    end = start + w.len();
-                }
-            }
-        }
-        if self.is_binary {
-            self.buf.clear()
-        } else {
-            for _ in self.buf.drain(0..end) {}
+/// Options for a search.
+#[derive(Debug, Clone, Default)]
+pub struct SearchOptions {
+    /// If `true`, lines are matched against the query ignoring case.
+    ///
+    /// Files under roots not indexed with `IndexOptions::case_insensitive` can not be pruned
+    /// in this mode, so all of them are scanned.
+    pub ignore_case: bool,
+
+    /// If `Some(t)`, only the lines appended at or after `t` are searched.
+    ///
+    /// The arrival time of a line is approximated by the block containing it
+    /// (see `IndexOptions::block_duration`), and the lines existing when the agent started
+    /// are regarded as having arrived at that time.
+    pub since: Option<SystemTime>,
+
+    /// If `Some(t)`, only the lines appended at or before `t` are searched.
+    pub until: Option<SystemTime>,
+
+    /// If `Some(n)`, at most `n` matches are returned by a search.
+    ///
+    /// The matches are taken in the order of their paths and line numbers.
+    pub max_results: Option<usize>,
+}
+
+/// The result of a search.
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    /// The lines which satisfy the query.
+    pub matches: Vec<Match>,
+
+    /// The number of the files (partially) scanned to verify the candidates.
+    pub scanned_files: usize,
+
+    /// The number of the files indexed by the agent.
+    pub total_files: usize,
+
+    /// The number of the files which could not be read to the end (see `FileStatus::error`).
+    ///
+    /// Matches in the unread parts of them are missing.
+    pub failed_files: usize,
+
+    /// If `true`, the query had no literals usable for pruning, so every file was scanned.
+    pub full_scan: bool,
+}
+
+/// The statistics of an agent.
+#[derive(Debug, Clone)]
+pub struct AgentStats {
+    /// The watched root directories.
+    pub roots: Vec<PathBuf>,
+
+    /// The number of the indexed files.
+    pub files: usize,
+
+    /// The total number of the blocks of the indexed files.
+    pub blocks: usize,
+
+    /// The total number of the bytes read from the indexed files.
+    pub indexed_bytes: u64,
+
+    /// The approximate memory usage of the filters in bytes.
+    pub filter_bytes: u64,
+
+    /// The number of the times directories have been rescanned
+    /// because some file system events may have been lost (see `FileSystemWatcher::rescans`).
+    pub rescans: u64,
+
+    /// The number of the times the periodic listings of directories have found changes
+    /// whose events had been lost (see `FileSystemWatcher::overflows`).
+    pub overflows: u64,
+}
+
+/// The indexing status of a file.
+#[derive(Debug, Clone)]
+pub struct FileStatus {
+    pub path: PathBuf,
+
+    /// The current size of the file (`None` if it is not accessible).
+    ///
+    /// For compressed files, this is the size of the decompressed content,
+    /// which is known only after it has been read to the end.
+    pub size: Option<u64>,
+
+    /// The number of the bytes read from the file.
+    pub indexed_bytes: u64,
+
+    /// The number of the blocks of the file.
+    pub blocks: usize,
+
+    /// `true` if the file has not been fully indexed yet.
+    pub is_pending: bool,
+
+    /// The reason why the rest of the file could not be read (e.g., a truncated gzip file).
+    pub error: Option<Error>,
+}
+
+/// Options for indexing the files under a watched root directory.
+#[derive(Debug, Clone)]
+pub struct IndexOptions {
+    /// If `true`, pairs of adjacent words are also indexed so that phrase queries can be pruned.
+    ///
+    /// This roughly doubles the size of the filter of each file.
+    pub bigrams: bool,
+
+    /// If `true`, byte trigrams are also indexed so that substring queries can be pruned.
+    pub trigrams: bool,
+
+    /// If `true`, words and trigrams are indexed after being converted by `tokenize::fold_case`.
+    ///
+    /// Queries are also case-folded when pruning the files indexed in this mode,
+    /// and then verified either exactly or ignoring case according to `SearchOptions`.
+    pub case_insensitive: bool,
+
+    /// The approximate number of bytes covered by a filter.
+    ///
+    /// Each file is divided into blocks of consecutive lines, and each block has its own filters.
+    /// Searches only read the blocks whose filters match the query.
+    /// Smaller blocks reduce the amount of reads, but increase the memory usage.
+    pub block_size: u64,
+
+    /// The maximum period during which the lines of a block are appended.
+    ///
+    /// This determines the granularity of `SearchOptions::since` and `SearchOptions::until`.
+    pub block_duration: Duration,
+}
+impl Default for IndexOptions {
+    fn default() -> Self {
+        IndexOptions {
+            bigrams: false,
+            trigrams: false,
+            case_insensitive: false,
+            block_size: DEFAULT_BLOCK_SIZE,
+            block_duration: Duration::from_secs(DEFAULT_BLOCK_DURATION_SECS),
         }
     }
 }
+
+#[derive(Debug)]
+enum Command {
+    Lookup {
+        query: Query,
+        options: SearchOptions,
+        reply: oneshot::Monitored<Vec<PathBuf>, Error>,
+    },
+    Search {
+        query: Query,
+        options: SearchOptions,
+        reply: oneshot::Monitored<SearchResult, Error>,
+    },
+    Subscribe {
+        query: Query,
+        options: SearchOptions,
+        subscriber: Subscriber,
+    },
+    Stats {
+        reply: oneshot::Monitored<AgentStats, Error>,
+    },
+    Files {
+        reply: oneshot::Monitored<Vec<FileStatus>, Error>,
+    },
+    Reindex {
+        path: PathBuf,
+        reply: oneshot::Monitored<usize, Error>,
+    },
+    WaitScanned {
+        reply: oneshot::Monitored<(), Error>,
+    },
+    Summary {
+        known_version: Option<u64>,
+        reply: oneshot::Monitored<(u64, Option<Summary>), Error>,
+    },
+}
+
+#[derive(Debug)]
+enum FileEvent {
+    Updated {
+        watcher_id: u64,
+        content: FileContent,
+    },
+    Deleted {
+        watcher_id: u64,
+    },
+    Scanned,
+}
@@ -0,0 +1,137 @@
+use fibers::sync::mpsc;
+use futures::{Async, Poll, Stream};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use super::{Match, SearchOptions};
+use query::Query;
+use tokenize;
+use Error;
+
+/// The maximum number of events which can be queued for a subscriber.
+///
+/// If a subscriber can not keep up with the matches, the excess ones are dropped
+/// rather than blocking the indexing.
+const MAX_PENDING_EVENTS: usize = 4096;
+
+/// An event notified to a subscriber.
+#[derive(Debug, Clone)]
+pub enum SubscriptionEvent {
+    /// A newly appended line satisfies the query.
+    Matched(Match),
+
+    /// Some matches have been dropped because the subscriber was too slow.
+    Dropped { count: u64 },
+}
+
+/// A stream of the events of a live query registered by `AgentHandle::subscribe`.
+///
+/// The subscription is cancelled when this stream is dropped.
+/// The stream terminates if the agent stops.
+#[derive(Debug)]
+pub struct Subscription {
+    event_rx: mpsc::Receiver<SubscriptionEvent>,
+    pending: Arc<AtomicUsize>,
+}
+impl Subscription {
+    pub(super) fn new() -> (Self, Subscriber) {
+        let (event_tx, event_rx) = mpsc::channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let subscription = Subscription {
+            event_rx,
+            pending: Arc::clone(&pending),
+        };
+        let subscriber = Subscriber {
+            event_tx,
+            pending,
+            dropped: 0,
+        };
+        (subscription, subscriber)
+    }
+}
+impl Stream for Subscription {
+    type Item = SubscriptionEvent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let polled = self.event_rx.poll().expect("Never fails");
+        if let Async::Ready(Some(_)) = polled {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+        Ok(polled)
+    }
+}
+
+/// The agent side of a subscription.
+#[derive(Debug)]
+pub struct Subscriber {
+    event_tx: mpsc::Sender<SubscriptionEvent>,
+    pending: Arc<AtomicUsize>,
+    dropped: u64,
+}
+impl Subscriber {
+    pub fn is_cancelled(&self) -> bool {
+        self.event_tx.is_disconnected()
+    }
+    fn notify(&mut self, m: Match) {
+        if self.pending.load(Ordering::SeqCst) >= MAX_PENDING_EVENTS {
+            self.dropped += 1;
+            return;
+        }
+        if self.dropped > 0 {
+            let event = SubscriptionEvent::Dropped {
+                count: self.dropped,
+            };
+            self.send(event);
+            self.dropped = 0;
+        }
+        self.send(SubscriptionEvent::Matched(m));
+    }
+    fn send(&mut self, event: SubscriptionEvent) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let _ = self.event_tx.send(event);
+    }
+}
+
+/// A live query registered to an agent.
+#[derive(Debug)]
+pub struct LiveQuery {
+    query: Query,
+    ignore_case: bool,
+    subscriber: Subscriber,
+}
+impl LiveQuery {
+    pub fn new(query: Query, options: SearchOptions, subscriber: Subscriber) -> Self {
+        let query = if options.ignore_case {
+            query.to_folded()
+        } else {
+            query
+        };
+        LiveQuery {
+            query,
+            ignore_case: options.ignore_case,
+            subscriber,
+        }
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.subscriber.is_cancelled()
+    }
+
+    /// Notifies the subscriber if the line satisfies the query.
+    pub fn handle_line(&mut self, path: &Path, offset: u64, line_number: u64, line: &[u8]) {
+        let is_matched = if self.ignore_case {
+            let folded = tokenize::fold_case(&String::from_utf8_lossy(line));
+            self.query.is_match(folded.as_bytes())
+        } else {
+            self.query.is_match(line)
+        };
+        if is_matched {
+            self.subscriber.notify(Match {
+                path: path.to_path_buf(),
+                line_number,
+                offset,
+                line: super::verify::line_text(line),
+            });
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::collections::HashSet;
+
+use query::Filter;
+use tokenize;
+use {Error, ErrorKind, Result};
+
+/// The number of bits of a summary (and a dense `FileSummary`).
+///
+/// Summaries of the agents with more than about 200,000 distinct words hardly skip them.
+const SUMMARY_BITS: u32 = 1 << 20;
+
+/// The minimum number of bits of an encoded summary.
+const MIN_ENCODED_BITS: u32 = 1 << 13;
+
+/// The number of bits set by a word.
+const HASH_COUNT: u32 = 4;
+
+/// The number of bits above which a `FileSummary` switches to the dense representation.
+const MAX_SPARSE_BITS: usize = (SUMMARY_BITS / 64) as usize;
+
+/// A Bloom filter of the (case-folded) words in all the files indexed by an agent.
+///
+/// This is shipped to coordinators so that they can route queries
+/// only to the agents which may have the words of the queries.
+/// Unlike cuckoo filters, Bloom filters of the same size can be merged by bitwise OR,
+/// so this is maintained as the union of the `FileSummary` of every file.
+///
+/// The words indexed after the summary was shipped are unknown to the coordinator
+/// until the next heartbeat, so queries for them may skip the agent meanwhile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Summary {
+    bits: Vec<u64>,
+}
+impl Summary {
+    pub fn new() -> Self {
+        Summary {
+            bits: vec![0; (SUMMARY_BITS / 64) as usize],
+        }
+    }
+
+    /// Encodes the summary into a base64 string.
+    ///
+    /// Sparse summaries are folded in half (by bitwise OR) while at most a quarter of the bits
+    /// are set, so the size of the encoded summary depends on the number of the words.
+    pub fn encode(&self) -> String {
+        let mut bits = self.bits.clone();
+        while bits.len() * 64 > MIN_ENCODED_BITS as usize {
+            let (low, high) = bits.split_at(bits.len() / 2);
+            let folded = low.iter().zip(high).map(|(x, y)| x | y).collect::<Vec<_>>();
+            let ones: usize = folded.iter().map(|x| x.count_ones() as usize).sum();
+            if ones > folded.len() * 64 / 4 {
+                break;
+            }
+            bits = folded;
+        }
+
+        let mut bytes = Vec::with_capacity(bits.len() * 8);
+        for x in &bits {
+            bytes.extend_from_slice(&x.to_le_bytes());
+        }
+        BASE64.encode(bytes)
+    }
+
+    /// Decodes a summary encoded by `Summary::encode`.
+    pub fn decode(s: &str) -> Result<Self> {
+        let bytes = track!(BASE64.decode(s).map_err(Error::from))?;
+        let bits = bytes.len() * 8;
+        track_assert!(
+            bits.is_power_of_two()
+                && MIN_ENCODED_BITS as usize <= bits
+                && bits <= SUMMARY_BITS as usize,
+            ErrorKind::InvalidInput,
+            "invalid summary size: {} bytes",
+            bytes.len()
+        );
+        let bits = bytes
+            .chunks(8)
+            .map(|b| {
+                let mut x = [0; 8];
+                x.copy_from_slice(b);
+                u64::from_le_bytes(x)
+            })
+            .collect();
+        Ok(Summary { bits })
+    }
+
+    pub(super) fn merge(&mut self, file: &FileSummary) {
+        match *file {
+            FileSummary::Sparse(ref bits) => {
+                for &bit in bits {
+                    self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+                }
+            }
+            FileSummary::Dense(ref bits) => {
+                for (x, y) in self.bits.iter_mut().zip(bits.iter()) {
+                    *x |= *y;
+                }
+            }
+        }
+    }
+}
+impl Default for Summary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Filter for Summary {
+    fn contains_word(&self, word: &str) -> bool {
+        // Decoded summaries may have been folded (see `Summary::encode`)
+        let len = self.bits.len() as u32 * 64;
+        word_bits(&tokenize::fold_case(word))
+            .map(|bit| bit % len)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+    fn contains_bigram(&self, _first: &str, _second: &str) -> bool {
+        true
+    }
+    fn contains_trigram(&self, _trigram: &[u8]) -> bool {
+        true
+    }
+}
+
+/// The bits of a `Summary` set by the words of a file.
+///
+/// This is kept sparse while the file has a few distinct words.
+#[derive(Debug)]
+pub enum FileSummary {
+    Sparse(HashSet<u32>),
+    Dense(Vec<u64>),
+}
+impl FileSummary {
+    pub fn new() -> Self {
+        FileSummary::Sparse(HashSet::new())
+    }
+
+    /// Adds a case-folded word.
+    ///
+    /// Returns `true` if the word sets any new bits.
+    pub fn insert(&mut self, folded_word: &str) -> bool {
+        let mut is_changed = false;
+        for bit in word_bits(folded_word) {
+            match *self {
+                FileSummary::Sparse(ref mut bits) => is_changed |= bits.insert(bit),
+                FileSummary::Dense(ref mut bits) => {
+                    let x = &mut bits[(bit / 64) as usize];
+                    is_changed |= *x & (1 << (bit % 64)) == 0;
+                    *x |= 1 << (bit % 64);
+                }
+            }
+        }
+        if let FileSummary::Sparse(ref bits) = *self {
+            if bits.len() > MAX_SPARSE_BITS {
+                let mut summary = Summary::new();
+                summary.merge(self);
+                *self = FileSummary::Dense(summary.bits);
+            }
+        }
+        is_changed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match *self {
+            FileSummary::Sparse(ref bits) => bits.is_empty(),
+            FileSummary::Dense(_) => false,
+        }
+    }
+}
+impl Default for FileSummary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the positions of the bits for `word` (by double hashing on FNV-1a).
+///
+/// The hash function is fixed so that the summaries made by different processes are compatible.
+fn word_bits(word: &str) -> impl Iterator<Item = u32> {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in word.as_bytes() {
+        h ^= u64::from(b);
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    let h1 = h as u32;
+    let h2 = (h >> 32) as u32 | 1;
+    (0..HASH_COUNT).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % SUMMARY_BITS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary_of<'a, I>(words: I) -> (Summary, FileSummary)
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut file = FileSummary::new();
+        for word in words {
+            file.insert(&tokenize::fold_case(word));
+        }
+        let mut summary = Summary::new();
+        summary.merge(&file);
+        (summary, file)
+    }
+
+    fn encoded_bits(s: &str) -> usize {
+        BASE64.decode(s).expect("Never fails").len() * 8
+    }
+
+    #[test]
+    fn sparse_summaries_are_folded() {
+        let (summary, _) = summary_of(vec!["foo", "Bar", "baz"]);
+        let encoded = summary.encode();
+        assert_eq!(encoded_bits(&encoded), MIN_ENCODED_BITS as usize);
+
+        let decoded = Summary::decode(&encoded).expect("Never fails");
+        for word in &["foo", "FOO", "bar", "baz"] {
+            assert!(summary.contains_word(word), "{}", word);
+            assert!(decoded.contains_word(word), "{}", word);
+        }
+        assert!(!summary.contains_word("qux"));
+        assert!(!decoded.contains_word("qux"));
+    }
+
+    #[test]
+    fn dense_summaries_are_folded_while_sparse_enough() {
+        let words = (0..20_000).map(|i| format!("word{}", i)).collect::<Vec<_>>();
+        let (summary, file) = summary_of(words.iter().map(|w| w.as_str()));
+        assert!(matches!(file, FileSummary::Dense(_)));
+
+        let encoded = summary.encode();
+        let bits = encoded_bits(&encoded);
+        assert!(bits > MIN_ENCODED_BITS as usize && bits < SUMMARY_BITS as usize);
+
+        let decoded = Summary::decode(&encoded).expect("Never fails");
+        assert!(words.iter().all(|w| decoded.contains_word(w)));
+        assert_eq!(
+            Summary::decode(&summary.encode()).expect("Never fails"),
+            decoded
+        );
+    }
+
+    #[test]
+    fn file_summaries_report_new_bits() {
+        let mut file = FileSummary::new();
+        assert!(file.is_empty());
+        assert!(file.insert("foo"));
+        assert!(!file.insert("foo"));
+        assert!(!file.is_empty());
+    }
+
+    #[test]
+    fn invalid_summaries_are_rejected() {
+        assert!(Summary::decode("not base64!").is_err());
+        let too_small = BASE64.encode(vec![0; MIN_ENCODED_BITS as usize / 16]);
+        assert!(Summary::decode(&too_small).is_err());
+        let not_power_of_two = BASE64.encode(vec![0; MIN_ENCODED_BITS as usize / 8 * 3]);
+        assert!(Summary::decode(&not_power_of_two).is_err());
+    }
+}
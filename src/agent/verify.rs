@@ -0,0 +1,440 @@
+use fibers_tasque::{AsyncCall, DefaultCpuTaskQueue, TaskQueueExt};
+use futures::{Async, Future, Poll};
+use std;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::file_state::{Region, MAX_LINE_LEN};
+use query::Query;
+use tokenize;
+use watch::fs::gzip::GzipReader;
+use watch::fs::tar_gzip::{self, TarReader};
+use watch::fs::FileType;
+use {Error, Result};
+
+/// A line which exactly matches a query.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Match {
+    /// The path of the file containing the line.
+    pub path: PathBuf,
+
+    /// The line number (1-origin).
+    pub line_number: u64,
+
+    /// The byte offset of the beginning of the line.
+    pub offset: u64,
+
+    /// The text of the line (without the trailing newline).
+    pub line: String,
+}
+
+/// The limits of the matches collected by `VerifyFiles`.
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    /// The maximum number of matches.
+    pub max_results: Option<usize>,
+
+    /// The maximum total size of the paths and the lines of matches.
+    ///
+    /// Verification stops after the first match exceeding this size,
+    /// so that the response can be marked as truncated.
+    pub max_bytes: usize,
+}
+impl Limit {
+    fn is_reached(&self, matches: usize, bytes: usize) -> bool {
+        self.max_results.is_some_and(|n| matches >= n) || bytes > self.max_bytes
+    }
+}
+
+/// A future which verifies candidate regions of files by scanning their contents.
+///
+/// The files are verified on the CPU threads, so that large searches do not block indexing.
+/// Once the matches of the first files (in the order of `candidates`) reach the limit,
+/// the verification of the rest of the files is cancelled.
+#[derive(Debug)]
+pub struct VerifyFiles {
+    calls: Vec<Option<AsyncCall<Result<Vec<Match>>>>>,
+    results: Vec<Option<Vec<Match>>>,
+    next: usize,
+    matches: Vec<Match>,
+    bytes: usize,
+    limit: Limit,
+    is_cancelled: Arc<AtomicBool>,
+}
+impl VerifyFiles {
+    /// Makes a new `VerifyFiles` instance.
+    ///
+    /// If `ignore_case` is `true`, `query` must be case-folded.
+    pub fn new(
+        candidates: Vec<(PathBuf, FileType, Vec<Region>)>,
+        query: Query,
+        ignore_case: bool,
+        limit: Limit,
+    ) -> Self {
+        let query = Arc::new(query);
+        let is_cancelled = Arc::new(AtomicBool::new(false));
+        let calls = candidates
+            .into_iter()
+            .map(|(path, file_type, regions)| {
+                let query = Arc::clone(&query);
+                let is_cancelled = Arc::clone(&is_cancelled);
+                Some(DefaultCpuTaskQueue.async_call(move || {
+                    track!(verify_file(
+                        path,
+                        file_type,
+                        &regions,
+                        &query,
+                        ignore_case,
+                        &limit,
+                        &is_cancelled
+                    ))
+                }))
+            })
+            .collect::<Vec<_>>();
+        VerifyFiles {
+            results: calls.iter().map(|_| None).collect(),
+            calls,
+            next: 0,
+            matches: Vec::new(),
+            bytes: 0,
+            limit,
+            is_cancelled,
+        }
+    }
+
+    fn finish(&mut self) -> Vec<Match> {
+        self.is_cancelled.store(true, Ordering::SeqCst);
+        self.calls.clear();
+        let mut matches = std::mem::take(&mut self.matches);
+        matches.sort();
+        if let Some(n) = self.limit.max_results {
+            matches.truncate(n);
+        }
+        matches
+    }
+}
+impl Future for VerifyFiles {
+    type Item = Vec<Match>;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        for i in self.next..self.calls.len() {
+            let result = match self.calls[i] {
+                Some(ref mut call) => track!(call.poll().map_err(Error::from))?,
+                None => continue,
+            };
+            if let Async::Ready(result) = result {
+                self.results[i] = Some(track!(result)?);
+                self.calls[i] = None;
+            }
+        }
+
+        // The matches are collected in the order of the files,
+        // so that the same matches are returned regardless of the order of completion
+        while self.next < self.calls.len() {
+            let matches = match self.results[self.next].take() {
+                Some(matches) => matches,
+                None => return Ok(Async::NotReady),
+            };
+            self.next += 1;
+            for m in matches {
+                self.bytes += match_size(&m);
+                self.matches.push(m);
+                if self.limit.is_reached(self.matches.len(), self.bytes) {
+                    return Ok(Async::Ready(self.finish()));
+                }
+            }
+        }
+        Ok(Async::Ready(self.finish()))
+    }
+}
+impl Drop for VerifyFiles {
+    fn drop(&mut self) {
+        self.is_cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+fn match_size(m: &Match) -> usize {
+    m.path.as_os_str().len() + m.line.len()
+}
+
+fn verify_file(
+    path: PathBuf,
+    file_type: FileType,
+    regions: &[Region],
+    query: &Query,
+    ignore_case: bool,
+    limit: &Limit,
+    is_cancelled: &AtomicBool,
+) -> Result<Vec<Match>> {
+    let mut reader = match Content::open(&path, file_type) {
+        Ok(reader) => reader,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(track!(Error::from(e))),
+    };
+    let mut matches = Vec::new();
+    let mut bytes = 0;
+    let mut buf = Vec::new();
+    for region in regions {
+        track!(reader.seek(region.start).map_err(Error::from))?;
+        let mut offset = region.start;
+        let mut line_number = region.start_line;
+        while region.end.is_none_or(|end| offset < end) {
+            if is_cancelled.load(Ordering::SeqCst) {
+                return Ok(matches);
+            }
+            buf.clear();
+            let read_size = track!(reader.read_line(&mut buf).map_err(Error::from))?;
+            if read_size == 0 {
+                break;
+            }
+
+            let is_matched = if ignore_case {
+                let line = tokenize::fold_case(&String::from_utf8_lossy(&buf));
+                query.is_match(line.as_bytes())
+            } else {
+                query.is_match(&buf)
+            };
+            if is_matched {
+                let m = Match {
+                    path: path.clone(),
+                    line_number,
+                    offset,
+                    line: line_text(&buf),
+                };
+                bytes += match_size(&m);
+                matches.push(m);
+
+                // No file contributes more matches than the whole response can contain
+                if limit.is_reached(matches.len(), bytes) {
+                    return Ok(matches);
+                }
+            }
+            offset += read_size as u64;
+            line_number += 1;
+        }
+    }
+    Ok(matches)
+}
+
+/// A reader of the (decompressed) content of a file.
+enum Content {
+    Plain(BufReader<File>),
+    Gzip {
+        path: PathBuf,
+        reader: Box<BufReader<GzipReader>>,
+        position: u64,
+
+        // The range of the content in the decompressed data (for members of archives)
+        start: u64,
+        end: Option<u64>,
+    },
+}
+impl Content {
+    fn open(path: &Path, file_type: FileType) -> io::Result<Self> {
+        match file_type {
+            FileType::TarGzip => {
+                let (archive, _) =
+                    tar_gzip::split_member_path(path).ok_or(io::ErrorKind::NotFound)?;
+                Self::open_member(archive, path)
+            }
+            FileType::Gzip => Ok(Content::Gzip {
+                path: path.to_path_buf(),
+                reader: Box::new(BufReader::new(GzipReader::new(File::open(path)?))),
+                position: 0,
+                start: 0,
+                end: None,
+            }),
+            _ => Ok(Content::Plain(BufReader::new(File::open(path)?))),
+        }
+    }
+
+    fn open_member(archive: PathBuf, path: &Path) -> io::Result<Self> {
+        let file = File::open(&archive)?;
+        let mut reader = TarReader::new(BufReader::new(GzipReader::new(file)));
+        loop {
+            let member = reader.next_member().map_err(io::Error::other)?;
+            let member = member.ok_or(io::ErrorKind::NotFound)?;
+            if tar_gzip::member_path(&archive, &member.path) == path {
+                return Ok(Content::Gzip {
+                    path: archive,
+                    reader: Box::new(reader.into_inner()),
+                    position: member.position,
+                    start: member.position,
+                    end: Some(member.position + member.size),
+                });
+            }
+        }
+    }
+
+    /// Moves to `offset` of the content.
+    ///
+    /// Compressed contents are decompressed from the beginning for moving backward.
+    fn seek(&mut self, offset: u64) -> io::Result<()> {
+        match *self {
+            Content::Plain(ref mut r) => r.seek(SeekFrom::Start(offset)).map(|_| ()),
+            Content::Gzip {
+                ref path,
+                ref mut reader,
+                ref mut position,
+                start,
+                ..
+            } => {
+                let offset = start + offset;
+                if offset < *position {
+                    **reader = BufReader::new(GzipReader::new(File::open(path)?));
+                    *position = 0;
+                }
+                match io::copy(&mut reader.take(offset - *position), &mut io::sink()) {
+                    Ok(size) => *position += size,
+                    Err(ref e) if is_broken_stream(e) => {}
+                    Err(e) => return Err(e),
+                }
+                Ok(())
+            }
+        }
+    }
+    /// Reads a line in the same way as `file_state::first_line_len` splits lines.
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let max_len = MAX_LINE_LEN as u64;
+        match *self {
+            Content::Plain(ref mut r) => r.take(max_len).read_until(b'\n', buf),
+            Content::Gzip {
+                ref mut reader,
+                ref mut position,
+                end,
+                ..
+            } => {
+                let limit = end.map_or(max_len, |end| end.saturating_sub(*position).min(max_len));
+                match reader.take(limit).read_until(b'\n', buf) {
+                    Ok(read_size) => {
+                        *position += read_size as u64;
+                        Ok(read_size)
+                    }
+                    Err(ref e) if is_broken_stream(e) => Ok(0),
+                    Err(e) => Err(e),
+                }
+            }
+        }
+    }
+}
+
+/// Returns `true` if `e` is caused by a truncated or corrupted compressed stream.
+///
+/// Such streams are regarded as ending at the broken part,
+/// because `GzipFileWatcher` indexes the content only up to there.
+fn is_broken_stream(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+    )
+}
+
+pub fn line_text(line: &[u8]) -> String {
+    let mut line = line;
+    if line.ends_with(b"\n") {
+        line = &line[..line.len() - 1];
+    }
+    if line.ends_with(b"\r") {
+        line = &line[..line.len() - 1];
+    }
+    String::from_utf8_lossy(line).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn long_lines_are_counted_as_indexed() {
+        let path = std::env::temp_dir().join(format!("dg-verify-test-{}.log", process::id()));
+        let mut data = vec![b'x'; MAX_LINE_LEN * 2 + 10];
+        data.extend_from_slice(b"\nfoo\nbar foo\n");
+        fs::write(&path, &data).expect("Never fails");
+
+        let query = "foo".parse::<Query>().expect("Never fails");
+        let region = Region {
+            start: 0,
+            end: None,
+            start_line: 1,
+        };
+        let limit = Limit {
+            max_results: None,
+            max_bytes: usize::MAX,
+        };
+        let is_cancelled = AtomicBool::new(false);
+        let result = verify_file(
+            path.clone(),
+            FileType::Text,
+            &[region],
+            &query,
+            false,
+            &limit,
+            &is_cancelled,
+        );
+        let _ = fs::remove_file(&path);
+        let matches = result.expect("Never fails");
+        let lines = matches
+            .iter()
+            .map(|m| (m.line_number, m.offset, m.line.as_str()))
+            .collect::<Vec<_>>();
+        let offset = MAX_LINE_LEN as u64 * 2 + 11;
+        assert_eq!(lines, vec![(4, offset, "foo"), (5, offset + 4, "bar foo")]);
+    }
+
+    #[test]
+    fn verification_stops_at_limit() {
+        let path = std::env::temp_dir().join(format!("dg-verify-limit-{}.log", process::id()));
+        fs::write(&path, b"foo 1\nbar\nfoo 2\nfoo 3\n").expect("Never fails");
+
+        let query = "foo".parse::<Query>().expect("Never fails");
+        let region = Region {
+            start: 0,
+            end: None,
+            start_line: 1,
+        };
+        let verify = |limit: Limit, is_cancelled: bool| {
+            let is_cancelled = AtomicBool::new(is_cancelled);
+            let matches = verify_file(
+                path.clone(),
+                FileType::Text,
+                std::slice::from_ref(&region),
+                &query,
+                false,
+                &limit,
+                &is_cancelled,
+            ).expect("Never fails");
+            matches.into_iter().map(|m| m.line_number).collect::<Vec<_>>()
+        };
+        let by_count = verify(
+            Limit {
+                max_results: Some(2),
+                max_bytes: usize::MAX,
+            },
+            false,
+        );
+        let by_size = verify(
+            Limit {
+                max_results: None,
+                max_bytes: 1,
+            },
+            false,
+        );
+        let cancelled = verify(
+            Limit {
+                max_results: None,
+                max_bytes: usize::MAX,
+            },
+            true,
+        );
+        let _ = fs::remove_file(&path);
+        assert_eq!(by_count, vec![1, 3]);
+        assert_eq!(by_size, vec![1]);
+        assert_eq!(cancelled, Vec::<u64>::new());
+    }
+}
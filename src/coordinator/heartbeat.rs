@@ -0,0 +1,111 @@
+use fibers::time::timer::{self, Timeout};
+use futures::future::Join;
+use futures::{Async, Future, Poll};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use agent::{AgentHandle, AgentStats, AsyncReply, Summary};
+use rpc::{AgentInfo, Call, Method, ResponseBody};
+use Error;
+
+type Report = Join<AsyncReply<AgentStats>, AsyncReply<(u64, Option<Summary>)>>;
+
+/// A future which periodically registers an agent to a coordinator.
+///
+/// Each heartbeat carries the statistics of the agent,
+/// and its summary if the coordinator does not have the latest one.
+///
+/// Failures of heartbeats (e.g., the coordinator is down) are ignored,
+/// and the next heartbeat is sent after `interval` anyway.
+/// This future terminates only if the agent stops.
+#[derive(Debug)]
+pub struct Heartbeat {
+    coordinator: SocketAddr,
+    agent_addr: SocketAddr,
+    agent: AgentHandle,
+    interval: Duration,
+    timer: Timeout,
+    report: Option<Report>,
+    call: Option<(Call, Timeout)>,
+
+    // The version of the summary held by the coordinator
+    acked_summary_version: Option<u64>,
+}
+impl Heartbeat {
+    /// Makes a new `Heartbeat` instance.
+    ///
+    /// `agent_addr` is the address of the RPC server of the agent reachable from the coordinator.
+    pub fn new(
+        coordinator: SocketAddr,
+        agent_addr: SocketAddr,
+        agent: AgentHandle,
+        interval: Duration,
+    ) -> Self {
+        Heartbeat {
+            coordinator,
+            agent_addr,
+            agent,
+            interval,
+            timer: timer::timeout(Duration::from_secs(0)),
+            report: None,
+            call: None,
+            acked_summary_version: None,
+        }
+    }
+}
+impl Future for Heartbeat {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some((mut call, mut timeout)) = self.call.take() {
+                match call.poll() {
+                    Ok(Async::NotReady) => {
+                        if let Ok(Async::NotReady) = timeout.poll() {
+                            self.call = Some((call, timeout));
+                        }
+                    }
+                    Ok(Async::Ready(response)) => {
+                        if let ResponseBody::Heartbeat {
+                            summary_version, ..
+                        } = response.body
+                        {
+                            self.acked_summary_version = summary_version;
+                        }
+                    }
+                    Err(_) => {}
+                }
+            }
+            if let Some(mut report) = self.report.take() {
+                match track!(report.poll())? {
+                    Async::NotReady => self.report = Some(report),
+                    Async::Ready((stats, (summary_version, summary))) => {
+                        let info = AgentInfo {
+                            addr: self.agent_addr,
+                            roots: stats
+                                .roots
+                                .iter()
+                                .map(|root| root.to_string_lossy().into_owned())
+                                .collect(),
+                            stats: stats.into(),
+                            summary_version,
+                            summary: summary.map(|s| s.encode()),
+                        };
+                        let call = Call::with_method(self.coordinator, Method::Heartbeat(info));
+                        self.call = Some((call, timer::timeout(self.interval)));
+                        continue;
+                    }
+                }
+            }
+
+            if let Async::NotReady = self.timer.poll().expect("Never fails") {
+                return Ok(Async::NotReady);
+            }
+            self.timer = timer::timeout(self.interval);
+            if self.report.is_none() && self.call.is_none() {
+                let summary = self.agent.summary(self.acked_summary_version);
+                self.report = Some(self.agent.stats().join(summary));
+            }
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std;
+use std::net::SocketAddr;
+
+use rpc::{AgentFailure, LookupResponse, ResponseBody, SearchResponse};
+
+/// The reply from an agent, or the reason why it could not be obtained.
+pub type AgentReply = std::result::Result<ResponseBody, String>;
+
+/// Merges the replies to a `lookup` request, tagging the paths with the agents.
+pub fn merge_lookup(replies: Vec<(SocketAddr, AgentReply)>) -> LookupResponse {
+    let mut merged = LookupResponse::default();
+    for (agent, reply) in replies {
+        match reply {
+            Ok(ResponseBody::Lookup(response)) => {
+                for mut entry in response.paths {
+                    entry.host = Some(entry.host.unwrap_or_else(|| agent.to_string()));
+                    merged.paths.push(entry);
+                }
+                merged.partial |= response.partial;
+                merged.failures.extend(response.failures);
+            }
+            Ok(body) => merged.failures.push(unexpected_reply(agent, &body)),
+            Err(message) => merged.failures.push(failure(agent, message)),
+        }
+    }
+    merged.paths.sort();
+    merged.paths.dedup();
+    merged.partial |= !merged.failures.is_empty();
+    merged
+}
+
+/// Merges the replies to a `search` request, tagging the matches with the agents.
+///
+/// The same lines reported more than once are de-duplicated,
+/// and then the matches are truncated to `max_results` and `MAX_SEARCH_RESPONSE_BYTES`.
+pub fn merge_search(
+    replies: Vec<(SocketAddr, AgentReply)>,
+    max_results: Option<usize>,
+) -> SearchResponse {
+    let mut merged = SearchResponse::default();
+    for (agent, reply) in replies {
+        match reply {
+            Ok(ResponseBody::Search(response)) => {
+                for mut entry in response.matches {
+                    entry.host = Some(entry.host.unwrap_or_else(|| agent.to_string()));
+                    merged.matches.push(entry);
+                }
+                merged.scanned_files += response.scanned_files;
+                merged.total_files += response.total_files;
+                merged.failed_files += response.failed_files;
+                merged.full_scan |= response.full_scan;
+                merged.truncated |= response.truncated;
+                merged.partial |= response.partial;
+                merged.failures.extend(response.failures);
+            }
+            Ok(body) => merged.failures.push(unexpected_reply(agent, &body)),
+            Err(message) => merged.failures.push(failure(agent, message)),
+        }
+    }
+    merged.matches.sort_by(|a, b| {
+        let a = (&a.host, &a.path, a.line_number, a.offset);
+        let b = (&b.host, &b.path, b.line_number, b.offset);
+        a.cmp(&b)
+    });
+    merged
+        .matches
+        .dedup_by(|a, b| (&a.host, &a.path, a.offset) == (&b.host, &b.path, b.offset));
+    if let Some(n) = max_results {
+        merged.matches.truncate(n);
+    }
+    merged.limit_size();
+    merged.partial |= !merged.failures.is_empty();
+    merged
+}
+
+fn failure(agent: SocketAddr, message: String) -> AgentFailure {
+    AgentFailure {
+        agent: agent.to_string(),
+        message,
+    }
+}
+
+fn unexpected_reply(agent: SocketAddr, body: &ResponseBody) -> AgentFailure {
+    failure(agent, format!("Unexpected reply: {:?}", body))
+}
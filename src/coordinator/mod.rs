@@ -0,0 +1,536 @@
+//! Coordinator which distributes queries to multiple agents.
+//!
+//! A coordinator serves the same RPC protocol as agents (see the `rpc` module).
+//! It forwards each request to all the agents in parallel, and merges their responses
+//! into a single one, tagging every entry with the address of the agent it came from.
+//!
+//! Agents are either given statically, or registered by their heartbeats (see `Heartbeat`).
+//! Registered agents which have not sent a heartbeat within `CoordinatorOptions::agent_ttl`
+//! are forgotten.
+//!
+//! Registered agents also report the summaries of their indices,
+//! and queries are not sent to the agents whose summaries show that they have no matches.
+//! Summaries lag behind the indices by up to a heartbeat interval,
+//! so agents whose indices were updated since their previous heartbeats are never skipped.
+//! The number of the skipped agents is reported in the responses as `skipped_agents`,
+//! since an idle agent may still index new words after its last heartbeat.
+use fibers::sync::{mpsc, oneshot};
+use fibers::time::timer::TimerExt;
+use futures::{self, Async, Future, Poll, Stream};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use self::merge::AgentReply;
+use agent::Summary;
+use query::Query;
+use rpc::{self, AgentInfo, AgentStatus, BoxEvents, BoxReply, Call, Method, QueryParams, ResponseBody,
+          Service};
+use {Error, ErrorKind, Result};
+
+pub use self::heartbeat::Heartbeat;
+
+mod heartbeat;
+mod merge;
+
+/// The default address of the RPC server of a coordinator.
+pub const DEFAULT_COORDINATOR_ADDR: &str = "127.0.0.1:7879";
+
+const DEFAULT_AGENT_TIMEOUT_MILLIS: u64 = 5000;
+const DEFAULT_AGENT_TTL_SECS: u64 = 30;
+
+/// A coordinator of agents.
+#[derive(Debug)]
+pub struct Coordinator {
+    options: CoordinatorOptions,
+    static_agents: Vec<SocketAddr>,
+    registered_agents: HashMap<SocketAddr, RegisteredAgent>,
+    command_tx: mpsc::Sender<Command>,
+    command_rx: mpsc::Receiver<Command>,
+}
+impl Coordinator {
+    /// Makes a new coordinator which always distributes queries to `static_agents`
+    /// in addition to the registered agents.
+    pub fn new(static_agents: Vec<SocketAddr>, options: CoordinatorOptions) -> Self {
+        let (command_tx, command_rx) = mpsc::channel();
+        Coordinator {
+            options,
+            static_agents,
+            registered_agents: HashMap::new(),
+            command_tx,
+            command_rx,
+        }
+    }
+
+    /// Returns a handle for querying the coordinator.
+    ///
+    /// The handle is usable while the coordinator is being polled.
+    pub fn handle(&self) -> CoordinatorHandle {
+        CoordinatorHandle {
+            command_tx: self.command_tx.clone(),
+            agent_timeout: self.options.agent_timeout,
+            agent_ttl: self.options.agent_ttl,
+        }
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Heartbeat {
+                info,
+                summary,
+                reply,
+            } => {
+                reply.exit(Ok(self.register(info, summary)));
+            }
+            Command::Agents { query, reply } => {
+                reply.exit(Ok(self.select_agents(&query)));
+            }
+            Command::Status { reply } => {
+                self.expire_agents();
+                let now = Instant::now();
+                let mut agents = self.static_agents
+                    .iter()
+                    .filter(|addr| !self.registered_agents.contains_key(addr))
+                    .map(|&addr| AgentStatus {
+                        addr,
+                        roots: Vec::new(),
+                        stats: None,
+                        expires_in_secs: None,
+                        summary_version: None,
+                    })
+                    .collect::<Vec<_>>();
+                for agent in self.registered_agents.values() {
+                    let elapsed = now.duration_since(agent.last_heartbeat);
+                    let expires_in = self.options.agent_ttl.checked_sub(elapsed);
+                    agents.push(AgentStatus {
+                        addr: agent.info.addr,
+                        roots: agent.info.roots.clone(),
+                        stats: Some(agent.info.stats.clone()),
+                        expires_in_secs: Some(expires_in.unwrap_or_default().as_secs()),
+                        summary_version: agent.summary.as_ref().map(|&(v, _)| v),
+                    });
+                }
+                agents.sort_by_key(|a| a.addr);
+                reply.exit(Ok(agents));
+            }
+        }
+    }
+    /// Registers (or refreshes) an agent, and returns the version of its summary held.
+    fn register(&mut self, info: AgentInfo, summary: Option<Summary>) -> Option<u64> {
+        let version = info.summary_version;
+        let previous = self.registered_agents.remove(&info.addr);
+        let is_index_updated = previous.as_ref().is_none_or(|agent| {
+            let (old, new) = (&agent.info.stats, &info.stats);
+            (old.files, old.indexed_bytes) != (new.files, new.indexed_bytes)
+        });
+        let summary = summary.map(|s| (version, s)).or_else(|| {
+            previous
+                .and_then(|agent| agent.summary)
+                .filter(|&(v, _)| v == version)
+        });
+        let agent = RegisteredAgent {
+            info,
+            summary,
+            is_index_updated,
+            last_heartbeat: Instant::now(),
+        };
+        let summary_version = agent.summary.as_ref().map(|&(v, _)| v);
+        self.registered_agents.insert(agent.info.addr, agent);
+        summary_version
+    }
+
+    /// Returns the agents to which `query` is sent, and the number of the skipped agents.
+    fn select_agents(&mut self, query: &Query) -> (Vec<SocketAddr>, usize) {
+        self.expire_agents();
+        let mut agents = self.static_agents.clone();
+        let mut skipped = 0;
+        for agent in self.registered_agents.values() {
+            if agent.may_match(query) {
+                agents.push(agent.info.addr);
+            } else if !self.static_agents.contains(&agent.info.addr) {
+                skipped += 1;
+            }
+        }
+        agents.sort();
+        agents.dedup();
+        (agents, skipped)
+    }
+    fn expire_agents(&mut self) {
+        let now = Instant::now();
+        let ttl = self.options.agent_ttl;
+        self.registered_agents
+            .retain(|_, agent| now.duration_since(agent.last_heartbeat) < ttl);
+    }
+}
+impl Future for Coordinator {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while let Async::Ready(Some(command)) = self.command_rx.poll().expect("Never fails") {
+            self.handle_command(command);
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// A handle of a `Coordinator`.
+///
+/// This serves the RPC requests to the coordinator.
+#[derive(Debug, Clone)]
+pub struct CoordinatorHandle {
+    command_tx: mpsc::Sender<Command>,
+    agent_timeout: Duration,
+    agent_ttl: Duration,
+}
+impl CoordinatorHandle {
+    /// Sends a request to every agent which may satisfy `query`,
+    /// and collects the replies (or the reasons of the failures).
+    ///
+    /// The number of the agents skipped by their summaries is also returned.
+    fn fan_out(
+        &self,
+        query: Query,
+        method: Method,
+    ) -> impl Future<Item = (Vec<(SocketAddr, AgentReply)>, usize), Error = Error> {
+        let (reply, monitor) = oneshot::monitor();
+        let _ = self.command_tx.send(Command::Agents { query, reply });
+
+        let timeout = self.agent_timeout;
+        monitor.map_err(Error::from).and_then(move |(agents, skipped)| {
+            let calls = agents
+                .into_iter()
+                .map(|agent| {
+                    Call::with_method(agent, method.clone())
+                        .timeout_after(timeout)
+                        .then(move |result| {
+                            let reply = match result {
+                                Ok(response) => match response.body {
+                                    ResponseBody::Error { message } => Err(message),
+                                    body => Ok(body),
+                                },
+                                Err(None) => Err("timed out".to_owned()),
+                                Err(Some(e)) => Err(rpc::error_message(&e)),
+                            };
+                            Ok((agent, reply))
+                        })
+                })
+                .collect::<Vec<_>>();
+            futures::future::join_all(calls).map(move |replies| (replies, skipped))
+        })
+    }
+}
+impl Service for CoordinatorHandle {
+    fn lookup(&self, params: QueryParams) -> BoxReply {
+        let query = match params.query.parse::<Query>() {
+            Err(e) => return Box::new(futures::failed(track!(e))),
+            Ok(query) => query,
+        };
+        let future = self.fan_out(query, Method::Lookup(params));
+        Box::new(future.map(|(replies, skipped)| {
+            let mut merged = merge::merge_lookup(replies);
+            merged.skipped_agents = skipped;
+            ResponseBody::Lookup(merged)
+        }))
+    }
+    fn search(&self, params: QueryParams) -> BoxReply {
+        let query = match params.query.parse::<Query>() {
+            Err(e) => return Box::new(futures::failed(track!(e))),
+            Ok(query) => query,
+        };
+        let max_results = params.max_results;
+        let future = self.fan_out(query, Method::Search(params));
+        Box::new(future.map(move |(replies, skipped)| {
+            let mut merged = merge::merge_search(replies, max_results);
+            merged.skipped_agents = skipped;
+            ResponseBody::Search(merged)
+        }))
+    }
+    fn subscribe(&self, _params: QueryParams) -> Result<BoxEvents> {
+        track_panic!(
+            ErrorKind::InvalidInput,
+            "Subscriptions are not supported by coordinators"
+        );
+    }
+    fn heartbeat(&self, mut info: AgentInfo) -> BoxReply {
+        let summary = match info.summary.take().map(|s| Summary::decode(&s)) {
+            Some(Err(e)) => return Box::new(futures::failed(track!(e))),
+            Some(Ok(summary)) => Some(summary),
+            None => None,
+        };
+        let (reply, monitor) = oneshot::monitor();
+        let command = Command::Heartbeat {
+            info,
+            summary,
+            reply,
+        };
+        let _ = self.command_tx.send(command);
+
+        let ttl_secs = self.agent_ttl.as_secs();
+        Box::new(
+            monitor
+                .map_err(Error::from)
+                .map(move |summary_version| ResponseBody::Heartbeat {
+                    ttl_secs,
+                    summary_version,
+                }),
+        )
+    }
+    fn agents(&self) -> BoxReply {
+        let (reply, monitor) = oneshot::monitor();
+        let _ = self.command_tx.send(Command::Status { reply });
+        Box::new(
+            monitor
+                .map_err(Error::from)
+                .map(|agents| ResponseBody::Agents { agents }),
+        )
+    }
+}
+
+/// Options for a coordinator.
+#[derive(Debug, Clone)]
+pub struct CoordinatorOptions {
+    /// The maximum time to wait for the reply from an agent.
+    ///
+    /// Agents which do not reply within this time are reported as failed,
+    /// and the merged result is marked as partial.
+    pub agent_timeout: Duration,
+
+    /// The period during which a registered agent is regarded as alive after its last heartbeat.
+    pub agent_ttl: Duration,
+}
+impl Default for CoordinatorOptions {
+    fn default() -> Self {
+        CoordinatorOptions {
+            agent_timeout: Duration::from_millis(DEFAULT_AGENT_TIMEOUT_MILLIS),
+            agent_ttl: Duration::from_secs(DEFAULT_AGENT_TTL_SECS),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RegisteredAgent {
+    info: AgentInfo,
+    summary: Option<(u64, Summary)>,
+
+    // The index was updated since the previous heartbeat (or this is the first one),
+    // so it is likely to be updated again before the next heartbeat
+    is_index_updated: bool,
+
+    last_heartbeat: Instant,
+}
+impl RegisteredAgent {
+    /// Returns `false` if the summary shows that the agent has no matches for `query`.
+    ///
+    /// Agents whose indices are being updated are never skipped,
+    /// because their summaries may already lag behind the indices.
+    fn may_match(&self, query: &Query) -> bool {
+        self.is_index_updated
+            || self.summary
+                .as_ref()
+                .is_none_or(|(_, summary)| query.may_match(summary))
+    }
+}
+
+#[derive(Debug)]
+enum Command {
+    Heartbeat {
+        info: AgentInfo,
+        summary: Option<Summary>,
+        reply: oneshot::Monitored<Option<u64>, Error>,
+    },
+    Agents {
+        query: Query,
+        reply: oneshot::Monitored<(Vec<SocketAddr>, usize), Error>,
+    },
+    Status {
+        reply: oneshot::Monitored<Vec<AgentStatus>, Error>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use fibers::time::timer;
+    use fibers::{Executor, InPlaceExecutor, Spawn};
+    use std::net;
+
+    use super::*;
+    use agent::Agent;
+    use rpc::{MatchEntry, RpcServer, SearchResponse};
+    use watch::fs::FileSystemWatcher;
+
+    /// An agent which replies the same matches (including a duplicate) to every `search` request.
+    #[derive(Debug, Clone)]
+    struct FixedAgent;
+    impl Service for FixedAgent {
+        fn lookup(&self, _params: QueryParams) -> BoxReply {
+            Box::new(futures::finished(ResponseBody::Lookup(Default::default())))
+        }
+        fn search(&self, _params: QueryParams) -> BoxReply {
+            let entry = |line_number, offset| MatchEntry {
+                host: None,
+                path: "a.log".to_owned(),
+                line_number,
+                offset,
+                line: "foo".to_owned(),
+            };
+            let response = SearchResponse {
+                matches: vec![entry(2, 4), entry(1, 0), entry(2, 4)],
+                scanned_files: 1,
+                total_files: 3,
+                ..Default::default()
+            };
+            Box::new(futures::finished(ResponseBody::Search(response)))
+        }
+        fn subscribe(&self, _params: QueryParams) -> Result<BoxEvents> {
+            track_panic!(ErrorKind::InvalidInput, "Not supported");
+        }
+    }
+
+    fn unused_addr() -> SocketAddr {
+        let listener = net::TcpListener::bind("127.0.0.1:0").expect("Never fails");
+        listener.local_addr().expect("Never fails")
+    }
+
+    #[test]
+    fn search_with_failing_agents() {
+        let mut executor = InPlaceExecutor::new().expect("Never fails");
+        let live_agent = unused_addr();
+        let server = RpcServer::new(executor.handle(), live_agent, FixedAgent);
+        executor.spawn(server.map_err(|e| panic!("{}", e)));
+
+        // Connections to this agent are accepted by the kernel, but no replies are sent
+        let silent_listener = net::TcpListener::bind("127.0.0.1:0").expect("Never fails");
+        let silent_agent = silent_listener.local_addr().expect("Never fails");
+        let down_agent = unused_addr();
+
+        let options = CoordinatorOptions {
+            agent_timeout: Duration::from_millis(300),
+            ..Default::default()
+        };
+        let coordinator = Coordinator::new(vec![live_agent, silent_agent, down_agent], options);
+        let handle = coordinator.handle();
+        executor.spawn(coordinator.map_err(|e| panic!("{}", e)));
+
+        let params = QueryParams {
+            query: "foo".to_owned(),
+            ..Default::default()
+        };
+        let monitor = executor.spawn_monitor(handle.search(params));
+        let response = executor.run_fiber(monitor).expect("Never fails");
+        let response = match response.expect("Never fails") {
+            ResponseBody::Search(response) => response,
+            body => panic!("Unexpected response: {:?}", body),
+        };
+
+        let matches = response
+            .matches
+            .iter()
+            .map(|m| (m.host.clone(), m.line_number, m.offset))
+            .collect::<Vec<_>>();
+        let host = Some(live_agent.to_string());
+        assert_eq!(matches, vec![(host.clone(), 1, 0), (host, 2, 4)]);
+        assert_eq!(response.scanned_files, 1);
+        assert_eq!(response.total_files, 3);
+        assert!(response.partial);
+
+        let mut failures = response
+            .failures
+            .iter()
+            .map(|f| f.agent.clone())
+            .collect::<Vec<_>>();
+        failures.sort();
+        let mut expected = vec![silent_agent.to_string(), down_agent.to_string()];
+        expected.sort();
+        assert_eq!(failures, expected);
+        let silent_failure = response
+            .failures
+            .iter()
+            .find(|f| f.agent == silent_agent.to_string())
+            .expect("Never fails");
+        assert_eq!(silent_failure.message, "timed out");
+    }
+
+    fn sleep(executor: &mut InPlaceExecutor, duration: Duration) {
+        let monitor = executor.spawn_monitor(timer::timeout(duration));
+        let result = executor.run_fiber(monitor).expect("Never fails");
+        result.expect("Never fails");
+    }
+
+    fn registered_agents(
+        executor: &mut InPlaceExecutor,
+        coordinator: SocketAddr,
+    ) -> Vec<SocketAddr> {
+        let monitor = executor.spawn_monitor(Call::with_method(coordinator, Method::Agents));
+        let response = executor.run_fiber(monitor).expect("Never fails");
+        match response.expect("Never fails").body {
+            ResponseBody::Agents { agents } => agents
+                .into_iter()
+                .filter(|a| a.expires_in_secs.is_some())
+                .map(|a| a.addr)
+                .collect(),
+            body => panic!("Unexpected response: {:?}", body),
+        }
+    }
+
+    #[test]
+    fn agents_are_registered_by_heartbeats_until_expired() {
+        let mut executor = InPlaceExecutor::new().expect("Never fails");
+        let options = CoordinatorOptions {
+            agent_ttl: Duration::from_millis(400),
+            ..Default::default()
+        };
+        let coordinator = Coordinator::new(Vec::new(), options);
+        let coordinator_addr = unused_addr();
+        let server = RpcServer::new(executor.handle(), coordinator_addr, coordinator.handle());
+        executor.spawn(server.map_err(|e| panic!("{}", e)));
+        executor.spawn(coordinator.map_err(|e| panic!("{}", e)));
+
+        let agent = Agent::new(executor.handle(), FileSystemWatcher::oneshot(executor.handle()));
+        let agent_addr = unused_addr();
+        let interval = Duration::from_millis(50);
+        let heartbeat = Heartbeat::new(coordinator_addr, agent_addr, agent.handle(), interval);
+        executor.spawn(agent.map_err(|e| panic!("{}", e)));
+
+        // The agent stops sending heartbeats after a while
+        let stop = timer::timeout(Duration::from_millis(300)).map_err(|_| ());
+        let heartbeat = heartbeat.map_err(|e| panic!("{}", e)).select(stop);
+        executor.spawn(heartbeat.then(|_| Ok(())));
+
+        sleep(&mut executor, Duration::from_millis(200));
+        assert_eq!(registered_agents(&mut executor, coordinator_addr), vec![agent_addr]);
+
+        sleep(&mut executor, Duration::from_millis(800));
+        assert_eq!(registered_agents(&mut executor, coordinator_addr), Vec::new());
+    }
+
+    #[test]
+    fn agents_are_not_skipped_while_indices_are_updated() {
+        let mut coordinator = Coordinator::new(Vec::new(), CoordinatorOptions::default());
+        let addr = unused_addr();
+        let info = |indexed_bytes| AgentInfo {
+            addr,
+            roots: Vec::new(),
+            stats: rpc::IndexStats {
+                files: 1,
+                indexed_bytes,
+                ..Default::default()
+            },
+            summary_version: 1,
+            summary: None,
+        };
+        let query = "foo".parse::<Query>().expect("Never fails");
+
+        // The summary of the first heartbeat may already be outdated
+        assert_eq!(coordinator.register(info(100), Some(Summary::new())), Some(1));
+        assert_eq!(coordinator.select_agents(&query), (vec![addr], 0));
+
+        assert_eq!(coordinator.register(info(100), None), Some(1));
+        assert_eq!(coordinator.select_agents(&query), (Vec::new(), 1));
+
+        assert_eq!(coordinator.register(info(200), None), Some(1));
+        assert_eq!(coordinator.select_agents(&query), (vec![addr], 0));
+
+        assert_eq!(coordinator.register(info(200), None), Some(1));
+        assert_eq!(coordinator.select_agents(&query), (Vec::new(), 1));
+    }
+}
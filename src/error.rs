@@ -1,6 +1,10 @@
+use base64;
 use fibers::sync::oneshot::MonitorError;
 use fibers_inotify;
 use fibers_tasque;
+use regex;
+use regex_syntax;
+use serde_json;
 use std;
 use std::io;
 use std::sync::mpsc::{RecvError, SendError};
@@ -49,6 +53,26 @@ impl From<fibers_tasque::AsyncCallError> for Error {
         ErrorKind::Other.cause(f).into()
     }
 }
+impl From<regex::Error> for Error {
+    fn from(f: regex::Error) -> Self {
+        ErrorKind::InvalidInput.cause(f).into()
+    }
+}
+impl From<regex_syntax::Error> for Error {
+    fn from(f: regex_syntax::Error) -> Self {
+        ErrorKind::InvalidInput.cause(f).into()
+    }
+}
+impl From<serde_json::Error> for Error {
+    fn from(f: serde_json::Error) -> Self {
+        ErrorKind::InvalidInput.cause(f).into()
+    }
+}
+impl From<base64::DecodeError> for Error {
+    fn from(f: base64::DecodeError) -> Self {
+        ErrorKind::InvalidInput.cause(f).into()
+    }
+}
 impl From<fibers_inotify::Error> for Error {
     fn from(f: fibers_inotify::Error) -> Self {
         let kind = match *f.kind() {
@@ -1,15 +1,27 @@
+extern crate base64;
 extern crate fibers;
 extern crate fibers_inotify;
 extern crate fibers_tasque;
+extern crate flate2;
 extern crate futures;
+extern crate mio_uds;
 extern crate rand;
+extern crate regex;
+extern crate regex_syntax;
 extern crate scalable_cuckoo_filter;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 #[macro_use]
 extern crate trackable;
 
 pub use error::{Error, ErrorKind};
 
 pub mod agent;
+pub mod coordinator;
+pub mod query;
+pub mod rpc;
 pub mod tokenize;
 pub mod watch;
 
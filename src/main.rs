@@ -3,20 +3,184 @@ extern crate dg;
 extern crate fibers;
 extern crate fibers_tasque;
 extern crate futures;
+extern crate serde_json;
 #[macro_use]
 extern crate trackable;
 
+use std::io::{self, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, Instant};
 
 use clap::Parser;
-use dg::{agent, watch};
+use dg::query::Query;
+use dg::{agent, coordinator, rpc, watch};
+use fibers::sync::oneshot::MonitorError;
+use fibers::time::timer::TimerExt;
 use fibers::{Executor, InPlaceExecutor, Spawn};
 use futures::{Future, Stream};
 
 #[derive(Parser)]
 enum Args {
     Watch { dir: PathBuf },
-    Agent { dir: PathBuf },
+    Agent {
+        dir: PathBuf,
+
+        /// Indexes pairs of adjacent words to speed up phrase queries.
+        #[arg(long)]
+        bigrams: bool,
+
+        /// Indexes byte trigrams to speed up substring queries.
+        #[arg(long)]
+        trigrams: bool,
+
+        /// Indexes words and trigrams ignoring case.
+        #[arg(long)]
+        ignore_case: bool,
+
+        /// Approximate size of the file regions covered by a filter (in MiB).
+        #[arg(long, default_value_t = 16)]
+        block_size_mib: u64,
+
+        /// Address of the RPC server.
+        #[arg(long, default_value = rpc::DEFAULT_AGENT_ADDR)]
+        listen: SocketAddr,
+
+        /// Address of a coordinator to register this agent to.
+        #[arg(long)]
+        coordinator: Option<SocketAddr>,
+
+        /// Address of the RPC server reported to the coordinator [default: the listen address].
+        #[arg(long)]
+        advertise_addr: Option<SocketAddr>,
+
+        /// Interval between heartbeats to the coordinator (in seconds).
+        #[arg(long, default_value_t = 10)]
+        heartbeat_interval_secs: u64,
+
+        /// Path of a Unix domain socket which serves admin operations in addition to queries.
+        #[arg(long)]
+        admin_socket: Option<PathBuf>,
+
+        /// Permission bits of the admin socket (in octal).
+        #[arg(long, default_value = "600", value_parser = parse_mode)]
+        admin_socket_mode: u32,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    Coordinator {
+        /// Address of an agent which is always queried (can be specified multiple times).
+        ///
+        /// Agents can also register themselves by `dg agent --coordinator`.
+        #[arg(long = "agent")]
+        agents: Vec<SocketAddr>,
+
+        /// Address of the RPC server.
+        #[arg(long, default_value = coordinator::DEFAULT_COORDINATOR_ADDR)]
+        listen: SocketAddr,
+
+        /// Maximum time to wait for the reply from an agent (in milliseconds).
+        #[arg(long, default_value_t = 5000)]
+        agent_timeout_ms: u64,
+
+        /// Period after which an agent is forgotten if no heartbeat arrives (in seconds).
+        #[arg(long, default_value_t = 30)]
+        agent_ttl_secs: u64,
+    },
+    /// Searches the lines satisfying a query by asking an agent (or a coordinator).
+    ///
+    /// Exits with 0 if any lines are found, 1 if none, and 2 if an error occurred
+    /// (including the case where the result is partial).
+    Search {
+        query: String,
+
+        /// Address of the agent or coordinator.
+        #[arg(long, default_value = rpc::DEFAULT_AGENT_ADDR)]
+        agent: String,
+
+        /// Matches lines ignoring case.
+        #[arg(short, long)]
+        ignore_case: bool,
+
+        /// Prints each match as a JSON object.
+        #[arg(long)]
+        json: bool,
+
+        /// Maximum number of matches to print.
+        #[arg(long)]
+        max_results: Option<usize>,
+
+        /// Maximum time to wait for the result, including connecting (in milliseconds).
+        #[arg(long, default_value_t = 30000)]
+        timeout_ms: u64,
+    },
+    /// Indexes a directory in-process, searches it, and exits (no agent is needed).
+    ///
+    /// Exit codes are the same as `search`.
+    Grep {
+        query: String,
+        dir: PathBuf,
+
+        /// Matches lines ignoring case (the files are indexed ignoring case as well).
+        #[arg(short, long)]
+        ignore_case: bool,
+
+        /// Indexes pairs of adjacent words to speed up phrase queries.
+        #[arg(long)]
+        bigrams: bool,
+
+        /// Indexes byte trigrams to speed up substring queries.
+        #[arg(long)]
+        trigrams: bool,
+
+        /// Prints each match as a JSON object.
+        #[arg(long)]
+        json: bool,
+
+        /// Maximum number of matches to print.
+        #[arg(long)]
+        max_results: Option<usize>,
+
+        /// Prints the number of the scanned files and the elapsed times to stderr.
+        #[arg(long)]
+        stats: bool,
+
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+}
+
+#[derive(clap::Args)]
+struct FilterArgs {
+    /// Indexes only the files matching a glob pattern (can be specified multiple times).
+    ///
+    /// Patterns are written in the syntax of `.gitignore`.
+    #[arg(long = "include", value_name = "GLOB")]
+    includes: Vec<String>,
+
+    /// Skips the files and directories matching a glob pattern (can be specified multiple times).
+    #[arg(long = "exclude", value_name = "GLOB")]
+    excludes: Vec<String>,
+
+    /// Honors `.gitignore` and `.dgignore` files, and skips `.git` directories.
+    #[arg(long)]
+    ignore_files: bool,
+
+    /// Follows symbolic links to files and directories.
+    #[arg(long)]
+    follow_symlinks: bool,
+}
+impl FilterArgs {
+    fn into_options(self) -> watch::fs::WatchOptions {
+        watch::fs::WatchOptions {
+            include: self.includes,
+            exclude: self.excludes,
+            ignore_files: self.ignore_files,
+            follow_symlinks: self.follow_symlinks,
+        }
+    }
 }
 
 fn main() {
@@ -26,8 +190,103 @@ fn main() {
         Args::Watch { dir } => {
             handle_watch(dir);
         }
-        Args::Agent { dir } => {
-            handle_agent(dir);
+        Args::Agent {
+            dir,
+            bigrams,
+            trigrams,
+            ignore_case,
+            block_size_mib,
+            listen,
+            coordinator,
+            advertise_addr,
+            heartbeat_interval_secs,
+            admin_socket,
+            admin_socket_mode,
+            filter,
+        } => {
+            let options = agent::IndexOptions {
+                bigrams,
+                trigrams,
+                case_insensitive: ignore_case,
+                block_size: block_size_mib * 1024 * 1024,
+                ..Default::default()
+            };
+            let registration = coordinator.map(|coordinator| Registration {
+                coordinator,
+                advertise_addr: advertise_addr.unwrap_or(listen),
+                heartbeat_interval: Duration::from_secs(heartbeat_interval_secs),
+            });
+            let admin_socket = admin_socket.map(|path| (path, admin_socket_mode));
+            let watch_options = filter.into_options();
+            handle_agent(
+                dir,
+                options,
+                watch_options,
+                listen,
+                registration,
+                admin_socket,
+            );
+        }
+        Args::Coordinator {
+            agents,
+            listen,
+            agent_timeout_ms,
+            agent_ttl_secs,
+        } => {
+            let options = coordinator::CoordinatorOptions {
+                agent_timeout: Duration::from_millis(agent_timeout_ms),
+                agent_ttl: Duration::from_secs(agent_ttl_secs),
+            };
+            handle_coordinator(agents, options, listen);
+        }
+        Args::Search {
+            query,
+            agent,
+            ignore_case,
+            json,
+            max_results,
+            timeout_ms,
+        } => {
+            let options = agent::SearchOptions {
+                ignore_case,
+                max_results,
+                ..Default::default()
+            };
+            let params = rpc::QueryParams::new(query, &options);
+            let timeout = Duration::from_millis(timeout_ms);
+            process::exit(handle_search(&agent, params, timeout, json));
+        }
+        Args::Grep {
+            query,
+            dir,
+            ignore_case,
+            bigrams,
+            trigrams,
+            json,
+            max_results,
+            stats,
+            filter,
+        } => {
+            let index_options = agent::IndexOptions {
+                bigrams,
+                trigrams,
+                case_insensitive: ignore_case,
+                ..Default::default()
+            };
+            let search_options = agent::SearchOptions {
+                ignore_case,
+                max_results,
+                ..Default::default()
+            };
+            process::exit(handle_grep(
+                &query,
+                dir,
+                index_options,
+                &filter.into_options(),
+                search_options,
+                json,
+                stats,
+            ));
         }
     }
 }
@@ -39,8 +298,10 @@ fn handle_watch(dir: PathBuf) {
     let handle = executor.handle();
     executor.spawn(
         watcher
-            .for_each(move |file| {
-                handle.spawn(file.for_each(|_content| Ok(())).map_err(|_e| ()));
+            .for_each(move |event| {
+                if let watch::fs::FileSystemEvent::Watch(file) = event {
+                    handle.spawn(file.for_each(|_content| Ok(())).map_err(|_e| ()));
+                }
                 Ok(())
             })
             .then(|_| Ok(())),
@@ -48,13 +309,206 @@ fn handle_watch(dir: PathBuf) {
     executor.run().unwrap();
 }
 
-fn handle_agent(dir: PathBuf) {
+struct Registration {
+    coordinator: SocketAddr,
+    advertise_addr: SocketAddr,
+    heartbeat_interval: Duration,
+}
+
+fn handle_agent(
+    dir: PathBuf,
+    options: agent::IndexOptions,
+    watch_options: watch::fs::WatchOptions,
+    listen: SocketAddr,
+    registration: Option<Registration>,
+    admin_socket: Option<(PathBuf, u32)>,
+) {
     let executor = InPlaceExecutor::new().unwrap();
-    let mut watcher = watch::fs::FileSystemWatcher::new(executor.handle());
-    track_try_unwrap!(watcher.watch(dir));
+    let watcher = watch::fs::FileSystemWatcher::new(executor.handle());
 
     fibers_tasque::DefaultIoTaskQueue.get().set_worker_count(1);
-    let agent = agent::Agent::new(executor.handle(), watcher);
+    let mut agent = agent::Agent::new(executor.handle(), watcher);
+    track_try_unwrap!(agent.watch_with_options(dir, options, &watch_options));
+    let server = rpc::RpcServer::new(executor.handle(), listen, agent.handle());
+    executor.spawn(server.map_err(|e| panic!("{}", e)));
+    if let Some((path, mode)) = admin_socket {
+        let server = track_try_unwrap!(rpc::UnixRpcServer::new(
+            executor.handle(),
+            path,
+            mode,
+            agent.handle()
+        ));
+        executor.spawn(server.map_err(|e| panic!("{}", e)));
+    }
+    if let Some(r) = registration {
+        let heartbeat = coordinator::Heartbeat::new(
+            r.coordinator,
+            r.advertise_addr,
+            agent.handle(),
+            r.heartbeat_interval,
+        );
+        executor.spawn(heartbeat.map_err(|e| panic!("{}", e)));
+    }
     executor.spawn(agent.map_err(|e| panic!("{}", e)));
     executor.run().unwrap();
 }
+
+fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8).map_err(|e| e.to_string())
+}
+
+fn handle_coordinator(
+    agents: Vec<SocketAddr>,
+    options: coordinator::CoordinatorOptions,
+    listen: SocketAddr,
+) {
+    let executor = InPlaceExecutor::new().unwrap();
+    let coordinator = coordinator::Coordinator::new(agents, options);
+    let server = rpc::RpcServer::new(executor.handle(), listen, coordinator.handle());
+    executor.spawn(server.map_err(|e| panic!("{}", e)));
+    executor.spawn(coordinator.map_err(|e| panic!("{}", e)));
+    executor.run().unwrap();
+}
+
+fn handle_search(agent: &str, params: rpc::QueryParams, timeout: Duration, json: bool) -> i32 {
+    if let Err(e) = params.query.parse::<Query>() {
+        eprintln!("dg: {}", rpc::error_message(&e));
+        return 2;
+    }
+    let addr = match agent.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => addr,
+        Ok(None) => {
+            eprintln!("dg: {}: no addresses", agent);
+            return 2;
+        }
+        Err(e) => {
+            eprintln!("dg: {}: {}", agent, e);
+            return 2;
+        }
+    };
+
+    let mut executor = InPlaceExecutor::new().unwrap();
+    let call = rpc::Call::with_method(addr, rpc::Method::Search(params)).timeout_after(timeout);
+    let monitor = executor.spawn_monitor(call);
+    let response = match executor.run_fiber(monitor).unwrap() {
+        Err(e) => {
+            let message = match e {
+                MonitorError::Failed(None) => "timed out".to_owned(),
+                MonitorError::Failed(Some(e)) => rpc::error_message(&e),
+                MonitorError::Aborted => rpc::error_message(&MonitorError::Aborted.into()),
+            };
+            eprintln!("dg: {}: {}", agent, message);
+            return 2;
+        }
+        Ok(response) => response,
+    };
+    let result = match response.body {
+        rpc::ResponseBody::Search(result) => result,
+        rpc::ResponseBody::Error { message } => {
+            eprintln!("dg: {}", message);
+            return 2;
+        }
+        body => {
+            eprintln!("dg: unexpected response: {:?}", body);
+            return 2;
+        }
+    };
+
+    print_search_result(&result, json)
+}
+
+fn handle_grep(
+    query: &str,
+    dir: PathBuf,
+    index_options: agent::IndexOptions,
+    watch_options: &watch::fs::WatchOptions,
+    search_options: agent::SearchOptions,
+    json: bool,
+    stats: bool,
+) -> i32 {
+    let query = match query.parse::<Query>() {
+        Err(e) => {
+            eprintln!("dg: {}", rpc::error_message(&e));
+            return 2;
+        }
+        Ok(query) => query,
+    };
+
+    let mut executor = InPlaceExecutor::new().unwrap();
+    let watcher = watch::fs::FileSystemWatcher::oneshot(executor.handle());
+    let mut agent = agent::Agent::new(executor.handle(), watcher);
+    if let Err(e) = agent.watch_with_options(&dir, index_options, watch_options) {
+        eprintln!("dg: {:?}: {}", dir, rpc::error_message(&e));
+        return 2;
+    }
+    let handle = agent.handle();
+    executor.spawn(agent.map_err(|e| panic!("{}", e)));
+
+    let start = Instant::now();
+    let monitor = executor.spawn_monitor(handle.wait_scanned());
+    if let Err(e) = executor.run_fiber(monitor).unwrap() {
+        eprintln!("dg: {}", rpc::error_message(&e.into()));
+        return 2;
+    }
+    let indexed = Instant::now();
+    let monitor = executor.spawn_monitor(handle.search(query, search_options));
+    let result = match executor.run_fiber(monitor).unwrap() {
+        Err(e) => {
+            eprintln!("dg: {}", rpc::error_message(&e.into()));
+            return 2;
+        }
+        Ok(result) => result,
+    };
+    if stats {
+        eprintln!(
+            "dg: scanned {} of {} files{} (indexing: {:?}, search: {:?})",
+            result.scanned_files,
+            result.total_files,
+            if result.full_scan { ", full scan" } else { "" },
+            indexed.duration_since(start),
+            indexed.elapsed()
+        );
+    }
+    print_search_result(&result.into(), json)
+}
+
+fn print_search_result(result: &rpc::SearchResponse, json: bool) -> i32 {
+    let stdout = io::stdout();
+    let mut stdout = io::BufWriter::new(stdout.lock());
+    for m in &result.matches {
+        let written = if json {
+            serde_json::to_writer(&mut stdout, m)
+                .map_err(io::Error::from)
+                .and_then(|()| writeln!(stdout))
+        } else if let Some(ref host) = m.host {
+            writeln!(stdout, "{}:{}:{}:{}", host, m.path, m.line_number, m.line)
+        } else {
+            writeln!(stdout, "{}:{}:{}", m.path, m.line_number, m.line)
+        };
+        if written.is_err() {
+            // e.g., the output is piped to `head`
+            break;
+        }
+    }
+    let _ = stdout.flush();
+
+    for failure in &result.failures {
+        eprintln!("dg: {}: {}", failure.agent, failure.message);
+    }
+    if result.failed_files > 0 {
+        eprintln!(
+            "dg: failed to read {} file(s) to the end; some matches may be missing",
+            result.failed_files
+        );
+    }
+    if result.truncated {
+        eprintln!("dg: too many matches; the rest are omitted (use --max-results)");
+    }
+    if result.partial {
+        2
+    } else if result.matches.is_empty() {
+        1
+    } else {
+        0
+    }
+}
@@ -0,0 +1,609 @@
+use regex::bytes::{Regex, RegexBuilder};
+use regex_syntax::hir::{Hir, HirKind};
+use std;
+use std::str::FromStr;
+
+use tokenize::{self, WordTokenizer};
+use {Error, ErrorKind, Result};
+
+/// Query expression.
+///
+/// # Syntax
+///
+/// ```text
+/// QUERY := OR_EXPR
+/// OR_EXPR := AND_EXPR ("OR" AND_EXPR)*
+/// AND_EXPR := UNARY_EXPR (["AND"] UNARY_EXPR)*
+/// UNARY_EXPR := "NOT" UNARY_EXPR | "(" OR_EXPR ")" | TERM | "\"" PHRASE "\"" | "/" REGEX "/"
+/// ```
+///
+/// Terms and phrases are split into words by `WordTokenizer`,
+/// and those consisting of multiple words match the lines containing the words consecutively.
+///
+/// A term starting and/or ending with `*` is a substring pattern:
+/// `*foo*` matches any occurrence of `foo`, `foo*` matches `foo` at the beginning of a word,
+/// and `*foo` matches `foo` at the end of a word.
+///
+/// In a regular expression, `/` needs to be escaped as `\/`.
+/// The literals which any match must contain are extracted from it and used for pruning.
+///
+/// Queries are evaluated line by line (e.g., `foo AND bar` matches the lines containing both words).
+#[derive(Debug, Clone)]
+pub enum Query {
+    Word(String),
+    Phrase(Vec<String>),
+    Substring {
+        text: String,
+        word_start: bool,
+        word_end: bool,
+    },
+    Regex {
+        regex: Regex,
+        required: Option<Box<Query>>,
+    },
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+    Not(Box<Query>),
+}
+impl Query {
+    /// Returns `false` if it is certain that no line in `filter` satisfies the query.
+    ///
+    /// Because a negated word can not be judged by approximate membership tests,
+    /// `NOT` expressions never prune the target.
+    pub fn may_match<F: Filter>(&self, filter: &F) -> bool {
+        match *self {
+            Query::Word(ref w) => filter.contains_word(w),
+            Query::Phrase(ref ws) => {
+                ws.iter().all(|w| filter.contains_word(w))
+                    && ws.windows(2)
+                        .all(|pair| filter.contains_bigram(&pair[0], &pair[1]))
+            }
+            Query::Substring { ref text, .. } => text.as_bytes()
+                .windows(3)
+                .all(|trigram| filter.contains_trigram(trigram)),
+            Query::Regex { ref required, .. } => {
+                required.as_ref().is_none_or(|q| q.may_match(filter))
+            }
+            Query::And(ref a, ref b) => a.may_match(filter) && b.may_match(filter),
+            Query::Or(ref a, ref b) => a.may_match(filter) || b.may_match(filter),
+            Query::Not(_) => true,
+        }
+    }
+
+    /// Returns the case-folded version of the query.
+    ///
+    /// The literals in the query are converted by `tokenize::fold_case`,
+    /// and regular expressions are made case-insensitive.
+    pub fn to_folded(&self) -> Query {
+        match *self {
+            Query::Word(ref w) => Query::Word(tokenize::fold_case(w)),
+            Query::Phrase(ref ws) => Query::Phrase(ws.iter().map(|w| tokenize::fold_case(w)).collect()),
+            Query::Substring {
+                ref text,
+                word_start,
+                word_end,
+            } => Query::Substring {
+                text: tokenize::fold_case(text),
+                word_start,
+                word_end,
+            },
+            Query::Regex {
+                ref regex,
+                ref required,
+            } => {
+                let regex = RegexBuilder::new(regex.as_str())
+                    .case_insensitive(true)
+                    .build()
+                    .expect("Never fails");
+                let required = required.as_ref().map(|q| Box::new(q.to_folded()));
+                Query::Regex { regex, required }
+            }
+            Query::And(ref a, ref b) => Query::And(Box::new(a.to_folded()), Box::new(b.to_folded())),
+            Query::Or(ref a, ref b) => Query::Or(Box::new(a.to_folded()), Box::new(b.to_folded())),
+            Query::Not(ref a) => Query::Not(Box::new(a.to_folded())),
+        }
+    }
+
+    /// Returns `true` if the query contains no literals usable for pruning,
+    /// which means that every file needs to be scanned.
+    ///
+    /// `bigrams` and `trigrams` tell whether the index contains word pairs and byte trigrams;
+    /// if not, phrases can only be pruned by their words and substrings can not be pruned at all.
+    pub fn needs_full_scan(&self, bigrams: bool, trigrams: bool) -> bool {
+        self.may_match(&EmptyFilter { bigrams, trigrams })
+    }
+
+    /// Returns `true` if the given line satisfies the query.
+    pub fn is_match(&self, line: &[u8]) -> bool {
+        let words = WordTokenizer::new(line)
+            .filter_map(|w| w.ok())
+            .map(|(_, w)| w)
+            .collect::<Vec<_>>();
+        self.is_match_words(line, &words)
+    }
+
+    fn is_match_words(&self, line: &[u8], words: &[&str]) -> bool {
+        match *self {
+            Query::Word(ref w) => words.contains(&w.as_str()),
+            Query::Phrase(ref ws) => words.windows(ws.len()).any(|window| window == &ws[..]),
+            Query::Substring {
+                ref text,
+                word_start,
+                word_end,
+            } => {
+                let text = text.as_bytes();
+                line.windows(text.len()).enumerate().any(|(i, window)| {
+                    let is_start_ok = !word_start || i == 0 || tokenize::is_delimiter(line[i - 1]);
+                    let is_end_ok = !word_end
+                        || line.get(i + text.len())
+                            .is_none_or(|&b| tokenize::is_delimiter(b));
+                    window == text && is_start_ok && is_end_ok
+                })
+            }
+            Query::Regex { ref regex, .. } => regex.is_match(trim_newline(line)),
+            Query::And(ref a, ref b) => {
+                a.is_match_words(line, words) && b.is_match_words(line, words)
+            }
+            Query::Or(ref a, ref b) => {
+                a.is_match_words(line, words) || b.is_match_words(line, words)
+            }
+            Query::Not(ref a) => !a.is_match_words(line, words),
+        }
+    }
+}
+impl FromStr for Query {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let tokens = track!(lex(s))?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            position: 0,
+        };
+        let query = track!(parser.parse_or_expr())?;
+        track_assert!(
+            parser.position == tokens.len(),
+            ErrorKind::InvalidInput,
+            "unexpected token {:?} in query {:?}",
+            tokens[parser.position],
+            s
+        );
+        Ok(query)
+    }
+}
+
+/// Approximate membership test used for pruning targets of a query.
+///
+/// Implementations may return false positives but must not return false negatives.
+pub trait Filter {
+    /// Returns `false` if it is certain that `word` does not appear in the target.
+    fn contains_word(&self, word: &str) -> bool;
+
+    /// Returns `false` if it is certain that `first` immediately followed by `second`
+    /// does not appear in the target.
+    ///
+    /// Implementations which do not index word pairs must return `true`.
+    fn contains_bigram(&self, first: &str, second: &str) -> bool;
+
+    /// Returns `false` if it is certain that the byte sequence `trigram` does not appear in the target.
+    ///
+    /// Implementations which do not index trigrams must return `true`.
+    fn contains_trigram(&self, trigram: &[u8]) -> bool;
+}
+
+// Filter of an empty target, which still returns `true` for the kinds of entries not indexed
+#[derive(Debug)]
+struct EmptyFilter {
+    bigrams: bool,
+    trigrams: bool,
+}
+impl Filter for EmptyFilter {
+    fn contains_word(&self, _word: &str) -> bool {
+        false
+    }
+    fn contains_bigram(&self, _first: &str, _second: &str) -> bool {
+        !self.bigrams
+    }
+    fn contains_trigram(&self, _trigram: &[u8]) -> bool {
+        !self.trigrams
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    Term(String),
+    Phrase(String),
+    Regex(String),
+}
+
+fn lex(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '"' => {
+                let mut phrase = String::new();
+                loop {
+                    match chars.next() {
+                        None => track_panic!(ErrorKind::InvalidInput, "unterminated phrase: {:?}", s),
+                        Some('"') => break,
+                        Some(c) => phrase.push(c),
+                    }
+                }
+                tokens.push(Token::Phrase(phrase));
+            }
+            '/' => {
+                let mut regex = String::new();
+                loop {
+                    match chars.next() {
+                        None => track_panic!(ErrorKind::InvalidInput, "unterminated regex: {:?}", s),
+                        Some('/') => break,
+                        Some('\\') if chars.peek() == Some(&'/') => {
+                            regex.push('/');
+                            chars.next();
+                        }
+                        Some(c) => regex.push(c),
+                    }
+                }
+                tokens.push(Token::Regex(regex));
+            }
+            _ if c.is_whitespace() => {}
+            _ => {
+                let mut term = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+                        break;
+                    }
+                    term.push(c);
+                    chars.next();
+                }
+                let token = match term.as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => Token::Term(term),
+                };
+                tokens.push(token);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+}
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.position)
+    }
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+    fn parse_or_expr(&mut self) -> Result<Query> {
+        let mut query = track!(self.parse_and_expr())?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            let right = track!(self.parse_and_expr())?;
+            query = Query::Or(Box::new(query), Box::new(right));
+        }
+        Ok(query)
+    }
+    fn parse_and_expr(&mut self) -> Result<Query> {
+        let mut query = track!(self.parse_unary_expr())?;
+        loop {
+            match self.peek() {
+                None | Some(&Token::Or) | Some(&Token::Close) => break,
+                Some(&Token::And) => {
+                    self.next();
+                }
+                _ => {}
+            }
+            let right = track!(self.parse_unary_expr())?;
+            query = Query::And(Box::new(query), Box::new(right));
+        }
+        Ok(query)
+    }
+    fn parse_unary_expr(&mut self) -> Result<Query> {
+        match self.next() {
+            Some(&Token::Not) => {
+                let query = track!(self.parse_unary_expr())?;
+                Ok(Query::Not(Box::new(query)))
+            }
+            Some(&Token::Open) => {
+                let query = track!(self.parse_or_expr())?;
+                track_assert_eq!(
+                    self.next(),
+                    Some(&Token::Close),
+                    ErrorKind::InvalidInput,
+                    "unbalanced parentheses"
+                );
+                Ok(query)
+            }
+            Some(Token::Term(term)) if term.starts_with('*') || term.ends_with('*') => {
+                track!(pattern_to_query(term))
+            }
+            Some(Token::Term(term)) | Some(Token::Phrase(term)) => track!(term_to_query(term)),
+            Some(Token::Regex(pattern)) => track!(regex_to_query(pattern)),
+            token => track_panic!(
+                ErrorKind::InvalidInput,
+                "unexpected token: {:?}",
+                token
+            ),
+        }
+    }
+}
+
+fn term_to_query(term: &str) -> Result<Query> {
+    let mut words = Vec::new();
+    for w in WordTokenizer::new(term.as_bytes()) {
+        let (_, w) = track!(w)?;
+        words.push(w.to_owned());
+    }
+    track_assert!(
+        !words.is_empty(),
+        ErrorKind::InvalidInput,
+        "no searchable words in term {:?}",
+        term
+    );
+    if words.len() == 1 {
+        Ok(Query::Word(words.remove(0)))
+    } else {
+        Ok(Query::Phrase(words))
+    }
+}
+
+fn pattern_to_query(pattern: &str) -> Result<Query> {
+    let word_start = !pattern.starts_with('*');
+    let word_end = !pattern.ends_with('*');
+    let text = pattern.trim_matches('*');
+    track_assert!(
+        !text.is_empty() && !text.contains('*'),
+        ErrorKind::InvalidInput,
+        "wildcards are only allowed at the beginning or end of a pattern: {:?}",
+        pattern
+    );
+    Ok(Query::Substring {
+        text: text.to_owned(),
+        word_start,
+        word_end,
+    })
+}
+
+fn regex_to_query(pattern: &str) -> Result<Query> {
+    let regex = track!(Regex::new(pattern).map_err(Error::from))?;
+    let hir = track!(regex_syntax::parse(pattern).map_err(Error::from))?;
+    let required = required_literals(&hir).map(Box::new);
+    Ok(Query::Regex { regex, required })
+}
+
+/// Makes a query which is satisfied by every line matching `hir`.
+///
+/// Returns `None` if there are no literals which any match must contain.
+fn required_literals(hir: &Hir) -> Option<Query> {
+    match *hir.kind() {
+        HirKind::Literal(ref literal) => literal_to_query(&literal.0),
+        HirKind::Capture(ref capture) => required_literals(&capture.sub),
+        HirKind::Repetition(ref repetition) if repetition.min > 0 => {
+            required_literals(&repetition.sub)
+        }
+        HirKind::Concat(ref hirs) => {
+            let mut queries = Vec::new();
+            let mut literal = Vec::new();
+            for hir in hirs {
+                if let HirKind::Literal(ref l) = *hir.kind() {
+                    literal.extend_from_slice(&l.0);
+                    continue;
+                }
+                queries.extend(literal_to_query(&literal));
+                literal.clear();
+                queries.extend(required_literals(hir));
+            }
+            queries.extend(literal_to_query(&literal));
+            queries
+                .into_iter()
+                .reduce(|a, b| Query::And(Box::new(a), Box::new(b)))
+        }
+        HirKind::Alternation(ref hirs) => {
+            let queries = hirs.iter().map(required_literals).collect::<Option<Vec<_>>>()?;
+            queries
+                .into_iter()
+                .reduce(|a, b| Query::Or(Box::new(a), Box::new(b)))
+        }
+        _ => None,
+    }
+}
+
+fn literal_to_query(literal: &[u8]) -> Option<Query> {
+    let text = std::str::from_utf8(literal).ok()?;
+    let mut queries = Vec::new();
+
+    // Only the words enclosed by delimiters within the literal are known to be complete words
+    for (start, word) in WordTokenizer::new(literal).filter_map(|w| w.ok()) {
+        let end = start + word.len();
+        if start > 0 && end < literal.len() {
+            queries.push(Query::Word(word.to_owned()));
+        }
+    }
+    if text.len() >= 3 {
+        queries.push(Query::Substring {
+            text: text.to_owned(),
+            word_start: false,
+            word_end: false,
+        });
+    }
+    queries
+        .into_iter()
+        .reduce(|a, b| Query::And(Box::new(a), Box::new(b)))
+}
+
+fn trim_newline(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn show(query: &Query) -> String {
+        match *query {
+            Query::Word(ref w) => w.clone(),
+            Query::Phrase(ref ws) => format!("{:?}", ws.join(" ")),
+            Query::Substring {
+                ref text,
+                word_start,
+                word_end,
+            } => format!(
+                "{}{}{}",
+                if word_start { "" } else { "*" },
+                text,
+                if word_end { "" } else { "*" }
+            ),
+            Query::Regex { ref regex, .. } => format!("/{}/", regex.as_str()),
+            Query::And(ref a, ref b) => format!("({} AND {})", show(a), show(b)),
+            Query::Or(ref a, ref b) => format!("({} OR {})", show(a), show(b)),
+            Query::Not(ref a) => format!("NOT {}", show(a)),
+        }
+    }
+
+    fn parse(s: &str) -> String {
+        show(&s.parse::<Query>().expect("Never fails"))
+    }
+
+    fn literals(pattern: &str) -> Option<String> {
+        let hir = regex_syntax::parse(pattern).expect("Never fails");
+        required_literals(&hir).map(|q| show(&q))
+    }
+
+    struct Words(&'static [&'static str]);
+    impl Filter for Words {
+        fn contains_word(&self, word: &str) -> bool {
+            self.0.contains(&word)
+        }
+        fn contains_bigram(&self, _first: &str, _second: &str) -> bool {
+            true
+        }
+        fn contains_trigram(&self, _trigram: &[u8]) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(parse("a OR b c"), "(a OR (b AND c))");
+        assert_eq!(parse("a b OR c"), "((a AND b) OR c)");
+        assert_eq!(parse("a AND b OR c AND d"), "((a AND b) OR (c AND d))");
+        assert_eq!(parse("a (b OR c)"), "(a AND (b OR c))");
+        assert_eq!(parse("a b c"), "((a AND b) AND c)");
+    }
+
+    #[test]
+    fn not_applies_to_the_next_unary_expr() {
+        assert_eq!(parse("NOT a b"), "(NOT a AND b)");
+        assert_eq!(parse("NOT (a OR b)"), "NOT (a OR b)");
+        assert_eq!(parse("NOT NOT a"), "NOT NOT a");
+        assert_eq!(parse("a OR NOT b"), "(a OR NOT b)");
+    }
+
+    #[test]
+    fn terms_and_patterns() {
+        assert_eq!(parse("foo-bar"), "\"foo bar\"");
+        assert_eq!(parse("\"foo bar\" baz"), "(\"foo bar\" AND baz)");
+        assert_eq!(parse("*oo*"), "*oo*");
+        assert_eq!(parse("fo*"), "fo*");
+        assert_eq!(parse("*oo"), "*oo");
+        assert_eq!(parse("/a\\/b/"), "/a/b/");
+    }
+
+    #[test]
+    fn invalid_queries() {
+        for s in &["", "(a", "a)", "OR a", "a OR", "NOT", "\"a", "/a", "*f*o", "*", "--"] {
+            assert!(s.parse::<Query>().is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn not_matches_lines_without_the_operand() {
+        let query: Query = "foo NOT bar".parse().expect("Never fails");
+        assert!(query.is_match(b"foo baz\n"));
+        assert!(!query.is_match(b"foo bar\n"));
+        assert!(!query.is_match(b"baz\n"));
+
+        let query: Query = "NOT (foo OR bar)".parse().expect("Never fails");
+        assert!(query.is_match(b"baz\n"));
+        assert!(!query.is_match(b"bar\n"));
+    }
+
+    #[test]
+    fn not_never_prunes() {
+        let filter = Words(&["foo"]);
+        let query: Query = "NOT foo".parse().expect("Never fails");
+        assert!(query.may_match(&filter));
+        let query: Query = "NOT bar".parse().expect("Never fails");
+        assert!(query.may_match(&filter));
+        let query: Query = "foo NOT foo".parse().expect("Never fails");
+        assert!(query.may_match(&filter));
+        let query: Query = "bar NOT foo".parse().expect("Never fails");
+        assert!(!query.may_match(&filter));
+        let query: Query = "bar OR NOT foo".parse().expect("Never fails");
+        assert!(query.may_match(&filter));
+    }
+
+    #[test]
+    fn full_scan_depends_on_indexed_kinds() {
+        let query: Query = "*foo*".parse().expect("Never fails");
+        assert!(query.needs_full_scan(true, false));
+        assert!(!query.needs_full_scan(false, true));
+
+        let query: Query = "/time.*up/".parse().expect("Never fails");
+        assert!(query.needs_full_scan(false, false));
+        assert!(!query.needs_full_scan(false, true));
+
+        let query: Query = "\"foo bar\"".parse().expect("Never fails");
+        assert!(!query.needs_full_scan(false, false));
+
+        let query: Query = "NOT foo".parse().expect("Never fails");
+        assert!(query.needs_full_scan(true, true));
+    }
+
+    #[test]
+    fn required_literals_of_concatenations() {
+        assert_eq!(literals("foo"), Some("*foo*".to_owned()));
+        assert_eq!(literals("foo.*bar"), Some("(*foo* AND *bar*)".to_owned()));
+        assert_eq!(literals("time.*up"), Some("*time*".to_owned()));
+        assert_eq!(literals(" error: "), Some("(error AND * error: *)".to_owned()));
+        assert_eq!(literals("ab"), None);
+        assert_eq!(literals(".*"), None);
+    }
+
+    #[test]
+    fn required_literals_of_alternations() {
+        assert_eq!(literals("foo|bar"), Some("(*foo* OR *bar*)".to_owned()));
+        assert_eq!(
+            literals("(foo|bar)baz"),
+            Some("((*foo* OR *bar*) AND *baz*)".to_owned())
+        );
+        // Every branch must contain a usable literal
+        assert_eq!(literals("foo|b"), None);
+        assert_eq!(literals("(foo|b)baz"), Some("*baz*".to_owned()));
+    }
+
+    #[test]
+    fn required_literals_of_repetitions() {
+        assert_eq!(literals("(abc)+"), Some("*abc*".to_owned()));
+        assert_eq!(literals("(abc){2,}x"), Some("*abc*".to_owned()));
+        assert_eq!(literals("(abc)*"), None);
+        assert_eq!(literals("(abc)?def"), Some("*def*".to_owned()));
+    }
+}
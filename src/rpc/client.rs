@@ -0,0 +1,59 @@
+use fibers::net::futures::Connect;
+use fibers::net::TcpStream;
+use futures::{Async, Future, Poll};
+use std::net::SocketAddr;
+
+use super::lines::JsonLines;
+use super::{Method, Request, Response};
+use {Error, ErrorKind};
+
+/// A future which issues a request to an RPC server and waits for the response.
+///
+/// A new connection is established for each call.
+/// This is not suitable for `subscribe` requests which have multiple responses.
+#[derive(Debug)]
+pub struct Call {
+    connect: Option<Connect>,
+    lines: Option<JsonLines<TcpStream>>,
+    request: Request,
+}
+impl Call {
+    pub fn new(server: SocketAddr, request: Request) -> Self {
+        Call {
+            connect: Some(TcpStream::connect(server)),
+            lines: None,
+            request,
+        }
+    }
+
+    /// Makes a call with a request consisting of `method` and an arbitrary identifier.
+    pub fn with_method(server: SocketAddr, method: Method) -> Self {
+        Self::new(server, Request::new(0, method))
+    }
+}
+impl Future for Call {
+    type Item = Response;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::Ready(Some(stream)) = track!(self.connect.poll().map_err(Error::from))? {
+            self.connect = None;
+            let mut lines = JsonLines::new(stream);
+            track!(lines.start_send(&self.request))?;
+            self.lines = Some(lines);
+        }
+        if let Some(ref mut lines) = self.lines {
+            track!(lines.poll_flush())?;
+            while let Async::Ready(response) = track!(lines.poll_message::<Response>())? {
+                let response = track_assert_some!(
+                    response,
+                    ErrorKind::Other,
+                    "Connection closed by the server"
+                );
+                if response.id == self.request.id {
+                    return Ok(Async::Ready(response));
+                }
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
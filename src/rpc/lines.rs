@@ -0,0 +1,108 @@
+use futures::{Async, Poll};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+use std::io::{self, Read, Write};
+
+use {Error, ErrorKind, Result};
+
+const MAX_LINE_LEN: usize = 16 * 1024 * 1024;
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A non-blocking stream of newline-delimited JSON messages.
+#[derive(Debug)]
+pub struct JsonLines<S> {
+    stream: S,
+    read_buf: Vec<u8>,
+
+    // The number of the bytes at the head of `read_buf` which are known to contain no newlines
+    scanned: usize,
+
+    write_buf: Vec<u8>,
+    written: usize,
+    is_eos: bool,
+}
+impl<S: Read + Write> JsonLines<S> {
+    pub fn new(stream: S) -> Self {
+        JsonLines {
+            stream,
+            read_buf: Vec::new(),
+            scanned: 0,
+            write_buf: Vec::new(),
+            written: 0,
+            is_eos: false,
+        }
+    }
+
+    /// Returns the number of bytes which have not been written to the stream yet.
+    pub fn pending_bytes(&self) -> usize {
+        self.write_buf.len() - self.written
+    }
+
+    /// Polls the next line.
+    ///
+    /// Returns `Ok(Async::Ready(None))` if the peer has closed the stream.
+    pub fn poll_line(&mut self) -> Poll<Option<Vec<u8>>, Error> {
+        loop {
+            if let Some(n) = self.read_buf[self.scanned..].iter().position(|&b| b == b'\n') {
+                let line = self.read_buf.drain(..self.scanned + n + 1).collect();
+                self.scanned = 0;
+                return Ok(Async::Ready(Some(line)));
+            }
+            self.scanned = self.read_buf.len();
+            if self.is_eos {
+                return Ok(Async::Ready(None));
+            }
+            track_assert!(
+                self.read_buf.len() <= MAX_LINE_LEN,
+                ErrorKind::InvalidInput,
+                "too long message"
+            );
+
+            let mut buf = [0; READ_BUFFER_SIZE];
+            match self.stream.read(&mut buf) {
+                Ok(0) => self.is_eos = true,
+                Ok(size) => self.read_buf.extend_from_slice(&buf[..size]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(track!(Error::from(e))),
+            }
+        }
+    }
+
+    /// Polls the next message.
+    pub fn poll_message<T: DeserializeOwned>(&mut self) -> Poll<Option<T>, Error> {
+        if let Async::Ready(line) = track!(self.poll_line())? {
+            let message = match line {
+                None => None,
+                Some(line) => Some(track!(serde_json::from_slice(&line).map_err(Error::from))?),
+            };
+            Ok(Async::Ready(message))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    /// Enqueues a message to be sent.
+    ///
+    /// The message will be written to the stream by `poll_flush`.
+    pub fn start_send<T: Serialize>(&mut self, message: &T) -> Result<()> {
+        track!(serde_json::to_writer(&mut self.write_buf, message).map_err(Error::from))?;
+        self.write_buf.push(b'\n');
+        Ok(())
+    }
+
+    /// Writes the enqueued messages to the stream.
+    pub fn poll_flush(&mut self) -> Poll<(), Error> {
+        while self.written < self.write_buf.len() {
+            match self.stream.write(&self.write_buf[self.written..]) {
+                Ok(0) => track_panic!(ErrorKind::Other, "write zero"),
+                Ok(size) => self.written += size,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(track!(Error::from(e))),
+            }
+        }
+        self.write_buf.clear();
+        self.written = 0;
+        Ok(Async::Ready(()))
+    }
+}
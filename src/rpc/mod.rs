@@ -0,0 +1,518 @@
+//! RPC protocol for querying agents remotely.
+//!
+//! # Protocol
+//!
+//! Messages are JSON objects, each of which is terminated by a newline (`\n`).
+//! A client sends requests over a TCP connection, and the server replies a response
+//! (or a stream of responses for `subscribe`) to each request.
+//! Responses may be returned in a different order from the requests,
+//! so they are associated with the requests by the `id` field.
+//!
+//! Every message has a `version` field which must be equal to `PROTOCOL_VERSION`.
+//!
+//! ## Requests
+//!
+//! ```text
+//! {"version": 1, "id": ID, "method": "lookup", "query": QUERY, ...SEARCH_OPTIONS}
+//! {"version": 1, "id": ID, "method": "search", "query": QUERY, ...SEARCH_OPTIONS}
+//! {"version": 1, "id": ID, "method": "subscribe", "query": QUERY, ...SEARCH_OPTIONS}
+//! {"version": 1, "id": ID, "method": "unsubscribe", "target": SUBSCRIBE_REQUEST_ID}
+//! {"version": 1, "id": ID, "method": "heartbeat", "addr": ADDRESS, "roots": [STRING...], "stats": STATS,
+//!  "summary_version": INTEGER, "summary": STRING}
+//! {"version": 1, "id": ID, "method": "agents"}
+//! {"version": 1, "id": ID, "method": "files"}
+//! {"version": 1, "id": ID, "method": "reindex", "path": STRING}
+//! {"version": 1, "id": ID, "method": "progress"}
+//! ```
+//!
+//! `heartbeat` and `agents` are handled only by coordinators.
+//! An agent registers itself to a coordinator by sending `heartbeat` requests periodically,
+//! and `agents` returns the agents known by the coordinator.
+//!
+//! `files`, `reindex` and `progress` are admin operations of agents,
+//! which are accepted only on the Unix domain socket served by `UnixRpcServer`.
+//! Access to them is controlled by the permission bits of the socket file.
+//! `reindex` discards the indices of the files under the path and reads them again.
+//! The path of a member of an archive reindexes the whole archive.
+//!
+//! `summary` is a Bloom filter of the words indexed by the agent (see `agent::Summary`),
+//! which is used by the coordinator to skip the agents that can not satisfy a query.
+//! It is omitted if it is the same as the `summary_version` held by the coordinator,
+//! which is reported in `heartbeat` responses.
+//! Since the summary is updated only by heartbeats, agents whose `files` or `indexed_bytes`
+//! changed since their previous heartbeats are not skipped.
+//! Words indexed by idle agents after their last heartbeats may still be missed
+//! by the agents skipped on the basis of it (see `skipped_agents`).
+//!
+//! `QUERY` is a string in the syntax of `query::Query`.
+//! `SEARCH_OPTIONS` are the following optional fields:
+//!
+//! - `"ignore_case"`: boolean
+//! - `"since"`: UNIX timestamp in seconds
+//! - `"until"`: UNIX timestamp in seconds
+//! - `"max_results"`: integer (only for `search`)
+//!
+//! ## Responses
+//!
+//! ```text
+//! {"version": 1, "id": ID, "type": "lookup", "paths": [PATH...], ...COMPLETENESS}
+//! {"version": 1, "id": ID, "type": "search", "matches": [MATCH...],
+//!  "scanned_files": INTEGER, "total_files": INTEGER, "full_scan": BOOLEAN,
+//!  "failed_files": INTEGER, "truncated": BOOLEAN, ...COMPLETENESS}
+//! {"version": 1, "id": ID, "type": "matched", ...MATCH}
+//! {"version": 1, "id": ID, "type": "dropped", "count": INTEGER}
+//! {"version": 1, "id": ID, "type": "unsubscribed"}
+//! {"version": 1, "id": ID, "type": "heartbeat", "ttl_secs": INTEGER, "summary_version": INTEGER}
+//! {"version": 1, "id": ID, "type": "agents", "agents": [AGENT...]}
+//! {"version": 1, "id": ID, "type": "files", "files": [FILE...]}
+//! {"version": 1, "id": ID, "type": "reindexed", "files": INTEGER}
+//! {"version": 1, "id": ID, "type": "progress", "files": INTEGER, "pending_files": INTEGER,
+//!  "failed_files": INTEGER, "indexed_bytes": INTEGER, "total_bytes": INTEGER, "rescans": INTEGER,
+//!  "overflows": INTEGER}
+//! {"version": 1, "id": ID, "type": "error", "message": STRING}
+//! ```
+//!
+//! `PATH` is an object of the form `{"host": HOST, "path": STRING}`, and
+//! `MATCH` is an object of the form
+//! `{"host": HOST, "path": STRING, "line_number": INTEGER, "offset": INTEGER, "line": STRING}`.
+//! `HOST` is the address of the agent which owns the file.
+//! It is omitted in the responses from agents themselves.
+//!
+//! Every message must fit in a line of 16 MiB, so the matches of a `search` response
+//! are limited to `MAX_SEARCH_RESPONSE_BYTES` and the response is marked as `truncated`
+//! if any matches are omitted.
+//! `max_results` or a more specific query is needed to get all of them then.
+//! `failed_files` is the number of the files which could not be read to the end
+//! (e.g., broken gzip files; see `FILE` below), and is omitted if zero.
+//!
+//! `COMPLETENESS` are the following fields:
+//!
+//! - `"partial"`: boolean, `true` if some agents failed to reply and the result is incomplete
+//!   (or some files could not be read to the end, in the case of `search`)
+//! - `"failures"`: array of `{"agent": ADDRESS, "message": STRING}` (omitted if empty)
+//! - `"skipped_agents"`: integer, the number of the agents which were not asked
+//!   because their summaries show no matches (omitted if zero)
+//!
+//! `STATS` is an object of the form
+//! `{"files": INTEGER, "blocks": INTEGER, "indexed_bytes": INTEGER, "filter_bytes": INTEGER,
+//! "rescans": INTEGER, "overflows": INTEGER}`, and
+//! `AGENT` is an object of the form
+//! `{"addr": ADDRESS, "roots": [STRING...], "stats": STATS, "expires_in_secs": INTEGER,
+//! "summary_version": INTEGER}`.
+//! `stats`, `expires_in_secs` and `summary_version` are omitted
+//! for the agents given statically to the coordinator.
+//!
+//! `FILE` is an object of the form
+//! `{"path": STRING, "size": INTEGER, "indexed_bytes": INTEGER, "blocks": INTEGER,
+//! "pending": BOOLEAN, "error": STRING}`.
+//! `size` is omitted if the file is not accessible.
+//! For compressed files, `size` is the decompressed size, which is omitted until the end is read.
+//! A file is pending while it is being indexed.
+//! `error` tells why the rest of the file could not be read (e.g., a truncated gzip file),
+//! in which case the content up to there is indexed; it is omitted if there is no such error.
+//!
+//! `rescans` is the number of the times the agent has rescanned directories
+//! because some file system events may have been lost while inotify watches were restarted.
+//! `overflows` is the number of the times the periodic listings of directories have found
+//! creations or removals of files whose events had been lost (e.g., by overflows of the inotify
+//! queue, which are not notified otherwise). Such files are indexed or dropped then.
+//!
+//! A `subscribe` request is replied by `matched` (and `dropped`) responses
+//! until it is cancelled by an `unsubscribe` request, which is replied by an `unsubscribed` response.
+use serde_json;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use agent::{AgentStats, FileStatus, Match, SearchOptions, SearchResult, SubscriptionEvent};
+use Error;
+
+pub use self::client::Call;
+pub use self::server::{BoxEvents, BoxReply, RpcServer, Service, UnixRpcServer};
+
+mod client;
+mod lines;
+mod server;
+mod unix;
+
+/// The version of the protocol.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The default address of the RPC server of an agent.
+pub const DEFAULT_AGENT_ADDR: &str = "127.0.0.1:7878";
+
+/// The maximum number of bytes of the encoded matches in a `search` response.
+///
+/// This leaves enough room for the rest of a response in the limit of the length of a message.
+pub const MAX_SEARCH_RESPONSE_BYTES: usize = 8 * 1024 * 1024;
+
+/// A request message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+    pub version: u32,
+    pub id: u64,
+    #[serde(flatten)]
+    pub method: Method,
+}
+impl Request {
+    pub fn new(id: u64, method: Method) -> Self {
+        Request {
+            version: PROTOCOL_VERSION,
+            id,
+            method,
+        }
+    }
+}
+
+/// The method of a request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum Method {
+    Lookup(QueryParams),
+    Search(QueryParams),
+    Subscribe(QueryParams),
+    Unsubscribe { target: u64 },
+    Heartbeat(AgentInfo),
+    Agents,
+    Files,
+    Reindex { path: String },
+    Progress,
+}
+impl Method {
+    /// Returns `true` if this is an admin operation.
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            *self,
+            Method::Files | Method::Reindex { .. } | Method::Progress
+        )
+    }
+}
+
+/// The parameters of a query.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QueryParams {
+    pub query: String,
+    #[serde(default)]
+    pub ignore_case: bool,
+    #[serde(default)]
+    pub since: Option<u64>,
+    #[serde(default)]
+    pub until: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_results: Option<usize>,
+}
+impl QueryParams {
+    pub fn new(query: String, options: &SearchOptions) -> Self {
+        QueryParams {
+            query,
+            ignore_case: options.ignore_case,
+            since: options.since.map(to_unix_secs),
+            until: options.until.map(to_unix_secs),
+            max_results: options.max_results,
+        }
+    }
+    pub fn search_options(&self) -> SearchOptions {
+        SearchOptions {
+            ignore_case: self.ignore_case,
+            since: self.since.map(from_unix_secs),
+            until: self.until.map(from_unix_secs),
+            max_results: self.max_results,
+        }
+    }
+}
+
+/// A response message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response {
+    pub version: u32,
+    pub id: u64,
+    #[serde(flatten)]
+    pub body: ResponseBody,
+}
+impl Response {
+    pub fn new(id: u64, body: ResponseBody) -> Self {
+        Response {
+            version: PROTOCOL_VERSION,
+            id,
+            body,
+        }
+    }
+}
+
+/// The body of a response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseBody {
+    Lookup(LookupResponse),
+    Search(SearchResponse),
+    Matched(MatchEntry),
+    Dropped { count: u64 },
+    Unsubscribed,
+    Heartbeat {
+        ttl_secs: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        summary_version: Option<u64>,
+    },
+    Agents { agents: Vec<AgentStatus> },
+    Files { files: Vec<FileEntry> },
+    Reindexed { files: usize },
+    Progress(IndexProgress),
+    Error { message: String },
+}
+impl From<SubscriptionEvent> for ResponseBody {
+    fn from(f: SubscriptionEvent) -> Self {
+        match f {
+            SubscriptionEvent::Matched(m) => ResponseBody::Matched(MatchEntry::from(m)),
+            SubscriptionEvent::Dropped { count } => ResponseBody::Dropped { count },
+        }
+    }
+}
+
+/// The result of a `lookup` request.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LookupResponse {
+    pub paths: Vec<PathEntry>,
+    #[serde(default)]
+    pub partial: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failures: Vec<AgentFailure>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub skipped_agents: usize,
+}
+impl From<Vec<PathBuf>> for LookupResponse {
+    fn from(f: Vec<PathBuf>) -> Self {
+        LookupResponse {
+            paths: f.iter().map(|path| PathEntry::new(path)).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+/// The result of a `search` request.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchResponse {
+    pub matches: Vec<MatchEntry>,
+    pub scanned_files: usize,
+    pub total_files: usize,
+    pub full_scan: bool,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub failed_files: usize,
+    #[serde(default)]
+    pub truncated: bool,
+    #[serde(default)]
+    pub partial: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failures: Vec<AgentFailure>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub skipped_agents: usize,
+}
+impl SearchResponse {
+    /// Drops the matches beyond `MAX_SEARCH_RESPONSE_BYTES`, and marks the response as `truncated`
+    /// if there are any.
+    pub fn limit_size(&mut self) {
+        let mut size = 0;
+        let n = self.matches
+            .iter()
+            .take_while(|m| {
+                size += serde_json::to_vec(m).map_or(0, |m| m.len()) + 1;
+                size <= MAX_SEARCH_RESPONSE_BYTES
+            })
+            .count();
+        if n < self.matches.len() {
+            self.matches.truncate(n);
+            self.truncated = true;
+        }
+    }
+}
+impl From<SearchResult> for SearchResponse {
+    fn from(f: SearchResult) -> Self {
+        SearchResponse {
+            matches: f.matches.into_iter().map(MatchEntry::from).collect(),
+            scanned_files: f.scanned_files,
+            total_files: f.total_files,
+            full_scan: f.full_scan,
+            failed_files: f.failed_files,
+            partial: f.failed_files > 0,
+            ..Default::default()
+        }
+    }
+}
+
+/// A file.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct PathEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    pub path: String,
+}
+impl PathEntry {
+    fn new(path: &Path) -> Self {
+        PathEntry {
+            host: None,
+            path: path.to_string_lossy().into_owned(),
+        }
+    }
+}
+
+/// A matched line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    pub path: String,
+    pub line_number: u64,
+    pub offset: u64,
+    pub line: String,
+}
+impl From<Match> for MatchEntry {
+    fn from(f: Match) -> Self {
+        MatchEntry {
+            host: None,
+            path: f.path.to_string_lossy().into_owned(),
+            line_number: f.line_number,
+            offset: f.offset,
+            line: f.line,
+        }
+    }
+}
+
+/// An agent which failed to reply to a request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentFailure {
+    pub agent: String,
+    pub message: String,
+}
+
+/// The information of an agent reported by a heartbeat.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentInfo {
+    /// The address of the RPC server of the agent.
+    pub addr: SocketAddr,
+    pub roots: Vec<String>,
+    pub stats: IndexStats,
+    pub summary_version: u64,
+
+    /// The summary encoded by `agent::Summary::encode`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+}
+
+/// The statistics of the index of an agent.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IndexStats {
+    pub files: usize,
+    pub blocks: usize,
+    pub indexed_bytes: u64,
+    pub filter_bytes: u64,
+    #[serde(default)]
+    pub rescans: u64,
+    #[serde(default)]
+    pub overflows: u64,
+}
+impl From<AgentStats> for IndexStats {
+    fn from(f: AgentStats) -> Self {
+        IndexStats {
+            files: f.files,
+            blocks: f.blocks,
+            indexed_bytes: f.indexed_bytes,
+            filter_bytes: f.filter_bytes,
+            rescans: f.rescans,
+            overflows: f.overflows,
+        }
+    }
+}
+
+/// The status of an agent known by a coordinator.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentStatus {
+    pub addr: SocketAddr,
+    #[serde(default)]
+    pub roots: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<IndexStats>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_in_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary_version: Option<u64>,
+}
+
+/// The indexing status of a file of an agent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    pub indexed_bytes: u64,
+    pub blocks: usize,
+    #[serde(default)]
+    pub pending: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+impl From<FileStatus> for FileEntry {
+    fn from(f: FileStatus) -> Self {
+        FileEntry {
+            path: f.path.to_string_lossy().into_owned(),
+            size: f.size,
+            indexed_bytes: f.indexed_bytes,
+            blocks: f.blocks,
+            pending: f.is_pending,
+            error: f.error.as_ref().map(error_message),
+        }
+    }
+}
+
+/// The indexing progress of an agent.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IndexProgress {
+    pub files: usize,
+    pub pending_files: usize,
+    #[serde(default)]
+    pub failed_files: usize,
+    pub indexed_bytes: u64,
+    pub total_bytes: u64,
+    #[serde(default)]
+    pub rescans: u64,
+    #[serde(default)]
+    pub overflows: u64,
+}
+impl<'a> From<&'a [FileStatus]> for IndexProgress {
+    fn from(f: &'a [FileStatus]) -> Self {
+        let mut progress = IndexProgress {
+            files: f.len(),
+            ..Default::default()
+        };
+        for file in f {
+            if file.is_pending {
+                progress.pending_files += 1;
+            }
+            if file.error.is_some() {
+                progress.failed_files += 1;
+            }
+            progress.indexed_bytes += file.indexed_bytes;
+            progress.total_bytes += file.size.unwrap_or(file.indexed_bytes);
+        }
+        progress
+    }
+}
+
+/// Returns the description of `e` without its tracking history.
+pub fn error_message(e: &Error) -> String {
+    let mut s = e.to_string();
+    if let Some(i) = s.rfind("\nHISTORY:") {
+        s.truncate(i);
+    }
+    s
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+fn to_unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn from_unix_secs(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
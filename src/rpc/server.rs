@@ -0,0 +1,529 @@
+use fibers::net::futures::{Connected, TcpListenerBind};
+use fibers::net::streams::Incoming;
+use fibers::net::{TcpListener, TcpStream};
+use fibers::time::timer::{self, Timeout};
+use fibers::{BoxSpawn, Spawn};
+use futures::{self, Async, Future, Poll, Stream};
+use serde_json;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use trackable::error::ErrorKindExt;
+
+use super::lines::JsonLines;
+use super::unix::UnixListener;
+use super::{error_message, AgentInfo, FileEntry, IndexProgress, Method, QueryParams, Request,
+            Response, ResponseBody, SearchResponse, PROTOCOL_VERSION};
+use agent::AgentHandle;
+use query::Query;
+use {Error, ErrorKind, Result};
+
+/// A future which yields the response to a request.
+pub type BoxReply = Box<dyn Future<Item = ResponseBody, Error = Error> + Send>;
+
+/// A stream of the responses to a `subscribe` request.
+pub type BoxEvents = Box<dyn Stream<Item = ResponseBody, Error = Error> + Send>;
+
+/// A handler of RPC requests.
+pub trait Service: Clone + Send + 'static {
+    /// Handles a `lookup` request.
+    fn lookup(&self, params: QueryParams) -> BoxReply;
+
+    /// Handles a `search` request.
+    fn search(&self, params: QueryParams) -> BoxReply;
+
+    /// Handles a `subscribe` request.
+    fn subscribe(&self, params: QueryParams) -> Result<BoxEvents>;
+
+    /// Handles a `heartbeat` request.
+    fn heartbeat(&self, _info: AgentInfo) -> BoxReply {
+        let e = ErrorKind::InvalidInput.cause("Heartbeats are not supported by this server");
+        Box::new(futures::failed(track!(Error::from(e))))
+    }
+
+    /// Handles an `agents` request.
+    fn agents(&self) -> BoxReply {
+        let e = ErrorKind::InvalidInput.cause("Agent listing is not supported by this server");
+        Box::new(futures::failed(track!(Error::from(e))))
+    }
+
+    /// Handles a `files` request.
+    fn files(&self) -> BoxReply {
+        let e = ErrorKind::InvalidInput.cause("File listing is not supported by this server");
+        Box::new(futures::failed(track!(Error::from(e))))
+    }
+
+    /// Handles a `reindex` request.
+    fn reindex(&self, _path: String) -> BoxReply {
+        let e = ErrorKind::InvalidInput.cause("Reindexing is not supported by this server");
+        Box::new(futures::failed(track!(Error::from(e))))
+    }
+
+    /// Handles a `progress` request.
+    fn progress(&self) -> BoxReply {
+        let e = ErrorKind::InvalidInput.cause("Progress reporting is not supported by this server");
+        Box::new(futures::failed(track!(Error::from(e))))
+    }
+}
+impl Service for AgentHandle {
+    fn lookup(&self, params: QueryParams) -> BoxReply {
+        match parse_query(&params) {
+            Err(e) => Box::new(futures::failed(e)),
+            Ok(query) => {
+                let reply = AgentHandle::lookup(self, query, params.search_options());
+                Box::new(reply.map(|paths| ResponseBody::Lookup(paths.into())))
+            }
+        }
+    }
+    fn search(&self, params: QueryParams) -> BoxReply {
+        match parse_query(&params) {
+            Err(e) => Box::new(futures::failed(e)),
+            Ok(query) => {
+                let reply = AgentHandle::search(self, query, params.search_options());
+                Box::new(reply.map(|result| {
+                    let mut response = SearchResponse::from(result);
+                    response.limit_size();
+                    ResponseBody::Search(response)
+                }))
+            }
+        }
+    }
+    fn subscribe(&self, params: QueryParams) -> Result<BoxEvents> {
+        let query = track!(parse_query(&params))?;
+        let subscription = AgentHandle::subscribe(self, query, params.search_options());
+        Ok(Box::new(subscription.map(ResponseBody::from)))
+    }
+    fn files(&self) -> BoxReply {
+        let reply = AgentHandle::files(self);
+        Box::new(reply.map(|files| ResponseBody::Files {
+            files: files.into_iter().map(FileEntry::from).collect(),
+        }))
+    }
+    fn reindex(&self, path: String) -> BoxReply {
+        let reply = AgentHandle::reindex(self, path);
+        Box::new(reply.map(|files| ResponseBody::Reindexed { files }))
+    }
+    fn progress(&self) -> BoxReply {
+        let reply = AgentHandle::files(self).join(AgentHandle::stats(self));
+        Box::new(reply.map(|(files, stats)| {
+            let mut progress = IndexProgress::from(&files[..]);
+            progress.rescans = stats.rescans;
+            progress.overflows = stats.overflows;
+            ResponseBody::Progress(progress)
+        }))
+    }
+}
+
+/// The maximum number of bytes buffered for a client before it receives them.
+///
+/// While this is exceeded, subscriptions of the client are not polled.
+const MAX_PENDING_BYTES: usize = 1024 * 1024;
+
+/// The period of waiting before accepting connections again after a failure
+/// (e.g., too many open files).
+const ACCEPT_RETRY_INTERVAL_MS: u64 = 100;
+
+/// A future which serves the RPC requests to a service (e.g., `AgentHandle`).
+#[derive(Debug)]
+pub struct RpcServer<T> {
+    spawner: BoxSpawn,
+    service: T,
+    bind: Option<TcpListenerBind>,
+    incoming: Option<Incoming>,
+    retry: Option<Timeout>,
+}
+impl<T: Service> RpcServer<T> {
+    pub fn new<S>(spawner: S, bind_addr: SocketAddr, service: T) -> Self
+    where
+        S: Spawn + Send + 'static,
+    {
+        RpcServer {
+            spawner: spawner.boxed(),
+            service,
+            bind: Some(TcpListener::bind(bind_addr)),
+            incoming: None,
+            retry: None,
+        }
+    }
+}
+impl<T: Service> Future for RpcServer<T> {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::Ready(Some(listener)) = track!(self.bind.poll().map_err(Error::from))? {
+            self.bind = None;
+            self.incoming = Some(listener.incoming());
+        }
+        if let Async::NotReady = self.retry.poll().expect("Never fails") {
+            return Ok(Async::NotReady);
+        }
+        self.retry = None;
+        if let Some(ref mut incoming) = self.incoming {
+            loop {
+                match incoming.poll() {
+                    Ok(Async::Ready(Some((client, _addr)))) => {
+                        let future = Connection::connect(client, self.service.clone());
+                        self.spawner.spawn(future.then(|_result| Ok(())));
+                    }
+                    Ok(_) => break,
+                    Err(e) => {
+                        self.retry = Some(retry_accept(&Error::from(e)));
+                        return self.poll();
+                    }
+                }
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// A future which serves the RPC requests to a service over a Unix domain socket.
+///
+/// Unlike `RpcServer`, this also accepts admin operations (e.g., `reindex`).
+/// Access to the server is controlled by the permission bits of the socket file.
+#[derive(Debug)]
+pub struct UnixRpcServer<T> {
+    spawner: BoxSpawn,
+    service: T,
+    listener: UnixListener,
+    retry: Option<Timeout>,
+}
+impl<T: Service> UnixRpcServer<T> {
+    /// Makes a new `UnixRpcServer` which listens on the socket file at `path`.
+    ///
+    /// The permission bits of the socket file are set to `mode` (e.g., `0o600`).
+    pub fn new<S, P>(spawner: S, path: P, mode: u32, service: T) -> Result<Self>
+    where
+        S: Spawn + Send + 'static,
+        P: AsRef<Path>,
+    {
+        let listener = track!(UnixListener::bind(path, mode))?;
+        Ok(UnixRpcServer {
+            spawner: spawner.boxed(),
+            service,
+            listener,
+            retry: None,
+        })
+    }
+}
+impl<T: Service> Future for UnixRpcServer<T> {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::NotReady = self.retry.poll().expect("Never fails") {
+            return Ok(Async::NotReady);
+        }
+        self.retry = None;
+        loop {
+            match self.listener.poll() {
+                Ok(Async::Ready(Some(client))) => {
+                    let future = Connection::new(client, self.service.clone(), true);
+                    self.spawner.spawn(future.then(|_result| Ok(())));
+                }
+                Ok(_) => break,
+                Err(e) => {
+                    self.retry = Some(retry_accept(&e));
+                    return self.poll();
+                }
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// A future which serves the RPC requests from a client.
+struct Connection<S, T> {
+    lines: JsonLines<S>,
+    service: T,
+    calls: Vec<(u64, BoxReply)>,
+    subscriptions: Vec<(u64, BoxEvents)>,
+    is_admin: bool,
+    is_closed: bool,
+}
+impl<S: fmt::Debug, T: fmt::Debug> fmt::Debug for Connection<S, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The boxed futures and streams are not `Debug`, so only their request IDs are shown
+        let calls = self.calls.iter().map(|(id, _)| id).collect::<Vec<_>>();
+        let subscriptions = self.subscriptions.iter().map(|(id, _)| id).collect::<Vec<_>>();
+        f.debug_struct("Connection")
+            .field("lines", &self.lines)
+            .field("service", &self.service)
+            .field("calls", &calls)
+            .field("subscriptions", &subscriptions)
+            .field("is_admin", &self.is_admin)
+            .field("is_closed", &self.is_closed)
+            .finish()
+    }
+}
+impl<T: Service> Connection<TcpStream, T> {
+    fn connect(client: Connected, service: T) -> impl Future<Item = (), Error = Error> {
+        client
+            .map_err(Error::from)
+            .and_then(move |stream| Connection::new(stream, service, false))
+    }
+}
+impl<S: Read + Write, T: Service> Connection<S, T> {
+    fn new(stream: S, service: T, is_admin: bool) -> Self {
+        Connection {
+            lines: JsonLines::new(stream),
+            service,
+            calls: Vec::new(),
+            subscriptions: Vec::new(),
+            is_admin,
+            is_closed: false,
+        }
+    }
+    fn handle_request(&mut self, line: &[u8]) -> Result<()> {
+        let request: Request = match serde_json::from_slice(line) {
+            Err(e) => return track!(self.reply_error(0, &e.to_string())),
+            Ok(request) => request,
+        };
+        if request.version != PROTOCOL_VERSION {
+            let message = format!("unsupported protocol version: {}", request.version);
+            return track!(self.reply_error(request.id, &message));
+        }
+
+        if request.method.is_admin() && !self.is_admin {
+            let message = "admin operations are only accepted on the admin socket";
+            return track!(self.reply_error(request.id, message));
+        }
+
+        let id = request.id;
+        match request.method {
+            Method::Lookup(params) => {
+                let reply = self.service.lookup(params);
+                self.calls.push((id, reply));
+            }
+            Method::Search(params) => {
+                let reply = self.service.search(params);
+                self.calls.push((id, reply));
+            }
+            Method::Subscribe(params) => match self.service.subscribe(params) {
+                Err(e) => track!(self.reply_error(id, &error_message(&e)))?,
+                Ok(events) => self.subscriptions.push((id, events)),
+            },
+            Method::Heartbeat(info) => {
+                let reply = self.service.heartbeat(info);
+                self.calls.push((id, reply));
+            }
+            Method::Agents => {
+                let reply = self.service.agents();
+                self.calls.push((id, reply));
+            }
+            Method::Files => {
+                let reply = self.service.files();
+                self.calls.push((id, reply));
+            }
+            Method::Reindex { path } => {
+                let reply = self.service.reindex(path);
+                self.calls.push((id, reply));
+            }
+            Method::Progress => {
+                let reply = self.service.progress();
+                self.calls.push((id, reply));
+            }
+            Method::Unsubscribe { target } => {
+                self.subscriptions.retain(|&(i, _)| i != target);
+                track!(self.reply(id, ResponseBody::Unsubscribed))?;
+            }
+        }
+        Ok(())
+    }
+    fn reply(&mut self, id: u64, body: ResponseBody) -> Result<()> {
+        track!(self.lines.start_send(&Response::new(id, body)))
+    }
+    fn reply_error(&mut self, id: u64, message: &str) -> Result<()> {
+        let body = ResponseBody::Error {
+            message: message.to_owned(),
+        };
+        track!(self.reply(id, body))
+    }
+    fn poll_calls(&mut self) -> Result<()> {
+        let mut i = 0;
+        while i < self.calls.len() {
+            match self.calls[i].1.poll() {
+                Ok(Async::NotReady) => i += 1,
+                Ok(Async::Ready(body)) => {
+                    let (id, _) = self.calls.swap_remove(i);
+                    track!(self.reply(id, body))?;
+                }
+                Err(e) => {
+                    let (id, _) = self.calls.swap_remove(i);
+                    track!(self.reply_error(id, &error_message(&e)))?;
+                }
+            }
+        }
+        Ok(())
+    }
+    fn poll_subscriptions(&mut self) -> Result<()> {
+        let mut i = 0;
+        while i < self.subscriptions.len() && self.lines.pending_bytes() < MAX_PENDING_BYTES {
+            let id = self.subscriptions[i].0;
+            match self.subscriptions[i].1.poll() {
+                Ok(Async::NotReady) => i += 1,
+                Ok(Async::Ready(None)) => {
+                    let _ = self.subscriptions.swap_remove(i);
+                }
+                Ok(Async::Ready(Some(body))) => track!(self.reply(id, body))?,
+                Err(e) => {
+                    let _ = self.subscriptions.swap_remove(i);
+                    track!(self.reply_error(id, &error_message(&e)))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+impl<S: Read + Write, T: Service> Future for Connection<S, T> {
+    type Item = ();
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while !self.is_closed {
+            match track!(self.lines.poll_line())? {
+                Async::NotReady => break,
+                Async::Ready(None) => {
+                    // Nobody receives the events any longer
+                    self.is_closed = true;
+                    self.subscriptions.clear();
+                }
+                Async::Ready(Some(line)) => track!(self.handle_request(&line))?,
+            }
+        }
+        track!(self.poll_calls())?;
+        track!(self.poll_subscriptions())?;
+
+        let is_flushed = track!(self.lines.poll_flush())?.is_ready();
+        if self.is_closed && is_flushed && self.calls.is_empty() && self.subscriptions.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Reports a failure of accepting a connection, and returns the timer to wait for before retrying.
+///
+/// Such failures are often transient, so the server keeps serving the other connections.
+fn retry_accept(e: &Error) -> Timeout {
+    eprintln!("dg: failed to accept a connection: {}", error_message(e));
+    timer::timeout(Duration::from_millis(ACCEPT_RETRY_INTERVAL_MS))
+}
+
+fn parse_query(params: &QueryParams) -> Result<Query> {
+    track!(params.query.parse())
+}
+
+#[cfg(test)]
+mod tests {
+    use fibers::{Executor, InPlaceExecutor};
+    use std::net;
+
+    use super::*;
+    use rpc::{Call, MatchEntry};
+
+    /// A service which replies a match consisting of the query to every `search` request.
+    #[derive(Debug, Clone)]
+    struct EchoService;
+    impl Service for EchoService {
+        fn lookup(&self, _params: QueryParams) -> BoxReply {
+            Box::new(futures::finished(ResponseBody::Lookup(Default::default())))
+        }
+        fn search(&self, params: QueryParams) -> BoxReply {
+            let response = SearchResponse {
+                matches: vec![MatchEntry {
+                    host: None,
+                    path: "a.log".to_owned(),
+                    line_number: 1,
+                    offset: 0,
+                    line: params.query,
+                }],
+                ..Default::default()
+            };
+            Box::new(futures::finished(ResponseBody::Search(response)))
+        }
+        fn subscribe(&self, _params: QueryParams) -> Result<BoxEvents> {
+            track_panic!(ErrorKind::InvalidInput, "Not supported");
+        }
+        fn files(&self) -> BoxReply {
+            Box::new(futures::finished(ResponseBody::Files { files: Vec::new() }))
+        }
+    }
+
+    fn unused_addr() -> SocketAddr {
+        let listener = net::TcpListener::bind("127.0.0.1:0").expect("Never fails");
+        listener.local_addr().expect("Never fails")
+    }
+
+    fn call(request: Request) -> Response {
+        let mut executor = InPlaceExecutor::new().expect("Never fails");
+        let addr = unused_addr();
+        let server = RpcServer::new(executor.handle(), addr, EchoService);
+        executor.spawn(server.map_err(|e| panic!("{}", e)));
+        let monitor = executor.spawn_monitor(Call::new(addr, request));
+        let result = executor.run_fiber(monitor).expect("Never fails");
+        result.expect("Never fails")
+    }
+
+    fn search_params(query: &str) -> QueryParams {
+        QueryParams {
+            query: query.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn error_of(response: Response) -> String {
+        match response.body {
+            ResponseBody::Error { message } => message,
+            body => panic!("Unexpected response: {:?}", body),
+        }
+    }
+
+    #[test]
+    fn search_round_trip() {
+        let response = call(Request::new(7, Method::Search(search_params("foo"))));
+        assert_eq!(response.version, PROTOCOL_VERSION);
+        assert_eq!(response.id, 7);
+        match response.body {
+            ResponseBody::Search(result) => {
+                assert_eq!(result.matches.len(), 1);
+                assert_eq!(result.matches[0].path, "a.log");
+                assert_eq!(result.matches[0].line, "foo");
+            }
+            body => panic!("Unexpected response: {:?}", body),
+        }
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let request = Request {
+            version: PROTOCOL_VERSION + 1,
+            ..Request::new(3, Method::Search(search_params("foo")))
+        };
+        let response = call(request);
+        assert_eq!(response.id, 3);
+        assert_eq!(
+            error_of(response),
+            format!("unsupported protocol version: {}", PROTOCOL_VERSION + 1)
+        );
+    }
+
+    #[test]
+    fn admin_methods_are_rejected_over_tcp() {
+        for method in [
+            Method::Files,
+            Method::Reindex {
+                path: "/".to_owned(),
+            },
+            Method::Progress,
+        ] {
+            let response = call(Request::new(5, method));
+            assert_eq!(response.id, 5);
+            assert_eq!(
+                error_of(response),
+                "admin operations are only accepted on the admin socket"
+            );
+        }
+    }
+}
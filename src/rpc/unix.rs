@@ -0,0 +1,211 @@
+use fibers::fiber::{self, Context};
+use fibers::io::poll::{EventedHandle, Interest, Register};
+use fibers::sync::oneshot::{Monitor, MonitorError};
+use futures::{Async, Future, Poll, Stream};
+use mio_uds;
+use std::fs::{self, DirBuilder};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+
+use {Error, ErrorKind, Result};
+
+/// A listener of a Unix domain socket which is polled by fibers.
+///
+/// The socket file is removed when this is dropped.
+#[derive(Debug)]
+pub struct UnixListener {
+    path: PathBuf,
+    listener: Option<mio_uds::UnixListener>,
+    register: Option<Register<mio_uds::UnixListener>>,
+    handle: Option<Arc<EventedHandle<mio_uds::UnixListener>>>,
+    monitor: Option<Monitor<(), io::Error>>,
+}
+impl UnixListener {
+    /// Makes a socket file at `path` whose permission bits are `mode`, and listens on it.
+    ///
+    /// A stale socket file left by a dead process is replaced,
+    /// but a socket accepting connections or a file of any other type is not.
+    pub fn bind<P: AsRef<Path>>(path: P, mode: u32) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Ok(metadata) = fs::symlink_metadata(&path) {
+            track_assert!(
+                metadata.file_type().is_socket(),
+                ErrorKind::InvalidInput,
+                "not a socket: {:?}",
+                path
+            );
+            track_assert!(
+                net::UnixStream::connect(&path).is_err(),
+                ErrorKind::InvalidInput,
+                "already in use: {:?}",
+                path
+            );
+            track!(fs::remove_file(&path).map_err(Error::from))?;
+        }
+
+        // The socket is made in a private directory and moved to `path` after the permission bits
+        // are set, so that nobody can connect to it in the meantime
+        let mut private_dir = path.clone().into_os_string();
+        private_dir.push(format!(".{}.tmp", process::id()));
+        let private_dir = PathBuf::from(private_dir);
+        track!(DirBuilder::new()
+            .mode(0o700)
+            .create(&private_dir)
+            .map_err(Error::from))?;
+        let result = bind_privately(&private_dir, &path, mode);
+        let _ = fs::remove_dir_all(&private_dir);
+        let listener = track!(result)?;
+        Ok(UnixListener {
+            path,
+            listener: Some(listener),
+            register: None,
+            handle: None,
+            monitor: None,
+        })
+    }
+
+    fn poll_handle(&mut self) -> Poll<Arc<EventedHandle<mio_uds::UnixListener>>, Error> {
+        if let Some(listener) = self.listener.take() {
+            let register = |mut c: Context| c.poller().register(listener);
+            let register = track_assert_some!(
+                fiber::with_current_context(register),
+                ErrorKind::Other,
+                "Not in a fiber"
+            );
+            self.register = Some(register);
+        }
+        if let Some(mut register) = self.register.take() {
+            let result = register.poll().map_err(io::Error::other);
+            match track!(result.map_err(Error::from))? {
+                Async::NotReady => {
+                    self.register = Some(register);
+                    return Ok(Async::NotReady);
+                }
+                Async::Ready(handle) => self.handle = Some(handle),
+            }
+        }
+        let handle = self.handle.clone().expect("Never fails");
+        Ok(Async::Ready(handle))
+    }
+}
+impl Stream for UnixListener {
+    type Item = UnixStream;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let handle = match track!(self.poll_handle())? {
+            Async::NotReady => return Ok(Async::NotReady),
+            Async::Ready(handle) => handle,
+        };
+        loop {
+            if let Some(mut monitor) = self.monitor.take() {
+                let result = monitor.poll().map_err(into_io_error);
+                if let Async::NotReady = track!(result.map_err(Error::from))? {
+                    self.monitor = Some(monitor);
+                    return Ok(Async::NotReady);
+                }
+            }
+            match handle.inner().accept() {
+                Err(e) => return Err(track!(Error::from(e))),
+                Ok(None) => self.monitor = Some(handle.monitor(Interest::Read)),
+                Ok(Some((stream, _addr))) => {
+                    let register = |mut c: Context| c.poller().register(stream);
+                    let register = track_assert_some!(
+                        fiber::with_current_context(register),
+                        ErrorKind::Other,
+                        "Not in a fiber"
+                    );
+                    return Ok(Async::Ready(Some(UnixStream::new(register))));
+                }
+            }
+        }
+    }
+}
+impl Drop for UnixListener {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn bind_privately(private_dir: &Path, path: &Path, mode: u32) -> Result<mio_uds::UnixListener> {
+    let temp_path = private_dir.join("socket");
+    let listener = track!(mio_uds::UnixListener::bind(&temp_path).map_err(Error::from))?;
+    let permissions = fs::Permissions::from_mode(mode);
+    track!(fs::set_permissions(&temp_path, permissions).map_err(Error::from))?;
+    track!(fs::rename(&temp_path, path).map_err(Error::from))?;
+    Ok(listener)
+}
+
+/// A stream of a Unix domain socket which is polled by fibers.
+#[derive(Debug)]
+pub struct UnixStream {
+    register: Option<Register<mio_uds::UnixStream>>,
+    handle: Option<Arc<EventedHandle<mio_uds::UnixStream>>>,
+    read_monitor: Option<Monitor<(), io::Error>>,
+    write_monitor: Option<Monitor<(), io::Error>>,
+}
+impl UnixStream {
+    fn new(register: Register<mio_uds::UnixStream>) -> Self {
+        UnixStream {
+            register: Some(register),
+            handle: None,
+            read_monitor: None,
+            write_monitor: None,
+        }
+    }
+
+    fn operate<F, T>(&mut self, interest: Interest, mut f: F) -> io::Result<T>
+    where
+        F: FnMut(&mut mio_uds::UnixStream) -> io::Result<T>,
+    {
+        if let Some(mut register) = self.register.take() {
+            match register.poll().map_err(io::Error::other)? {
+                Async::NotReady => {
+                    self.register = Some(register);
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                Async::Ready(handle) => self.handle = Some(handle),
+            }
+        }
+        let handle = self.handle.as_ref().expect("Never fails");
+        let monitor = if interest == Interest::Read {
+            &mut self.read_monitor
+        } else {
+            &mut self.write_monitor
+        };
+        loop {
+            if let Some(mut m) = monitor.take() {
+                if let Async::NotReady = m.poll().map_err(into_io_error)? {
+                    *monitor = Some(m);
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+            }
+            match f(&mut handle.inner()) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    *monitor = Some(handle.monitor(interest));
+                }
+                result => return result,
+            }
+        }
+    }
+}
+impl Read for UnixStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.operate(Interest::Read, |inner| inner.read(buf))
+    }
+}
+impl Write for UnixStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.operate(Interest::Write, |inner| inner.write(buf))
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.operate(Interest::Write, |inner| inner.flush())
+    }
+}
+
+fn into_io_error(e: MonitorError<io::Error>) -> io::Error {
+    e.unwrap_or_else(|| io::Error::other("Monitor channel disconnected"))
+}
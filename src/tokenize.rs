@@ -33,7 +33,7 @@ impl<'a> Iterator for WordTokenizer<'a> {
         let end = self.bytes
             .iter()
             .skip(start + 1)
-            .position(|&b| !(b as char).is_alphanumeric() && b < 0x80)
+            .position(|&b| is_delimiter(b))
             .map(|p| p + start + 1)
             .unwrap_or(self.bytes.len());
         self.position = end;
@@ -44,3 +44,16 @@ impl<'a> Iterator for WordTokenizer<'a> {
         ))
     }
 }
+
+/// Returns `true` if the given byte never appears in words yielded by `WordTokenizer`.
+pub fn is_delimiter(b: u8) -> bool {
+    !(b as char).is_alphanumeric() && b < 0x80
+}
+
+/// Converts `s` to lowercase character by character.
+///
+/// Unlike `str::to_lowercase`, the conversion does not depend on the context of each character,
+/// so the result for a string equals the concatenation of the results for its substrings.
+pub fn fold_case(s: &str) -> String {
+    s.chars().flat_map(char::to_lowercase).collect()
+}
@@ -1,27 +1,48 @@
+use fibers::time::timer::{self, Timeout};
 use fibers_inotify::{EventMask, InotifyEvent, InotifyService, WatchMask, Watcher, WatcherEvent};
 use fibers_tasque::{AsyncCall, DefaultIoTaskQueue, TaskQueueExt};
 use futures::future::Fuse;
 use futures::{Async, Future, Poll, Stream};
 use std;
 use std::fs::DirEntry;
+use std::mem;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use {Error, ErrorKind, Result};
+use {Error, Result};
 
 #[derive(Debug)]
 pub struct DirectoryWatcher {
     path: PathBuf,
-    watcher: Watcher,
+    watcher: Option<Watcher>,
     list_dir: Option<ListDirectory>,
+
+    // `true` if this watches the target of a symbolic link (see `DirectoryWatcher::link`)
+    is_link: bool,
+
+    // `true` if the kernel has removed the inotify watch
+    is_watch_removed: bool,
+
+    // The entries listed so far by the rescan after some events have been lost
+    rescanned: Option<Vec<PathBuf>>,
+
+    // The interval of listing the entries to find the changes whose events have been lost,
+    // the timer of the next listing, and the entries listed so far by the current one
+    reconciliation_interval: Option<Duration>,
+    reconciliation_timeout: Option<Timeout>,
+    reconciled: Option<Vec<ListedEntry>>,
 }
 impl DirectoryWatcher {
-    pub fn new<P: AsRef<Path>>(inotify: &InotifyService, path: P) -> Result<Self> {
-        track_assert!(
-            path.as_ref().is_dir(),
-            ErrorKind::InvalidInput,
-            "not a directory: {:?}",
-            path.as_ref()
-        );
+    /// Makes a `DirectoryWatcher` which watches the directory at `path`.
+    ///
+    /// The entries are listed every `reconciliation_interval` (see `DirectoryEvent::Listed`),
+    /// because the events lost by overflows of the inotify queue are not notified otherwise.
+    /// The caller ensures that `path` is a directory.
+    pub fn new<P: AsRef<Path>>(
+        inotify: &InotifyService,
+        path: P,
+        reconciliation_interval: Duration,
+    ) -> Self {
         let mask = WatchMask::CREATE
             | WatchMask::DELETE
             | WatchMask::DELETE_SELF
@@ -30,33 +51,132 @@ impl DirectoryWatcher {
             | WatchMask::MOVE_SELF
             | WatchMask::EXCL_UNLINK;
         let watcher = inotify.handle().watch(&path, mask);
-        Ok(DirectoryWatcher {
+        DirectoryWatcher {
             path: path.as_ref().to_path_buf(),
-            watcher,
+            watcher: Some(watcher),
+            list_dir: None,
+            is_link: false,
+            is_watch_removed: false,
+            rescanned: None,
+            reconciliation_interval: Some(reconciliation_interval),
+            reconciliation_timeout: None,
+            reconciled: None,
+        }
+    }
+
+    /// Makes a `DirectoryWatcher` which watches the target of the symbolic link to a file.
+    ///
+    /// Any change of the target (including its removal) is notified as an `Updated` event
+    /// of `link`, and no entries are listed.
+    /// The caller ensures that `link` is a link to a file.
+    pub fn link<P: AsRef<Path>>(inotify: &InotifyService, link: P) -> Self {
+        let mask = WatchMask::MODIFY
+            | WatchMask::ATTRIB
+            | WatchMask::DELETE_SELF
+            | WatchMask::MOVE_SELF;
+        let watcher = inotify.handle().watch(&link, mask);
+        DirectoryWatcher {
+            path: link.as_ref().to_path_buf(),
+            watcher: Some(watcher),
             list_dir: None,
-        })
+            is_link: true,
+            is_watch_removed: false,
+            rescanned: None,
+            reconciliation_interval: None,
+            reconciliation_timeout: None,
+            reconciled: None,
+        }
+    }
+
+    /// Makes a `DirectoryWatcher` which only lists the entries of the directory once.
+    ///
+    /// No inotify watch is installed, and the stream terminates after the listing.
+    pub fn list<P: AsRef<Path>>(path: P) -> Self {
+        DirectoryWatcher {
+            path: path.as_ref().to_path_buf(),
+            watcher: None,
+            list_dir: Some(ListDirectory::new(path.as_ref().to_path_buf())),
+            is_link: false,
+            is_watch_removed: false,
+            rescanned: None,
+            reconciliation_interval: None,
+            reconciliation_timeout: None,
+            reconciled: None,
+        }
     }
     fn poll_directory_listing(&mut self) -> Result<Option<DirectoryEvent>> {
-        if let Some(mut list_dir) = self.list_dir.take() {
+        while let Some(mut list_dir) = self.list_dir.take() {
             match track!(list_dir.poll())? {
                 Async::NotReady => {
                     self.list_dir = Some(list_dir);
-                    Ok(None)
+                    return Ok(None);
+                }
+                Async::Ready(None) => {
+                    self.start_reconciliation_timer();
+                    if let Some(entries) = self.reconciled.take() {
+                        let path = self.path.clone();
+                        return Ok(Some(DirectoryEvent::Listed { path, entries }));
+                    }
+                    let event = self.rescanned.take().map(|entries| DirectoryEvent::Rescanned {
+                        path: self.path.clone(),
+                        entries,
+                    });
+                    return Ok(event);
                 }
-                Async::Ready(None) => Ok(None),
                 Async::Ready(Some(entry)) => {
                     self.list_dir = Some(list_dir);
                     let path = entry.path();
-                    let is_dir = entry.file_type().ok().map_or(false, |ft| ft.is_dir());
-                    Ok(Some(DirectoryEvent::Updated { path, is_dir }))
+                    let file_type = entry.file_type().ok();
+                    let is_dir = file_type.is_some_and(|ft| ft.is_dir());
+                    if let Some(ref mut entries) = self.reconciled {
+                        let is_link = file_type.is_some_and(|ft| ft.is_symlink());
+                        entries.push(ListedEntry {
+                            path,
+                            is_dir,
+                            is_link,
+                        });
+                        continue;
+                    }
+                    if let Some(ref mut entries) = self.rescanned {
+                        entries.push(path.clone());
+                    }
+                    return Ok(Some(DirectoryEvent::Updated { path, is_dir }));
                 }
             }
-        } else {
-            Ok(None)
+        }
+        Ok(None)
+    }
+    fn start_reconciliation_timer(&mut self) {
+        if let Some(interval) = self.reconciliation_interval {
+            self.reconciliation_timeout = Some(timer::timeout(interval));
         }
     }
+
+    /// Starts listing the entries if the time of the next reconciliation has come.
+    ///
+    /// Returns `true` if the listing has been started.
+    fn poll_reconciliation_timer(&mut self) -> Result<bool> {
+        if let Some(mut timeout) = self.reconciliation_timeout.take() {
+            if let Async::NotReady = track!(timeout.poll().map_err(Error::from))? {
+                self.reconciliation_timeout = Some(timeout);
+            } else if self.list_dir.is_some() {
+                // Being listed by a rescan
+                self.start_reconciliation_timer();
+            } else {
+                self.list_dir = Some(ListDirectory::new(self.path.clone()));
+                self.reconciled = Some(Vec::new());
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
     fn poll_watcher(&mut self) -> Result<Action> {
-        match track!(self.watcher.poll())? {
+        let watcher = match self.watcher {
+            None if self.list_dir.is_some() => return Ok(Action::Wait),
+            None => return Ok(Action::Terminate),
+            Some(ref mut watcher) => watcher,
+        };
+        match track!(watcher.poll())? {
             Async::NotReady => Ok(Action::Wait),
             Async::Ready(None) => Ok(Action::Terminate),
             Async::Ready(Some(watcher_event)) => {
@@ -65,21 +185,63 @@ impl DirectoryWatcher {
                         self.handle_inotify_event(inotify_event)
                     }
                     WatcherEvent::StartWatching => {
+                        if !self.is_link {
+                            self.list_dir = Some(ListDirectory::new(self.path.clone()));
+                        }
+                        Action::Continue
+                    }
+                    WatcherEvent::RestartWatching if self.is_link => {
+                        // A change of the target may have been missed
+                        let path = self.path.clone();
+                        Action::Notify(DirectoryEvent::Updated { path, is_dir: false })
+                    }
+                    WatcherEvent::RestartWatching => {
+                        // The watch has been moved to another inotify instance
+                        // (by a conflict of inodes), and some events may have been lost then,
+                        // so the entries are listed again
                         self.list_dir = Some(ListDirectory::new(self.path.clone()));
+                        self.rescanned = Some(Vec::new());
                         Action::Continue
                     }
-                    WatcherEvent::RestartWatching => Action::Terminate,
                 };
                 Ok(action)
             }
         }
     }
     fn handle_inotify_event(&mut self, mut event: InotifyEvent) -> Action {
+        if event
+            .mask
+            .intersects(EventMask::DELETE_SELF | EventMask::IGNORED)
+        {
+            self.is_watch_removed = true;
+        }
+        if self.is_link {
+            return if event.mask.intersects(EventMask::IGNORED) {
+                Action::Terminate
+            } else {
+                let path = self.path.clone();
+                Action::Notify(DirectoryEvent::Updated { path, is_dir: false })
+            };
+        }
+
         if event
             .mask
             .intersects(EventMask::DELETE_SELF | EventMask::MOVE_SELF | EventMask::IGNORED)
         {
             Action::Terminate
+        } else if event
+            .mask
+            .intersects(EventMask::MOVED_FROM | EventMask::MOVED_TO)
+            && !event.mask.intersects(EventMask::ISDIR)
+        {
+            let name = event.name.take().expect("Never fails");
+            let path = self.path.join(name);
+            let cookie = event.cookie;
+            if event.mask.intersects(EventMask::MOVED_FROM) {
+                Action::Notify(DirectoryEvent::MovedFrom { path, cookie })
+            } else {
+                Action::Notify(DirectoryEvent::MovedTo { path, cookie })
+            }
         } else if event
             .mask
             .intersects(EventMask::CREATE | EventMask::MODIFY | EventMask::MOVED_TO)
@@ -101,6 +263,16 @@ impl DirectoryWatcher {
         }
     }
 }
+impl Drop for DirectoryWatcher {
+    fn drop(&mut self) {
+        if self.is_watch_removed {
+            // `InotifyService` fails if it is asked to remove the watch removed by the kernel
+            if let Some(watcher) = self.watcher.take() {
+                mem::forget(watcher);
+            }
+        }
+    }
+}
 impl Stream for DirectoryWatcher {
     type Item = DirectoryEvent;
     type Error = Error;
@@ -109,6 +281,9 @@ impl Stream for DirectoryWatcher {
             if let Some(event) = track!(self.poll_directory_listing())? {
                 return Ok(Async::Ready(Some(event)));
             }
+            if track!(self.poll_reconciliation_timer())? {
+                continue;
+            }
             match track!(self.poll_watcher())? {
                 Action::Continue => {}
                 Action::Wait => return Ok(Async::NotReady),
@@ -123,6 +298,45 @@ impl Stream for DirectoryWatcher {
 pub enum DirectoryEvent {
     Updated { path: PathBuf, is_dir: bool },
     Removed { path: PathBuf, is_dir: bool },
+
+    /// A file was moved from `path` (the first half of a rename).
+    ///
+    /// The halves of a rename have the same `cookie`.
+    /// Moves of directories are notified as `Removed` and `Updated` instead.
+    MovedFrom { path: PathBuf, cookie: u32 },
+
+    /// A file was moved to `path` (the second half of a rename).
+    MovedTo { path: PathBuf, cookie: u32 },
+
+    /// The directory at `path` has been listed again because some events may have been lost
+    /// while `fibers_inotify` was restarting the watch (`WatcherEvent::RestartWatching`).
+    ///
+    /// Overflows of the inotify queue are not notified by `fibers_inotify`,
+    /// so they do not cause rescans (see `Listed` instead).
+    ///
+    /// `entries` are the paths found by the listing, each of which has been notified
+    /// as `Updated` before this.
+    Rescanned { path: PathBuf, entries: Vec<PathBuf> },
+
+    /// The directory at `path` has been listed periodically, so that the changes
+    /// whose events have been lost (e.g., by overflows of the inotify queue) are found
+    /// by comparing `entries` with the watched ones.
+    ///
+    /// Unlike `Rescanned`, the entries are not notified as `Updated`.
+    Listed {
+        path: PathBuf,
+        entries: Vec<ListedEntry>,
+    },
+}
+
+/// An entry of a directory found by a periodic listing (see `DirectoryEvent::Listed`).
+#[derive(Debug)]
+pub struct ListedEntry {
+    pub path: PathBuf,
+    pub is_dir: bool,
+
+    /// `true` if the entry is a symbolic link (`is_dir` is `false` then).
+    pub is_link: bool,
 }
 
 #[derive(Debug)]
@@ -137,6 +351,7 @@ enum Action {
 struct ListDirectory {
     future: Fuse<AsyncCall<Result<Vec<DirEntry>>>>,
     entries: Vec<DirEntry>,
+    is_listed: bool,
 }
 impl ListDirectory {
     fn new(dir: PathBuf) -> Self {
@@ -151,6 +366,7 @@ impl ListDirectory {
         ListDirectory {
             future: future.fuse(),
             entries: Vec::new(),
+            is_listed: false,
         }
     }
 }
@@ -160,9 +376,12 @@ impl Stream for ListDirectory {
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         if let Async::Ready(entries) = track!(self.future.poll().map_err(Error::from))? {
             self.entries = track!(entries)?;
+            self.is_listed = true;
         }
         if let Some(entry) = self.entries.pop() {
             Ok(Async::Ready(Some(entry)))
+        } else if self.is_listed {
+            Ok(Async::Ready(None))
         } else {
             Ok(Async::NotReady)
         }
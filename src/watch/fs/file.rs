@@ -5,34 +5,84 @@ use futures::future::Fuse;
 use futures::{Async, Future, Poll, Stream};
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
+use watch::fs::{FileType, GzipFileWatcher, TarGzipFileWatcher};
 use {Error, Result};
 
 const MIN_READ_CONTENT_INTERVAL: u64 = 60;
 const READ_BUFFER_SIZE: usize = 1024 * 1024;
 
+/// A notification to a `FileWatcher`.
 #[derive(Debug)]
-pub struct FileUpdated;
+pub enum FileUpdated {
+    /// The file has been modified.
+    Modified,
+
+    /// The file has been renamed to the given path.
+    Renamed(PathBuf),
+}
 
 #[derive(Debug)]
 pub enum FileWatcher {
     Plain(PlainFileWatcher),
-    Gzip,
-    TarGzip,
+    Gzip(GzipFileWatcher),
+    TarGzip(TarGzipFileWatcher),
+    Binary(BinaryFileWatcher),
 }
 impl FileWatcher {
-    pub fn new<P: AsRef<Path>>(path: P, event_rx: mpsc::Receiver<FileUpdated>) -> Self {
-        // TODO: guess file type
-        // TODO: return Vec or Stream
-        FileWatcher::Plain(PlainFileWatcher::new(path, event_rx))
+    /// Makes a `FileWatcher` suited to `file_type` (see `FileType::detect`).
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        file_type: FileType,
+        event_rx: mpsc::Receiver<FileUpdated>,
+    ) -> Self {
+        match file_type {
+            FileType::Text => FileWatcher::Plain(PlainFileWatcher::new(path, event_rx)),
+            FileType::Gzip => FileWatcher::Gzip(GzipFileWatcher::new(path, event_rx)),
+            FileType::TarGzip => FileWatcher::TarGzip(TarGzipFileWatcher::new(path, event_rx)),
+            file_type => FileWatcher::Binary(BinaryFileWatcher::new(path, file_type, event_rx)),
+        }
+    }
+
+    /// Makes a `FileWatcher` which reads the file to the end only once.
+    pub fn oneshot<P: AsRef<Path>>(path: P, file_type: FileType) -> Self {
+        match file_type {
+            FileType::Text => FileWatcher::Plain(PlainFileWatcher::oneshot(path)),
+            FileType::Gzip => FileWatcher::Gzip(GzipFileWatcher::oneshot(path)),
+            FileType::TarGzip => FileWatcher::TarGzip(TarGzipFileWatcher::oneshot(path)),
+            file_type => FileWatcher::Binary(BinaryFileWatcher::oneshot(path, file_type)),
+        }
     }
     pub fn path(&self) -> &Path {
         match *self {
             FileWatcher::Plain(ref w) => w.path(),
-            FileWatcher::Gzip => unimplemented!(),
-            FileWatcher::TarGzip => unimplemented!(),
+            FileWatcher::Gzip(ref w) => w.path(),
+            FileWatcher::TarGzip(ref w) => w.path(),
+            FileWatcher::Binary(ref w) => w.path(),
+        }
+    }
+
+    /// Returns the type of the file given when this watcher was made.
+    pub fn file_type(&self) -> FileType {
+        match *self {
+            FileWatcher::Plain(_) => FileType::Text,
+            FileWatcher::Gzip(_) => FileType::Gzip,
+            FileWatcher::TarGzip(_) => FileType::TarGzip,
+            FileWatcher::Binary(ref w) => w.file_type(),
+        }
+    }
+
+    /// Returns `true` if the file is an archive.
+    ///
+    /// The contents yielded from an archive belong to its members rather than the archive itself
+    /// (see `FileContent::path`).
+    pub fn is_archive(&self) -> bool {
+        match *self {
+            FileWatcher::TarGzip(_) => true,
+            FileWatcher::Plain(_) | FileWatcher::Gzip(_) | FileWatcher::Binary(_) => false,
         }
     }
 }
@@ -42,35 +92,145 @@ impl Stream for FileWatcher {
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         match *self {
             FileWatcher::Plain(ref mut w) => track!(w.poll()),
-            FileWatcher::Gzip => unimplemented!(),
-            FileWatcher::TarGzip => unimplemented!(),
+            FileWatcher::Gzip(ref mut w) => track!(w.poll()),
+            FileWatcher::TarGzip(ref mut w) => track!(w.poll()),
+            FileWatcher::Binary(ref mut w) => track!(w.poll()),
         }
     }
 }
 
 #[derive(Debug)]
 pub struct FileContent {
+    /// The path of the file containing the data.
+    ///
+    /// This differs from the path of the watcher if the watcher is reading an archive,
+    /// and is a virtual path made by `tar_gzip::member_path` then.
+    pub path: PathBuf,
+
     pub offset: u64,
     pub data: Vec<u8>,
+
+    /// `true` if the data reaches the current end of the file.
+    ///
+    /// Plain file watchers yield the first end of a file even if no data is read then.
     pub eof: bool,
+
+    /// If `true`, the file has been truncated or replaced by another file,
+    /// and the contents yielded before this must be discarded.
+    ///
+    /// The offset of the content is always `0` then.
+    pub reset: bool,
+
+    /// The reason why the rest of the file can not be read (e.g., a truncated gzip stream).
+    ///
+    /// The contents read up to there are valid.
+    /// Compressed files are read again when they are modified (e.g., while being written),
+    /// and the next content without an error tells that the broken part has become readable.
+    pub error: Option<Error>,
 }
 
+/// A watcher of a binary file, whose contents are never read.
+///
+/// This only keeps track of the file until it is removed,
+/// so that the file is not examined again on every update.
+#[derive(Debug)]
+pub struct BinaryFileWatcher {
+    path: PathBuf,
+    file_type: FileType,
+
+    // `None` means the one-shot mode
+    event_rx: Option<mpsc::Receiver<FileUpdated>>,
+}
+impl BinaryFileWatcher {
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        file_type: FileType,
+        event_rx: mpsc::Receiver<FileUpdated>,
+    ) -> Self {
+        BinaryFileWatcher {
+            path: path.as_ref().to_path_buf(),
+            file_type,
+            event_rx: Some(event_rx),
+        }
+    }
+
+    /// Makes a `BinaryFileWatcher` which terminates immediately.
+    pub fn oneshot<P: AsRef<Path>>(path: P, file_type: FileType) -> Self {
+        BinaryFileWatcher {
+            path: path.as_ref().to_path_buf(),
+            file_type,
+            event_rx: None,
+        }
+    }
+    pub fn path(&self) -> &Path {
+        self.path.as_ref()
+    }
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+}
+impl Stream for BinaryFileWatcher {
+    type Item = FileContent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(ref mut event_rx) = self.event_rx {
+            while let Async::Ready(event) = event_rx.poll().expect("Never fails") {
+                match event {
+                    None => return Ok(Async::Ready(None)),
+                    Some(FileUpdated::Modified) => {}
+                    Some(FileUpdated::Renamed(path)) => self.path = path,
+                }
+            }
+            Ok(Async::NotReady)
+        } else {
+            Ok(Async::Ready(None))
+        }
+    }
+}
+
+/// A watcher of a plain file.
+///
+/// Appended contents are yielded as they are written.
+/// If the file is truncated (e.g., by `copytruncate` of logrotate) or replaced by another file,
+/// it is read again from the beginning (see `FileContent::reset`).
+/// Truncations followed by writes beyond the last position are detected too,
+/// unless the byte just before the position happens to be unchanged.
 #[derive(Debug)]
 pub struct PlainFileWatcher {
     path: PathBuf,
-    event_rx: mpsc::Receiver<FileUpdated>,
+
+    // `None` means the one-shot mode
+    event_rx: Option<mpsc::Receiver<FileUpdated>>,
+
     current_position: u64,
+    mark: Option<ReadMark>,
     read_file_content: Option<ReadFileContent>,
     is_updated: bool,
+
+    // `true` if the end of the file has been reached at least once
+    is_eof_reached: bool,
 }
 impl PlainFileWatcher {
     pub fn new<P: AsRef<Path>>(path: P, event_rx: mpsc::Receiver<FileUpdated>) -> Self {
+        Self::with_event_rx(path, Some(event_rx))
+    }
+
+    /// Makes a `PlainFileWatcher` which terminates after reading the file to the end.
+    pub fn oneshot<P: AsRef<Path>>(path: P) -> Self {
+        Self::with_event_rx(path, None)
+    }
+    fn with_event_rx<P: AsRef<Path>>(
+        path: P,
+        event_rx: Option<mpsc::Receiver<FileUpdated>>,
+    ) -> Self {
         let mut this = PlainFileWatcher {
             path: path.as_ref().to_path_buf(),
             event_rx,
             current_position: 0,
+            mark: None,
             read_file_content: None,
             is_updated: false,
+            is_eof_reached: false,
         };
         this.start_read_file_content(Duration::from_secs(0));
         this
@@ -83,6 +243,7 @@ impl PlainFileWatcher {
         self.read_file_content = Some(ReadFileContent::new(
             self.path.clone(),
             self.current_position,
+            self.mark,
             wait,
         ));
     }
@@ -91,75 +252,235 @@ impl Stream for PlainFileWatcher {
     type Item = FileContent;
     type Error = Error;
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        match self.event_rx.poll().expect("Never fails") {
-            Async::NotReady => {}
-            Async::Ready(None) => return Ok(Async::Ready(None)),
-            Async::Ready(Some(FileUpdated)) => self.is_updated = true,
+        let mut is_renamed = false;
+        if let Some(ref mut event_rx) = self.event_rx {
+            while let Async::Ready(event) = event_rx.poll().expect("Never fails") {
+                match event {
+                    None => return Ok(Async::Ready(None)),
+                    Some(FileUpdated::Modified) => self.is_updated = true,
+                    Some(FileUpdated::Renamed(path)) => {
+                        self.path = path;
+                        is_renamed = true;
+                    }
+                }
+            }
+        } else if self.read_file_content.is_none() {
+            return Ok(Async::Ready(None));
+        }
+        if is_renamed && self.read_file_content.is_some() {
+            // The pending read may open the old path
+            self.start_read_file_content(Duration::from_secs(0));
         }
         if self.read_file_content.is_none() && self.is_updated {
             let wait = Duration::from_secs(MIN_READ_CONTENT_INTERVAL);
             self.start_read_file_content(wait);
         }
-        if let Async::Ready(Some(content)) = track!(self.read_file_content.poll())? {
+        if let Async::Ready(Some((content, mark))) = track!(self.read_file_content.poll())? {
             self.read_file_content = None;
             self.current_position = content.offset + content.data.len() as u64;
+            self.mark = mark;
             if !content.eof {
                 self.start_read_file_content(Duration::from_secs(0));
             }
-            if !content.data.is_empty() {
+
+            let is_first_eof = content.eof && !self.is_eof_reached;
+            self.is_eof_reached |= content.eof;
+            if !content.data.is_empty() || content.reset || is_first_eof {
                 return Ok(Async::Ready(Some(content)));
             }
+            if self.event_rx.is_none() {
+                return Ok(Async::Ready(None));
+            }
         }
         Ok(Async::NotReady)
     }
 }
 
+/// What is known about the file read so far, which is used to detect truncations and replacements.
+#[derive(Debug, Clone, Copy)]
+struct ReadMark {
+    // The device and inode numbers of the file
+    file_id: (u64, u64),
+
+    // The byte just before the current position
+    last_byte: u8,
+}
+
+type ReadResult = Result<(FileContent, Option<ReadMark>)>;
+
 #[derive(Debug)]
 struct ReadFileContent {
     path: PathBuf,
     offset: u64,
+    mark: Option<ReadMark>,
     wait: Fuse<Timeout>,
-    read: Option<AsyncCall<Result<FileContent>>>,
+    read: Option<AsyncCall<ReadResult>>,
 }
 impl ReadFileContent {
-    fn new(path: PathBuf, offset: u64, wait: Duration) -> Self {
+    fn new(path: PathBuf, offset: u64, mark: Option<ReadMark>, wait: Duration) -> Self {
         ReadFileContent {
             path,
             offset,
+            mark,
             wait: timer::timeout(wait).fuse(),
             read: None,
         }
     }
 }
 impl Future for ReadFileContent {
-    type Item = FileContent;
+    type Item = (FileContent, Option<ReadMark>);
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         if let Async::Ready(()) = track!(self.wait.poll().map_err(Error::from))? {
             let path = self.path.clone();
             let offset = self.offset;
+            let mark = self.mark;
             let future = DefaultIoTaskQueue.async_call(move || {
-                let mut file = track!(File::open(path).map_err(Error::from))?;
+                let mut file = track!(File::open(&path).map_err(Error::from))?;
+                let metadata = track!(file.metadata().map_err(Error::from))?;
+                let file_id = (metadata.dev(), metadata.ino());
+
+                let mut reset = false;
+                if let Some(mark) = mark {
+                    reset = mark.file_id != file_id || metadata.len() < offset || {
+                        let mut last_byte = [0];
+                        track!(file.seek(SeekFrom::Start(offset - 1)).map_err(Error::from))?;
+                        track!(file.read_exact(&mut last_byte).map_err(Error::from))?;
+                        mark.last_byte != last_byte[0]
+                    };
+                }
+                let offset = if reset { 0 } else { offset };
                 track!(file.seek(SeekFrom::Start(offset)).map_err(Error::from))?;
 
                 let mut buf = vec![0; READ_BUFFER_SIZE];
                 let read_size = track!(file.read(&mut buf).map_err(Error::from))?;
                 let eof = read_size < buf.len();
                 buf.truncate(read_size);
+                let mark = match buf.last() {
+                    Some(&last_byte) => Some(ReadMark { file_id, last_byte }),
+                    None if reset => None,
+                    None => mark,
+                };
                 let content = FileContent {
+                    path,
                     offset,
                     data: buf,
                     eof,
+                    reset,
+                    error: None,
                 };
-                Ok(content)
+                Ok((content, mark))
             });
             self.read = Some(future);
         }
-        if let Async::Ready(Some(content)) = track!(self.read.poll().map_err(Error::from))? {
-            let content = track!(content)?;
-            Ok(Async::Ready(content))
+        if let Async::Ready(Some(result)) = track!(self.read.poll().map_err(Error::from))? {
+            let result = track!(result)?;
+            Ok(Async::Ready(result))
         } else {
             Ok(Async::NotReady)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use fibers::{Executor, InPlaceExecutor, Spawn};
+    use std::fs;
+    use std::process;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("dg-file-test-{}-{}.log", name, process::id()))
+    }
+
+    fn read(
+        executor: &mut InPlaceExecutor,
+        path: &Path,
+        offset: u64,
+        mark: Option<ReadMark>,
+    ) -> (FileContent, Option<ReadMark>) {
+        let future = ReadFileContent::new(path.to_path_buf(), offset, mark, Duration::from_secs(0));
+        let monitor = executor.spawn_monitor(future);
+        let result = executor.run_fiber(monitor).expect("Never fails");
+        result.expect("Never fails")
+    }
+
+    fn read_to_end(
+        executor: &mut InPlaceExecutor,
+        path: &Path,
+    ) -> (FileContent, Option<ReadMark>) {
+        let (content, mark) = read(executor, path, 0, None);
+        assert!(content.eof);
+        assert!(!content.reset);
+        (content, mark)
+    }
+
+    #[test]
+    fn appended_contents_are_read_from_the_last_position() {
+        let path = temp_path("append");
+        fs::write(&path, "foo\n").expect("Never fails");
+
+        let mut executor = InPlaceExecutor::new().expect("Never fails");
+        let (content, mark) = read_to_end(&mut executor, &path);
+        fs::write(&path, "foo\nbar\n").expect("Never fails");
+        let (appended, _) = read(&mut executor, &path, content.data.len() as u64, mark);
+
+        // Nothing has been appended
+        let (empty, same_mark) = read(&mut executor, &path, 8, mark);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(appended.offset, 4);
+        assert_eq!(appended.data, b"bar\n");
+        assert!(!appended.reset);
+        assert!(empty.data.is_empty());
+        assert!(!empty.reset);
+        assert_eq!(same_mark.map(|m| m.last_byte), mark.map(|m| m.last_byte));
+    }
+
+    #[test]
+    fn truncations_are_detected() {
+        let path = temp_path("truncate");
+        fs::write(&path, "foo\nbar\n").expect("Never fails");
+
+        let mut executor = InPlaceExecutor::new().expect("Never fails");
+        let (content, mark) = read_to_end(&mut executor, &path);
+        let offset = content.data.len() as u64;
+
+        // Truncated to a shorter content
+        fs::write(&path, "baz").expect("Never fails");
+        let (shorter, _) = read(&mut executor, &path, offset, mark);
+
+        // Truncated and written beyond the last position (with a different byte before it)
+        fs::write(&path, "qux qux qux\n").expect("Never fails");
+        let (longer, _) = read(&mut executor, &path, offset, mark);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(shorter.offset, 0);
+        assert_eq!(shorter.data, b"baz");
+        assert!(shorter.reset);
+        assert_eq!(longer.offset, 0);
+        assert_eq!(longer.data, b"qux qux qux\n");
+        assert!(longer.reset);
+    }
+
+    #[test]
+    fn replacements_are_detected() {
+        let path = temp_path("replace");
+        let new_path = temp_path("replace-new");
+        fs::write(&path, "foo\n").expect("Never fails");
+
+        let mut executor = InPlaceExecutor::new().expect("Never fails");
+        let (content, mark) = read_to_end(&mut executor, &path);
+
+        // Another file which has the same content and more
+        fs::write(&new_path, "foo\nbar\n").expect("Never fails");
+        fs::rename(&new_path, &path).expect("Never fails");
+        let (replaced, _) = read(&mut executor, &path, content.data.len() as u64, mark);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(replaced.offset, 0);
+        assert_eq!(replaced.data, b"foo\nbar\n");
+        assert!(replaced.reset);
+    }
+}
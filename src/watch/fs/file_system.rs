@@ -1,93 +1,1062 @@
-use fibers::sync::mpsc;
+use fibers::sync::{mpsc, oneshot};
+use fibers::time::timer::{self, Timeout};
 use fibers::{BoxSpawn, Spawn};
 use fibers_inotify::InotifyService;
+use fibers_tasque::{AsyncCall, DefaultIoTaskQueue, TaskQueueExt};
 use futures::{Async, Future, Poll, Stream};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+use watch::fs::filter::{self, PathFilter};
+use watch::fs::tar_gzip;
 use watch::fs::{DirectoryEvent, DirectoryWatcher};
-use watch::fs::{FileUpdated, FileWatcher};
-use {Error, Result};
+use watch::fs::{FileType, FileUpdated, FileWatcher, WatchOptions};
+use {Error, ErrorKind, Result};
+
+/// The maximum time to wait for the other half of a rename.
+///
+/// The halves of a rename across directories are notified by different `DirectoryWatcher`s,
+/// so they may arrive separately (and in either order).
+const MOVE_PAIRING_TIMEOUT_MILLIS: u64 = 500;
+
+/// The interval of listing each watched directory to find the changes whose events have been lost
+/// (see `DirectoryEvent::Listed`).
+const RECONCILIATION_INTERVAL_SECS: u64 = 60;
+
+/// An event yielded by `FileSystemWatcher`.
+#[derive(Debug)]
+pub enum FileSystemEvent {
+    /// A file to be watched has been found.
+    Watch(Box<FileWatcher>),
+
+    /// A watched file has been renamed.
+    ///
+    /// The `FileWatcher` of the file keeps watching it under the new path.
+    /// If another file was being watched at `to`, its `FileWatcher` terminates.
+    Renamed { from: PathBuf, to: PathBuf },
+}
 
 #[derive(Debug)]
 pub struct FileSystemWatcher {
     spawner: BoxSpawn,
-    inotify_service: InotifyService,
+
+    // `None` means the one-shot mode
+    inotify_service: Option<InotifyService>,
+
+    // The filters of the root directories
+    filters: Vec<PathFilter>,
+
+    // Directories being listed in the one-shot mode
+    listings: Vec<DirectoryWatcher>,
     dir_event_rx: mpsc::Receiver<DirectoryEvent>,
     dir_event_tx: mpsc::Sender<DirectoryEvent>,
-    watching_files: HashMap<PathBuf, mpsc::Sender<FileUpdated>>,
+
+    // Directory events waiting for the I/O needed to handle them (in the order of arrival),
+    // and the results of the I/O for the first one
+    pending_events: VecDeque<DirectoryEvent>,
+    examining: Option<AsyncCall<HashMap<PathBuf, PathInfo>>>,
+    examined: HashMap<PathBuf, PathInfo>,
+
+    // The entries under a directory are adjacent in the `BTreeMap`s (see `entries_under`)
+    watching_files: BTreeMap<PathBuf, mpsc::Sender<FileUpdated>>,
+
+    // Files whose `FileWatcher`s are made once their types are detected,
+    // and the results of the detections (tagged with their IDs)
+    pending_files: HashMap<PathBuf, PendingFile>,
+    detected_tx: mpsc::Sender<Detected>,
+    detected_rx: mpsc::Receiver<Detected>,
+    next_detection_id: u64,
+
+    // Directories being watched (or listed), and their paths keyed by their (device, inode) pairs
+    watching_dirs: BTreeMap<PathBuf, WatchingDirectory>,
+    dir_paths: HashMap<(u64, u64), PathBuf>,
+
+    // Followed symbolic links to files
+    watching_links: BTreeMap<PathBuf, WatchingLink>,
+
+    // Halves of renames waiting for their counterparts (keyed by the inotify cookies)
+    pending_moves: HashMap<u32, PendingMove>,
+    pending_moves_timeout: Option<Timeout>,
+
+    reconciliation_interval: Duration,
+    rescans: u64,
+    overflows: u64,
 }
 impl FileSystemWatcher {
     pub fn new<S>(spawner: S) -> Self
     where
         S: Spawn + Send + 'static,
     {
-        let inotify_service = InotifyService::new();
+        Self::with_inotify_service(spawner, Some(InotifyService::new()))
+    }
+
+    /// Makes a `FileSystemWatcher` which scans the watched directories only once.
+    ///
+    /// No inotify watches are installed. Each yielded `FileWatcher` terminates
+    /// after reading its file to the end, and this stream terminates after
+    /// all the (sub)directories have been listed.
+    pub fn oneshot<S>(spawner: S) -> Self
+    where
+        S: Spawn + Send + 'static,
+    {
+        Self::with_inotify_service(spawner, None)
+    }
+    fn with_inotify_service<S>(spawner: S, inotify_service: Option<InotifyService>) -> Self
+    where
+        S: Spawn + Send + 'static,
+    {
         let (dir_event_tx, dir_event_rx) = mpsc::channel();
+        let (detected_tx, detected_rx) = mpsc::channel();
         FileSystemWatcher {
             spawner: spawner.boxed(),
             inotify_service,
+            filters: Vec::new(),
+            listings: Vec::new(),
             dir_event_rx,
             dir_event_tx,
-            watching_files: HashMap::new(),
+            pending_events: VecDeque::new(),
+            examining: None,
+            examined: HashMap::new(),
+            watching_files: BTreeMap::new(),
+            pending_files: HashMap::new(),
+            detected_tx,
+            detected_rx,
+            next_detection_id: 0,
+            watching_dirs: BTreeMap::new(),
+            dir_paths: HashMap::new(),
+            watching_links: BTreeMap::new(),
+            pending_moves: HashMap::new(),
+            pending_moves_timeout: None,
+            reconciliation_interval: Duration::from_secs(RECONCILIATION_INTERVAL_SECS),
+            rescans: 0,
+            overflows: 0,
+        }
+    }
+
+    /// Returns `true` if this is made by `FileSystemWatcher::oneshot`.
+    pub fn is_oneshot(&self) -> bool {
+        self.inotify_service.is_none()
+    }
+
+    /// Returns the number of the times a directory has been rescanned
+    /// because some of its events may have been lost (see `DirectoryEvent::Rescanned`).
+    pub fn rescans(&self) -> u64 {
+        self.rescans
+    }
+
+    /// Returns the number of the times the periodic listing of a directory has found
+    /// creations or removals whose events had been lost (see `DirectoryEvent::Listed`).
+    ///
+    /// `fibers_inotify` drops the notifications of overflows of the inotify queue
+    /// (`IN_Q_OVERFLOW`), so this is how they are detected.
+    /// The lost events are recovered by the listing, except the modifications of existing files,
+    /// which are read when they are modified again.
+    pub fn overflows(&self) -> u64 {
+        self.overflows
+    }
+
+    /// Makes the watched files under `path` be read again from the beginning.
+    ///
+    /// The current `FileWatcher`s of the files terminate,
+    /// and new ones are yielded by this stream.
+    /// If `path` is the virtual path of a member of an archive (see `tar_gzip::member_path`),
+    /// the whole archive is read again.
+    /// Returns the number of the files.
+    pub fn reindex<P: AsRef<Path>>(&mut self, path: P) -> usize {
+        let path = path.as_ref();
+        let path = self.watching_files
+            .keys()
+            .find(|archive| tar_gzip::is_member_path(path, archive))
+            .map_or(path, |archive| archive.as_path())
+            .to_path_buf();
+        let paths = self.watching_files
+            .keys()
+            .filter(|p| p.starts_with(&path))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in &paths {
+            self.watching_files.remove(path);
+            let event = DirectoryEvent::Updated {
+                path: path.clone(),
+                is_dir: false,
+            };
+            let _ = self.dir_event_tx.send(event);
         }
+        paths.len()
     }
+
+    /// Starts watching all the files under `root_dir`.
     pub fn watch<P: AsRef<Path>>(&mut self, root_dir: P) -> Result<()> {
-        let root_dir = root_dir.as_ref().to_path_buf();
+        track!(self.watch_with_options(root_dir, &WatchOptions::default()))
+    }
 
-        let watcher = track!(DirectoryWatcher::new(&self.inotify_service, &root_dir))?;
+    /// Starts watching the files under `root_dir` selected by `options`.
+    pub fn watch_with_options<P: AsRef<Path>>(
+        &mut self,
+        root_dir: P,
+        options: &WatchOptions,
+    ) -> Result<()> {
+        let filter = track!(PathFilter::new(&root_dir, options))?;
+        self.filters.push(filter);
 
+        // This is not called in `poll`, so the I/O is done here
+        let root_dir = root_dir.as_ref();
+        let mut paths = HashMap::new();
+        self.add_dir_to_examine(&mut paths, root_dir);
+        self.examined = examine(paths);
+        let result = track!(self.watch_dir(root_dir));
+        self.examined.clear();
+        result
+    }
+    fn watch_dir(&mut self, dir: &Path) -> Result<()> {
+        let (id, ignore_lines) = match self.examined.get(dir) {
+            Some(&PathInfo {
+                id: Some(id),
+                is_dir: true,
+                ref ignore_lines,
+                ..
+            }) => (id, ignore_lines.clone()),
+            _ => track_panic!(ErrorKind::InvalidInput, "not a directory: {:?}", dir),
+        };
+        if self.watching_dirs.get(dir).is_some_and(|d| d.id == id) {
+            return Ok(());
+        }
+
+        if self.watching_dirs.contains_key(dir) {
+            // The path refers to another directory now (e.g., a link has been changed)
+            self.unwatch(dir);
+        }
+        if self.follows_symlinks(dir) && self.dir_paths.contains_key(&id) {
+            // A loop, or another path to a directory already being watched
+            // (the entries of removed directories have been dropped by `unwatch`)
+            return Ok(());
+        }
+
+        // The ignore files are loaded before the entries of the directory are listed
+        if let Some(filter) = self.filter_mut(dir) {
+            filter.set_ignore_rules(dir, &ignore_lines);
+        }
+
+        let stop_tx = match self.inotify_service {
+            None => {
+                self.listings.push(DirectoryWatcher::list(dir));
+                None
+            }
+            Some(ref inotify_service) => {
+                let interval = self.reconciliation_interval;
+                let watcher = DirectoryWatcher::new(inotify_service, dir, interval);
+                Some(self.spawn_dir_watcher(watcher))
+            }
+        };
+        let watching = WatchingDirectory { id, _stop_tx: stop_tx };
+        self.watching_dirs.insert(dir.to_path_buf(), watching);
+        self.dir_paths.insert(id, dir.to_path_buf());
+        Ok(())
+    }
+
+    /// Starts watching the target of the symbolic link to a file.
+    ///
+    /// Returns `false` if the target is being watched through another link.
+    fn watch_link(&mut self, link: &Path) -> Result<bool> {
+        let id = match self.examined.get(link) {
+            Some(&PathInfo {
+                id: Some(id),
+                is_dir: false,
+                ..
+            }) => id,
+            _ => track_panic!(ErrorKind::InvalidInput, "not a link to a file: {:?}", link),
+        };
+        if self.watching_links.get(link).is_some_and(|l| l.id == id) {
+            return Ok(true);
+        }
+        self.watching_links.remove(link);
+        if self.watching_links.values().any(|l| l.id == id) {
+            return Ok(false);
+        }
+
+        let watcher = match self.inotify_service {
+            None => return Ok(true),
+            Some(ref inotify_service) => DirectoryWatcher::link(inotify_service, link),
+        };
+        let stop_tx = self.spawn_dir_watcher(watcher);
+        let watching = WatchingLink { id, _stop_tx: stop_tx };
+        self.watching_links.insert(link.to_path_buf(), watching);
+        Ok(true)
+    }
+
+    /// Spawns a fiber forwarding the events of `watcher`.
+    ///
+    /// The fiber stops when the returned sender is dropped.
+    fn spawn_dir_watcher(&mut self, watcher: DirectoryWatcher) -> oneshot::Sender<()> {
+        let (stop_tx, stop_rx) = oneshot::channel();
         let tx = self.dir_event_tx.clone();
         let future = watcher
             .for_each(move |event| tx.send(event).map_err(Error::from))
+            .select(stop_rx.then(|_| Ok(())))
             .then(move |_result| Ok(()));
         self.spawner.spawn(future);
-        Ok(())
+        stop_tx
     }
-    fn handle_dir_event(&mut self, dir_event: DirectoryEvent) -> Option<FileWatcher> {
+
+    /// Stops watching `path` and everything under it.
+    fn unwatch(&mut self, path: &Path) {
+        self.watching_files.retain(|p, _| !p.starts_with(path));
+        self.watching_dirs.retain(|p, _| !p.starts_with(path));
+        self.dir_paths.retain(|_, p| !p.starts_with(path));
+        self.watching_links.retain(|p, _| !p.starts_with(path));
+    }
+
+    /// Returns `Some(true)` if `path` is a symbolic link to a directory to be followed,
+    /// `Some(false)` if it is such a link to a file, and `None` otherwise.
+    fn resolve_link(&self, path: &Path) -> Option<bool> {
+        if !self.follows_symlinks(path) {
+            return None;
+        }
+        match self.examined.get(path) {
+            Some(info) if info.is_link => info.id.map(|_| info.is_dir),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the non-directory entry at `path` has been followed as a link.
+    fn is_followed_link(&self, path: &Path) -> bool {
+        self.watching_links.contains_key(path) || self.watching_dirs.contains_key(path)
+    }
+    fn filter(&self, path: &Path) -> Option<&PathFilter> {
+        self.filters
+            .iter()
+            .filter(|f| path.starts_with(f.root()))
+            .max_by_key(|f| f.root().components().count())
+    }
+    fn filter_mut(&mut self, path: &Path) -> Option<&mut PathFilter> {
+        self.filters
+            .iter_mut()
+            .filter(|f| path.starts_with(f.root()))
+            .max_by_key(|f| f.root().components().count())
+    }
+    fn is_filtered(&self, path: &Path, is_dir: bool) -> bool {
+        self.filter(path).is_some_and(|f| f.is_filtered(path, is_dir))
+    }
+    fn follows_symlinks(&self, path: &Path) -> bool {
+        self.filter(path).is_some_and(|f| f.follows_symlinks())
+    }
+    fn is_ignore_file(&self, path: &Path) -> bool {
+        self.filter(path).is_some_and(|f| f.is_ignore_file(path))
+    }
+    fn reload_ignore_files(&mut self, path: &Path) {
+        if !self.is_ignore_file(path) {
+            return;
+        }
+        let dir = path.parent().expect("Never fails");
+        if let Some(info) = self.examined.get(dir) {
+            let lines = info.ignore_lines.clone();
+            if let Some(filter) = self.filter_mut(path) {
+                filter.set_ignore_rules(dir, &lines);
+            }
+        }
+    }
+
+    /// Returns the paths to be examined on the I/O threads before handling `dir_event`
+    /// (see `examine`), and whether the ignore files in them are read.
+    fn paths_to_examine(&self, dir_event: &DirectoryEvent) -> HashMap<PathBuf, bool> {
+        let mut paths = HashMap::new();
+        match *dir_event {
+            DirectoryEvent::Updated { ref path, is_dir } => {
+                // A link to a directory is watched as the directory
+                if is_dir || self.follows_symlinks(path) {
+                    self.add_dir_to_examine(&mut paths, path);
+                }
+                self.add_ignore_files_to_examine(&mut paths, path);
+            }
+            DirectoryEvent::Removed { ref path, .. } => {
+                self.add_ignore_files_to_examine(&mut paths, path);
+            }
+            DirectoryEvent::MovedFrom { ref path, cookie }
+            | DirectoryEvent::MovedTo { ref path, cookie } => {
+                // The halves of the rename are handled together (see `handle_renamed`)
+                let counterpart = self.pending_moves.get(&cookie);
+                let from_to = counterpart.iter().flat_map(|m| m.from.iter().chain(m.to.iter()));
+                for path in Some(path).into_iter().chain(from_to) {
+                    if self.follows_symlinks(path) {
+                        self.add_dir_to_examine(&mut paths, path);
+                    }
+                    self.add_ignore_files_to_examine(&mut paths, path);
+                }
+            }
+            DirectoryEvent::Rescanned { ref path, .. }
+            | DirectoryEvent::Listed { ref path, .. } => {
+                self.add_dir_to_examine(&mut paths, path);
+            }
+        }
+        paths
+    }
+    fn add_dir_to_examine(&self, paths: &mut HashMap<PathBuf, bool>, dir: &Path) {
+        let honors_ignore_files = self.filter(dir).is_some_and(|f| f.honors_ignore_files());
+        *paths.entry(dir.to_path_buf()).or_insert(false) |= honors_ignore_files;
+    }
+    fn add_ignore_files_to_examine(&self, paths: &mut HashMap<PathBuf, bool>, path: &Path) {
+        if self.is_ignore_file(path) {
+            let dir = path.parent().expect("Never fails");
+            paths.insert(dir.to_path_buf(), true);
+        }
+    }
+
+    /// Handles the directory events in the order of arrival.
+    ///
+    /// The blocking I/O needed to handle an event (e.g., reading ignore files or resolving links)
+    /// is done on the I/O threads in advance, so the following events wait for it.
+    fn poll_pending_events(&mut self) -> Result<Option<FileSystemEvent>> {
+        loop {
+            if self.examining.is_none() {
+                let paths = match self.pending_events.front() {
+                    None => return Ok(None),
+                    Some(dir_event) => self.paths_to_examine(dir_event),
+                };
+                if !paths.is_empty() {
+                    self.examining = Some(DefaultIoTaskQueue.async_call(move || examine(paths)));
+                }
+            }
+            if let Some(mut examining) = self.examining.take() {
+                match track!(examining.poll().map_err(Error::from))? {
+                    Async::NotReady => {
+                        self.examining = Some(examining);
+                        return Ok(None);
+                    }
+                    Async::Ready(examined) => self.examined = examined,
+                }
+            }
+
+            let dir_event = self.pending_events.pop_front().expect("Never fails");
+            let event = self.handle_dir_event(dir_event);
+            self.examined.clear();
+            if event.is_some() {
+                return Ok(event);
+            }
+        }
+    }
+    fn handle_dir_event(&mut self, dir_event: DirectoryEvent) -> Option<FileSystemEvent> {
         match dir_event {
             DirectoryEvent::Updated { path, is_dir: true } => {
-                if let Err(_e) = self.watch(&path) {}
+                if !self.is_filtered(&path, true) {
+                    if let Err(_e) = self.watch_dir(&path) {}
+                }
+                None
+            }
+            DirectoryEvent::Removed { path, is_dir: true } => {
+                self.unwatch(&path);
+                None
+            }
+            DirectoryEvent::Updated {
+                path,
+                is_dir: false,
+            } if self.resolve_link(&path) == Some(true) => {
+                self.handle_dir_event(DirectoryEvent::Updated { path, is_dir: true })
+            }
+            DirectoryEvent::Updated {
+                path,
+                is_dir: false,
+            } if self.is_oneshot() => {
+                if self.is_filtered(&path, false) {
+                    return None;
+                }
+                self.start_detecting(path, None);
                 None
             }
-            DirectoryEvent::Removed { is_dir: true, .. } => None,
             DirectoryEvent::Updated {
                 path,
                 is_dir: false,
             } => {
+                self.reload_ignore_files(&path);
+                if self.resolve_link(&path) == Some(false) && !self.is_filtered(&path, false) {
+                    if let Ok(false) = self.watch_link(&path) {
+                        self.watching_files.remove(&path);
+                        return None;
+                    }
+                }
                 if let Some(file_event_tx) = self.watching_files.get(&path).cloned() {
-                    if file_event_tx.send(FileUpdated).is_err() {
+                    if file_event_tx.send(FileUpdated::Modified).is_err() {
                         self.watching_files.remove(&path);
                     } else {
                         return None;
                     }
                 }
+                if self.is_filtered(&path, false) {
+                    return None;
+                }
 
                 let (file_event_tx, file_event_rx) = mpsc::channel();
                 self.watching_files.insert(path.clone(), file_event_tx);
-                let file = FileWatcher::new(path, file_event_rx);
-                Some(file)
+                self.start_detecting(path, Some(file_event_rx));
+                None
             }
             DirectoryEvent::Removed {
                 path,
                 is_dir: false,
             } => {
-                self.watching_files.remove(&path);
+                self.reload_ignore_files(&path);
+                if self.is_followed_link(&path) {
+                    self.unwatch(&path);
+                } else {
+                    self.watching_files.remove(&path);
+                }
+                None
+            }
+            DirectoryEvent::Rescanned { path, entries } => {
+                self.rescans += 1;
+                let entries = entries.into_iter().collect::<HashSet<_>>();
+                self.unwatch_vanished_entries(&path, &entries);
                 None
             }
+            DirectoryEvent::Listed { path, entries } => {
+                // The entries which have appeared while the events were lost
+                // (links may be left unwatched deliberately, e.g., as duplicates)
+                let created = entries
+                    .iter()
+                    .filter(|e| !(e.is_link && self.follows_symlinks(&e.path)))
+                    .filter(|e| !self.is_known(&e.path) && !self.is_filtered(&e.path, e.is_dir))
+                    .map(|e| DirectoryEvent::Updated {
+                        path: e.path.clone(),
+                        is_dir: e.is_dir,
+                    })
+                    .collect::<Vec<_>>();
+                let entries = entries.into_iter().map(|e| e.path).collect::<HashSet<_>>();
+                let removed = self.unwatch_vanished_entries(&path, &entries);
+                if !created.is_empty() || removed > 0 {
+                    self.overflows += 1;
+                }
+
+                // They are handled before the events following the listing
+                for event in created.into_iter().rev() {
+                    self.pending_events.push_front(event);
+                }
+                None
+            }
+            DirectoryEvent::MovedFrom { path, cookie } => match self.pending_moves.remove(&cookie) {
+                Some(PendingMove { to: Some(to), .. }) => self.handle_renamed(path, to),
+                _ => {
+                    self.add_pending_move(cookie, Some(path), None);
+                    None
+                }
+            },
+            DirectoryEvent::MovedTo { path, cookie } => match self.pending_moves.remove(&cookie) {
+                Some(PendingMove {
+                    from: Some(from), ..
+                }) => self.handle_renamed(from, path),
+                _ => {
+                    self.add_pending_move(cookie, None, Some(path));
+                    None
+                }
+            },
+        }
+    }
+
+    /// Stops watching the entries of `dir` which have disappeared while the events were lost,
+    /// and returns the number of them.
+    fn unwatch_vanished_entries(&mut self, dir: &Path, entries: &HashSet<PathBuf>) -> usize {
+        let removed = entries_under(&self.watching_files, dir)
+            .chain(entries_under(&self.watching_dirs, dir))
+            .chain(entries_under(&self.watching_links, dir))
+            .filter(|p| p.parent() == Some(dir) && !entries.contains(*p))
+            .filter(|p| !self.is_moving(p))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in &removed {
+            self.reload_ignore_files(path);
+            self.unwatch(path);
+        }
+        removed.len()
+    }
+
+    /// Returns `true` if `path` is being watched or waiting for the other half of a rename.
+    fn is_known(&self, path: &Path) -> bool {
+        self.watching_files.contains_key(path)
+            || self.watching_dirs.contains_key(path)
+            || self.watching_links.contains_key(path)
+            || self.is_moving(path)
+    }
+
+    /// Returns `true` if `path` is a half of a rename waiting for the other half.
+    fn is_moving(&self, path: &Path) -> bool {
+        self.pending_moves.values().any(|m| {
+            m.from.as_ref().is_some_and(|p| p == path) || m.to.as_ref().is_some_and(|p| p == path)
+        })
+    }
+    fn handle_renamed(&mut self, from: PathBuf, to: PathBuf) -> Option<FileSystemEvent> {
+        self.reload_ignore_files(&from);
+        self.reload_ignore_files(&to);
+        if self.is_followed_link(&from) || self.resolve_link(&to).is_some() {
+            // e.g., a temporary link renamed by `ln -sfn` (the target is resolved again)
+            self.unwatch(&from);
+            return self.handle_dir_event(DirectoryEvent::Updated {
+                path: to,
+                is_dir: false,
+            });
+        }
+        if self.is_filtered(&to, false) {
+            self.watching_files.remove(&from);
+            self.watching_files.remove(&to);
+            return None;
+        }
+        if let Some(pending) = self.pending_files.remove(&from) {
+            if let Some(file_event_tx) = self.watching_files.remove(&from) {
+                // The watcher has not been yielded yet, so it is made for the new path instead
+                self.watching_files.insert(to.clone(), file_event_tx);
+                self.start_detecting(to, pending.event_rx);
+                return None;
+            }
+        }
+        if let Some(file_event_tx) = self.watching_files.remove(&from) {
+            if file_event_tx.send(FileUpdated::Renamed(to.clone())).is_ok() {
+                self.watching_files.insert(to.clone(), file_event_tx);
+                return Some(FileSystemEvent::Renamed { from, to });
+            }
+        }
+        self.handle_dir_event(DirectoryEvent::Updated {
+            path: to,
+            is_dir: false,
+        })
+    }
+
+    /// Starts detecting the type of the file at `path` on the I/O threads.
+    ///
+    /// `FileType::detect` reads (and may decompress) the beginning of the file,
+    /// so it is not called in `poll`. The `FileWatcher` of the file is yielded
+    /// by `poll_pending_files` after the detection.
+    fn start_detecting(&mut self, path: PathBuf, event_rx: Option<mpsc::Receiver<FileUpdated>>) {
+        let id = self.next_detection_id;
+        self.next_detection_id += 1;
+        self.pending_files.insert(path.clone(), PendingFile { id, event_rx });
+
+        // The result is sent from the I/O thread directly, so that no fiber is woken up in between
+        let detected_tx = self.detected_tx.clone();
+        DefaultIoTaskQueue.with(|queue| {
+            queue.enqueue(move || {
+                let file_type = FileType::detect(&path);
+                let _ = detected_tx.send(Detected { id, path, file_type });
+            })
+        });
+    }
+
+    /// Returns the `FileWatcher` of a file whose type has been detected.
+    fn poll_pending_files(&mut self) -> Result<Option<FileSystemEvent>> {
+        while let Async::Ready(Some(detected)) = self.detected_rx.poll().expect("Never fails") {
+            let Detected { id, path, file_type } = detected;
+            if self.pending_files.get(&path).is_none_or(|p| p.id != id) {
+                // Abandoned (e.g., the file has been renamed during the detection)
+                continue;
+            }
+            let pending = self.pending_files.remove(&path).expect("Never fails");
+            let file = match pending.event_rx {
+                None => FileWatcher::oneshot(path, file_type),
+                Some(_) if !self.watching_files.contains_key(&path) => {
+                    // Removed (or unwatched) during the detection
+                    continue;
+                }
+                Some(event_rx) => FileWatcher::new(path, file_type, event_rx),
+            };
+            return Ok(Some(FileSystemEvent::Watch(Box::new(file))));
+        }
+        Ok(None)
+    }
+    fn add_pending_move(&mut self, cookie: u32, from: Option<PathBuf>, to: Option<PathBuf>) {
+        let deadline = Instant::now() + Duration::from_millis(MOVE_PAIRING_TIMEOUT_MILLIS);
+        self.pending_moves.insert(cookie, PendingMove { from, to, deadline });
+    }
+
+    /// Handles the halves of renames whose counterparts have not arrived in time.
+    ///
+    /// They are regarded as moves from or into unwatched directories.
+    fn poll_pending_moves(&mut self) -> Result<()> {
+        loop {
+            let now = Instant::now();
+            let expired = self.pending_moves
+                .iter()
+                .find(|(_, m)| m.deadline <= now)
+                .map(|(&cookie, _)| cookie);
+            if let Some(cookie) = expired {
+                let pending = self.pending_moves.remove(&cookie).expect("Never fails");
+                let dir_event = match (pending.from, pending.to) {
+                    (Some(path), _) => DirectoryEvent::Removed {
+                        path,
+                        is_dir: false,
+                    },
+                    (None, Some(path)) => DirectoryEvent::Updated {
+                        path,
+                        is_dir: false,
+                    },
+                    (None, None) => continue,
+                };
+                self.pending_events.push_back(dir_event);
+                continue;
+            }
+
+            let deadline = match self.pending_moves.values().map(|m| m.deadline).min() {
+                None => {
+                    self.pending_moves_timeout = None;
+                    return Ok(());
+                }
+                Some(deadline) => deadline,
+            };
+            let timeout = self.pending_moves_timeout
+                .get_or_insert_with(|| timer::timeout(deadline - now));
+            if let Async::NotReady = track!(timeout.poll().map_err(Error::from))? {
+                return Ok(());
+            }
+            self.pending_moves_timeout = None;
         }
     }
 }
 impl Stream for FileSystemWatcher {
-    type Item = FileWatcher;
+    type Item = FileSystemEvent;
     type Error = Error;
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        track!(self.inotify_service.poll().map_err(Error::from))?;
+        if let Some(ref mut inotify_service) = self.inotify_service {
+            track!(inotify_service.poll().map_err(Error::from))?;
+        }
         while let Async::Ready(Some(dir_event)) = self.dir_event_rx.poll().expect("Never fails") {
-            if let Some(file) = self.handle_dir_event(dir_event) {
-                return Ok(Async::Ready(Some(file)));
+            self.pending_events.push_back(dir_event);
+        }
+        if !self.is_oneshot() {
+            track!(self.poll_pending_moves())?;
+        }
+
+        let mut i = 0;
+        while i < self.listings.len() {
+            match self.listings[i].poll() {
+                Ok(Async::NotReady) => i += 1,
+                Ok(Async::Ready(Some(dir_event))) => {
+                    self.pending_events.push_back(dir_event);
+                }
+                Ok(Async::Ready(None)) | Err(_) => {
+                    self.listings.swap_remove(i);
+                }
+            }
+        }
+        if let Some(event) = track!(self.poll_pending_events())? {
+            return Ok(Async::Ready(Some(event)));
+        }
+        if let Some(event) = track!(self.poll_pending_files())? {
+            return Ok(Async::Ready(Some(event)));
+        }
+        let is_done = self.listings.is_empty()
+            && self.pending_events.is_empty()
+            && self.pending_files.is_empty();
+        if self.is_oneshot() && is_done {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct WatchingDirectory {
+    id: (u64, u64),
+
+    // Dropping this stops the `DirectoryWatcher` (`None` in the one-shot mode)
+    _stop_tx: Option<oneshot::Sender<()>>,
+}
+
+#[derive(Debug)]
+struct WatchingLink {
+    id: (u64, u64),
+    _stop_tx: oneshot::Sender<()>,
+}
+
+/// What has been found about a path by `examine`.
+#[derive(Debug)]
+struct PathInfo {
+    is_link: bool,
+
+    // The (device, inode) pair and the type of the file at the path (following symbolic links),
+    // `None` and `false` if the file does not exist
+    id: Option<(u64, u64)>,
+    is_dir: bool,
+
+    // The lines of the ignore files in the directory at the path (see `filter::read_ignore_files`)
+    ignore_lines: Vec<String>,
+}
+
+#[derive(Debug)]
+struct PendingFile {
+    // The ID of the latest detection of the type
+    id: u64,
+
+    // `None` in the one-shot mode
+    event_rx: Option<mpsc::Receiver<FileUpdated>>,
+}
+
+/// The result of a detection started by `FileSystemWatcher::start_detecting`.
+#[derive(Debug)]
+struct Detected {
+    id: u64,
+    path: PathBuf,
+    file_type: FileType,
+}
+
+#[derive(Debug)]
+struct PendingMove {
+    from: Option<PathBuf>,
+    to: Option<PathBuf>,
+    deadline: Instant,
+}
+
+/// Returns the paths under `dir` in `map`.
+fn entries_under<'a, T>(
+    map: &'a BTreeMap<PathBuf, T>,
+    dir: &'a Path,
+) -> impl Iterator<Item = &'a PathBuf> + 'a {
+    map.range(dir.to_path_buf()..)
+        .map(|(path, _)| path)
+        .take_while(move |path| path.starts_with(dir))
+}
+
+/// Does the blocking I/O needed to handle an event concerning the paths.
+///
+/// The ignore files are read only in the directories mapped to `true`.
+fn examine(paths: HashMap<PathBuf, bool>) -> HashMap<PathBuf, PathInfo> {
+    paths
+        .into_iter()
+        .map(|(path, reads_ignore_files)| {
+            let is_link = fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_symlink());
+            let metadata = fs::metadata(&path).ok();
+            let id = metadata.as_ref().map(|m| (m.dev(), m.ino()));
+            let is_dir = metadata.is_some_and(|m| m.is_dir());
+            let ignore_lines = if is_dir && reads_ignore_files {
+                filter::read_ignore_files(&path)
+            } else {
+                Vec::new()
+            };
+            let info = PathInfo {
+                is_link,
+                id,
+                is_dir,
+                ignore_lines,
+            };
+            (path, info)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use fibers::{Executor, InPlaceExecutor};
+    use futures::future;
+    use std::process;
+
+    use super::*;
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("dg-fs-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).expect("Never fails");
+        root
+    }
+
+    /// Polls `watcher` for `millis` milliseconds, and returns the events yielded meanwhile.
+    fn run_for(
+        executor: &mut InPlaceExecutor,
+        watcher: FileSystemWatcher,
+        millis: u64,
+    ) -> (FileSystemWatcher, Vec<FileSystemEvent>) {
+        let mut watcher = Some(watcher);
+        let mut events = Vec::new();
+        let mut timeout = timer::timeout(Duration::from_millis(millis));
+        let future = future::poll_fn(move || {
+            while let Async::Ready(event) = track!(watcher.as_mut().expect("Never fails").poll())? {
+                events.push(event.expect("Never fails"));
+            }
+            if let Async::NotReady = track!(timeout.poll().map_err(Error::from))? {
+                return Ok(Async::NotReady);
             }
+            let events = std::mem::take(&mut events);
+            Ok(Async::Ready((watcher.take().expect("Never fails"), events)))
+        });
+        let future = future.map_err(|e: Error| e);
+        let monitor = executor.spawn_monitor(future);
+        let result = executor.run_fiber(monitor).expect("Never fails");
+        result.expect("Never fails")
+    }
+
+    /// Returns `true` if `file` terminates within a second.
+    fn terminates(executor: &mut InPlaceExecutor, file: FileWatcher) -> bool {
+        let timeout = timer::timeout(Duration::from_secs(1)).map_err(Error::from);
+        let future = file.for_each(|_| Ok(()))
+            .map(|()| true)
+            .select(timeout.map(|()| false))
+            .map(|(terminated, _)| terminated)
+            .map_err(|(e, _)| e);
+        let monitor = executor.spawn_monitor(future);
+        let result = executor.run_fiber(monitor).expect("Never fails");
+        result.expect("Never fails")
+    }
+
+    fn take_watcher(events: &mut Vec<FileSystemEvent>, path: &Path) -> Option<FileWatcher> {
+        let i = events.iter().position(|e| match *e {
+            FileSystemEvent::Watch(ref file) => file.path() == path,
+            FileSystemEvent::Renamed { .. } => false,
+        })?;
+        match events.remove(i) {
+            FileSystemEvent::Watch(file) => Some(*file),
+            FileSystemEvent::Renamed { .. } => unreachable!(),
         }
-        Ok(Async::NotReady)
+    }
+
+    fn renames(events: &[FileSystemEvent]) -> Vec<(PathBuf, PathBuf)> {
+        events
+            .iter()
+            .filter_map(|e| match *e {
+                FileSystemEvent::Renamed { ref from, ref to } => Some((from.clone(), to.clone())),
+                FileSystemEvent::Watch(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn files_moved_across_directories_are_renamed() {
+        let root = temp_root("across");
+        fs::create_dir(root.join("a")).expect("Never fails");
+        fs::create_dir(root.join("b")).expect("Never fails");
+        fs::write(root.join("a/foo.log"), "foo\n").expect("Never fails");
+        fs::write(root.join("a/bar.log"), "bar\n").expect("Never fails");
+
+        let mut executor = InPlaceExecutor::new().expect("Never fails");
+        let mut watcher = FileSystemWatcher::new(executor.handle());
+        watcher.watch(&root).expect("Never fails");
+        let (watcher, mut events) = run_for(&mut executor, watcher, 300);
+        let foo = take_watcher(&mut events, &root.join("a/foo.log"));
+        let bar = take_watcher(&mut events, &root.join("a/bar.log"));
+        assert!(foo.is_some());
+        assert!(bar.is_some());
+
+        // The halves are notified by the watchers of the different directories
+        fs::rename(root.join("a/foo.log"), root.join("b/foo.log")).expect("Never fails");
+        let (watcher, events) = run_for(&mut executor, watcher, 300);
+        assert_eq!(
+            renames(&events),
+            [(root.join("a/foo.log"), root.join("b/foo.log"))]
+        );
+
+        // The halves may arrive in either order
+        let moved_to = DirectoryEvent::MovedTo {
+            path: root.join("b/bar.log"),
+            cookie: 12345,
+        };
+        let moved_from = DirectoryEvent::MovedFrom {
+            path: root.join("a/bar.log"),
+            cookie: 12345,
+        };
+        watcher.dir_event_tx.send(moved_to).expect("Never fails");
+        watcher.dir_event_tx.send(moved_from).expect("Never fails");
+        let (watcher, events) = run_for(&mut executor, watcher, 100);
+        let _ = fs::remove_dir_all(&root);
+
+        assert_eq!(
+            renames(&events),
+            [(root.join("a/bar.log"), root.join("b/bar.log"))]
+        );
+        assert!(watcher.watching_files.contains_key(&root.join("b/foo.log")));
+        assert!(watcher.watching_files.contains_key(&root.join("b/bar.log")));
+        assert!(!watcher.watching_files.contains_key(&root.join("a/foo.log")));
+        assert!(!watcher.watching_files.contains_key(&root.join("a/bar.log")));
+        assert!(watcher.pending_moves.is_empty());
+    }
+
+    #[test]
+    fn unpaired_moves_are_handled_after_timeout() {
+        let root = temp_root("unpaired");
+        let outside = temp_root("unpaired-outside");
+        fs::write(root.join("foo.log"), "foo\n").expect("Never fails");
+        fs::write(outside.join("bar.log"), "bar\n").expect("Never fails");
+
+        let mut executor = InPlaceExecutor::new().expect("Never fails");
+        let mut watcher = FileSystemWatcher::new(executor.handle());
+        watcher.watch(&root).expect("Never fails");
+        let (watcher, mut events) = run_for(&mut executor, watcher, 300);
+        let foo = take_watcher(&mut events, &root.join("foo.log")).expect("Never fails");
+
+        // Moved out of and into the watched directory
+        fs::rename(root.join("foo.log"), outside.join("foo.log")).expect("Never fails");
+        fs::rename(outside.join("bar.log"), root.join("bar.log")).expect("Never fails");
+        let (watcher, events) = run_for(&mut executor, watcher, 100);
+        assert!(events.is_empty());
+        assert_eq!(watcher.pending_moves.len(), 2);
+        assert!(watcher.watching_files.contains_key(&root.join("foo.log")));
+
+        let wait = MOVE_PAIRING_TIMEOUT_MILLIS + 200;
+        let (watcher, mut events) = run_for(&mut executor, watcher, wait);
+        let _ = fs::remove_dir_all(&root);
+        let _ = fs::remove_dir_all(&outside);
+
+        assert!(take_watcher(&mut events, &root.join("bar.log")).is_some());
+        assert!(renames(&events).is_empty());
+        assert!(watcher.pending_moves.is_empty());
+        assert!(!watcher.watching_files.contains_key(&root.join("foo.log")));
+        assert!(terminates(&mut executor, foo));
+    }
+
+    #[test]
+    fn renames_over_watched_files_replace_them() {
+        let root = temp_root("over");
+        fs::write(root.join("foo.log"), "foo\n").expect("Never fails");
+        fs::write(root.join("foo.log.tmp"), "new foo\n").expect("Never fails");
+
+        let mut executor = InPlaceExecutor::new().expect("Never fails");
+        let mut watcher = FileSystemWatcher::new(executor.handle());
+        watcher.watch(&root).expect("Never fails");
+        let (watcher, mut events) = run_for(&mut executor, watcher, 300);
+        let old = take_watcher(&mut events, &root.join("foo.log")).expect("Never fails");
+        let new = take_watcher(&mut events, &root.join("foo.log.tmp")).expect("Never fails");
+
+        fs::rename(root.join("foo.log.tmp"), root.join("foo.log")).expect("Never fails");
+        let (watcher, events) = run_for(&mut executor, watcher, 300);
+        assert_eq!(
+            renames(&events),
+            [(root.join("foo.log.tmp"), root.join("foo.log"))]
+        );
+        assert!(watcher.watching_files.contains_key(&root.join("foo.log")));
+        assert!(!watcher.watching_files.contains_key(&root.join("foo.log.tmp")));
+
+        // The watcher of the replaced file terminates, and the other one keeps watching
+        assert!(terminates(&mut executor, old));
+        assert!(!terminates(&mut executor, new));
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn lost_events_are_recovered_by_periodic_listings() {
+        let root = temp_root("listing");
+        fs::create_dir(root.join("sub")).expect("Never fails");
+        fs::write(root.join("foo.log"), "foo\n").expect("Never fails");
+        fs::write(root.join("sub/bar.log"), "bar\n").expect("Never fails");
+
+        let mut executor = InPlaceExecutor::new().expect("Never fails");
+        let mut watcher = FileSystemWatcher::new(executor.handle());
+        watcher.reconciliation_interval = Duration::from_millis(200);
+        watcher.watch(&root).expect("Never fails");
+        let (mut watcher, mut events) = run_for(&mut executor, watcher, 100);
+        assert!(take_watcher(&mut events, &root.join("foo.log")).is_some());
+        assert!(take_watcher(&mut events, &root.join("sub/bar.log")).is_some());
+
+        // As if the creation of `foo.log` and the removal of `baz.log` had not been notified
+        watcher.watching_files.remove(&root.join("foo.log"));
+        let (baz_tx, _baz_rx) = mpsc::channel();
+        watcher.watching_files.insert(root.join("baz.log"), baz_tx);
+
+        let (watcher, mut events) = run_for(&mut executor, watcher, 400);
+        assert!(take_watcher(&mut events, &root.join("foo.log")).is_some());
+        assert!(events.is_empty());
+        assert!(!watcher.watching_files.contains_key(&root.join("baz.log")));
+        assert_eq!(watcher.overflows(), 1);
+
+        // Nothing has been lost since then
+        let (watcher, events) = run_for(&mut executor, watcher, 400);
+        let _ = fs::remove_dir_all(&root);
+        assert!(events.is_empty());
+        assert_eq!(watcher.overflows(), 1);
+        assert_eq!(watcher.rescans(), 0);
     }
 }
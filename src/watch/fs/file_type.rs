@@ -0,0 +1,154 @@
+use flate2::read::MultiGzDecoder;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use watch::fs::{gzip, tar_gzip};
+
+/// The number of the leading bytes of a file examined to detect its type.
+const HEADER_SIZE: u64 = 512;
+
+const MAGICS: &[(&[u8], FileType)] = &[
+    (b"\x1f\x8b", FileType::Gzip),
+    (b"PK\x03\x04", FileType::Zip),
+    (b"PK\x05\x06", FileType::Zip),
+    (b"PK\x07\x08", FileType::Zip),
+    (b"BZh", FileType::Compressed),
+    (b"\xfd7zXZ\x00", FileType::Compressed),
+    (b"\x28\xb5\x2f\xfd", FileType::Compressed),
+    (b"7z\xbc\xaf\x27\x1c", FileType::Compressed),
+    (b"\x7fELF", FileType::Executable),
+    (b"\xfe\xed\xfa\xce", FileType::Executable),
+    (b"\xfe\xed\xfa\xcf", FileType::Executable),
+    (b"\xce\xfa\xed\xfe", FileType::Executable),
+    (b"\xcf\xfa\xed\xfe", FileType::Executable),
+    (b"\xca\xfe\xba\xbe", FileType::Executable),
+    (b"\x89PNG\r\n\x1a\n", FileType::Image),
+    (b"\xff\xd8\xff", FileType::Image),
+    (b"GIF87a", FileType::Image),
+    (b"GIF89a", FileType::Image),
+    (b"II*\x00", FileType::Image),
+    (b"MM\x00*", FileType::Image),
+    (b"\x00\x00\x01\x00", FileType::Image),
+    (b"%PDF-", FileType::Pdf),
+];
+
+/// The type of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileType {
+    /// A text file (or a file of an unknown type).
+    Text,
+
+    /// A gzip compressed file.
+    Gzip,
+
+    /// A gzip compressed tar archive.
+    TarGzip,
+
+    /// An uncompressed tar archive.
+    Tar,
+
+    /// A zip archive (including jar files and so on).
+    Zip,
+
+    /// A file compressed by an unsupported method (bzip2, xz, zstd or 7z).
+    Compressed,
+
+    /// An executable or object file (ELF, Mach-O, PE or Java class).
+    Executable,
+
+    /// An image file (PNG, JPEG, GIF, WebP, TIFF or ICO).
+    Image,
+
+    /// A PDF document.
+    Pdf,
+}
+impl FileType {
+    /// Detects the type of the file at `path`.
+    ///
+    /// The type is guessed from the magic bytes at the beginning of the file.
+    /// The extension of the path is used only if the file is too short to tell
+    /// (e.g., it has just been created), or to tell a tar archive without
+    /// the ustar magic in a gzip compressed file.
+    /// Files which can not be read are regarded as text files.
+    pub fn detect<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref();
+        let mut header = Vec::new();
+        if let Ok(file) = File::open(path) {
+            let _ = file.take(HEADER_SIZE).read_to_end(&mut header);
+        }
+        match Self::from_magic(&header) {
+            Some(FileType::Gzip) => {
+                if tar_gzip::is_tar_gzip_path(path) || is_gzip_compressed_tar(path) {
+                    FileType::TarGzip
+                } else {
+                    FileType::Gzip
+                }
+            }
+            Some(file_type) => file_type,
+            None if header.len() < 8 => Self::from_extension(path),
+            None => FileType::Text,
+        }
+    }
+
+    /// Returns `true` if the contents of files of this type can not be indexed as text
+    /// even after decompression.
+    pub fn is_binary(self) -> bool {
+        !matches!(self, FileType::Text | FileType::Gzip | FileType::TarGzip)
+    }
+
+    fn from_magic(header: &[u8]) -> Option<Self> {
+        if let Some(&(_, file_type)) = MAGICS.iter().find(|&&(m, _)| header.starts_with(m)) {
+            Some(file_type)
+        } else if header.starts_with(b"MZ") && header.contains(&0) {
+            // PE (the magic is too short to be reliable by itself)
+            Some(FileType::Executable)
+        } else if header.starts_with(b"RIFF") && header.get(8..12) == Some(b"WEBP") {
+            Some(FileType::Image)
+        } else if is_tar_header(header) {
+            Some(FileType::Tar)
+        } else {
+            None
+        }
+    }
+
+    fn from_extension(path: &Path) -> Self {
+        if tar_gzip::is_tar_gzip_path(path) {
+            return FileType::TarGzip;
+        }
+        if gzip::is_gzip_path(path) {
+            return FileType::Gzip;
+        }
+        let extension = match path.extension().and_then(|e| e.to_str()) {
+            None => return FileType::Text,
+            Some(e) => e.to_lowercase(),
+        };
+        match extension.as_str() {
+            "tar" => FileType::Tar,
+            "zip" | "jar" | "war" => FileType::Zip,
+            "bz2" | "xz" | "zst" | "7z" => FileType::Compressed,
+            "so" | "o" | "a" | "exe" | "dll" | "class" => FileType::Executable,
+            "png" | "jpg" | "jpeg" | "gif" | "webp" | "tif" | "tiff" | "ico" => FileType::Image,
+            "pdf" => FileType::Pdf,
+            _ => FileType::Text,
+        }
+    }
+}
+
+fn is_tar_header(header: &[u8]) -> bool {
+    // "ustar\0" (POSIX) or "ustar " (GNU)
+    header
+        .get(257..263)
+        .is_some_and(|magic| magic == b"ustar\x00" || magic == b"ustar ")
+}
+
+fn is_gzip_compressed_tar(path: &Path) -> bool {
+    let mut header = Vec::new();
+    if let Ok(file) = File::open(path) {
+        // Broken streams are not regarded as archives unless the header could be decompressed
+        let _ = MultiGzDecoder::new(file)
+            .take(HEADER_SIZE)
+            .read_to_end(&mut header);
+    }
+    is_tar_header(&header)
+}
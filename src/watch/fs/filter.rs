@@ -0,0 +1,401 @@
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use {Error, ErrorKind, Result};
+
+/// The names of the ignore files honored if `WatchOptions::ignore_files` is `true`.
+///
+/// The rules of the latter take precedence over the former in the same directory.
+pub const IGNORE_FILE_NAMES: [&str; 2] = [".gitignore", ".dgignore"];
+
+/// Options for selecting the files watched under a root directory.
+///
+/// Patterns are written in the syntax of `.gitignore`, and matched against the paths
+/// relative to the root directory: patterns containing no slashes (except the trailing one)
+/// match the names at any depth, and the others are anchored to the root directory.
+#[derive(Debug, Clone, Default)]
+pub struct WatchOptions {
+    /// Patterns of the files to be watched.
+    ///
+    /// If empty, all the files are watched.
+    /// Directories are traversed regardless of these patterns.
+    pub include: Vec<String>,
+
+    /// Patterns of the files and directories not to be watched.
+    ///
+    /// Excluded directories are not traversed at all.
+    pub exclude: Vec<String>,
+
+    /// If `true`, the rules of the ignore files (see `IGNORE_FILE_NAMES`) found under
+    /// the root directory are honored, and `.git` directories are ignored.
+    ///
+    /// Ignore files are reloaded when they are changed,
+    /// but the new rules do not affect the files already being watched.
+    pub ignore_files: bool,
+
+    /// If `true`, symbolic links to files and directories are followed.
+    ///
+    /// The targets are watched, but reported under the paths of the links.
+    /// A directory reachable through several paths (e.g., via a link to its ancestor)
+    /// is watched only under the path found first.
+    pub follow_symlinks: bool,
+}
+
+/// A filter of the paths under a root directory made from `WatchOptions`.
+#[derive(Debug)]
+pub struct PathFilter {
+    root: PathBuf,
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+    ignore_files: bool,
+    follow_symlinks: bool,
+
+    // The rules of the ignore files keyed by the directories containing them
+    ignore_rules: HashMap<PathBuf, Vec<Glob>>,
+}
+impl PathFilter {
+    pub fn new<P: AsRef<Path>>(root: P, options: &WatchOptions) -> Result<Self> {
+        let compile = |patterns: &[String]| -> Result<Vec<Glob>> {
+            let mut globs = Vec::new();
+            for pattern in patterns {
+                let glob = track!(Glob::new(pattern))?;
+                track_assert!(
+                    !glob.is_negated,
+                    ErrorKind::InvalidInput,
+                    "negated patterns are not allowed: {:?}",
+                    pattern
+                );
+                globs.push(glob);
+            }
+            Ok(globs)
+        };
+        Ok(PathFilter {
+            root: root.as_ref().to_path_buf(),
+            include: track!(compile(&options.include))?,
+            exclude: track!(compile(&options.exclude))?,
+            ignore_files: options.ignore_files,
+            follow_symlinks: options.follow_symlinks,
+            ignore_rules: HashMap::new(),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn follows_symlinks(&self) -> bool {
+        self.follow_symlinks
+    }
+
+    /// Returns `true` if the file (or directory) at `path` should not be watched.
+    ///
+    /// The ancestor directories of `path` are assumed to have passed this filter.
+    pub fn is_filtered(&self, path: &Path, is_dir: bool) -> bool {
+        let relative = match path.strip_prefix(&self.root) {
+            Err(_) => return true,
+            Ok(relative) => relative,
+        };
+        if self.exclude.iter().any(|g| g.is_match(relative, is_dir)) {
+            return true;
+        }
+        if !is_dir && !self.include.is_empty()
+            && !self.include.iter().any(|g| g.is_match(relative, is_dir))
+        {
+            return true;
+        }
+        self.ignore_files && self.is_ignored(path, is_dir)
+    }
+
+    /// Returns `true` if `path` is the path of an ignore file honored by this filter.
+    pub fn is_ignore_file(&self, path: &Path) -> bool {
+        self.ignore_files
+            && path.file_name()
+                .is_some_and(|name| IGNORE_FILE_NAMES.iter().any(|n| name == *n))
+    }
+
+    /// Returns `true` if the rules of the ignore files are honored by this filter.
+    pub fn honors_ignore_files(&self) -> bool {
+        self.ignore_files
+    }
+
+    /// Sets (or resets) the rules of the ignore files in `dir` from their lines
+    /// (see `read_ignore_files`).
+    ///
+    /// This does nothing unless `WatchOptions::ignore_files` is `true`.
+    /// Malformed lines are skipped.
+    pub fn set_ignore_rules(&mut self, dir: &Path, lines: &[String]) {
+        if !self.ignore_files {
+            return;
+        }
+        let rules = lines
+            .iter()
+            .filter_map(|line| Glob::new(line).ok())
+            .collect::<Vec<_>>();
+        if rules.is_empty() {
+            self.ignore_rules.remove(dir);
+        } else {
+            self.ignore_rules.insert(dir.to_path_buf(), rules);
+        }
+    }
+
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if is_dir && path.file_name().is_some_and(|name| name == ".git") {
+            return true;
+        }
+
+        // Rules in deeper directories and later lines take precedence
+        let mut is_ignored = false;
+        let mut dirs = path.ancestors().skip(1).collect::<Vec<_>>();
+        dirs.reverse();
+        for dir in dirs.into_iter().filter(|d| d.starts_with(&self.root)) {
+            let rules = match self.ignore_rules.get(dir) {
+                None => continue,
+                Some(rules) => rules,
+            };
+            let relative = path.strip_prefix(dir).expect("Never fails");
+            for rule in rules.iter().filter(|r| r.is_match(relative, is_dir)) {
+                is_ignored = !rule.is_negated;
+            }
+        }
+        is_ignored
+    }
+}
+
+/// Reads the lines of the ignore files in `dir` (in the order of `IGNORE_FILE_NAMES`).
+///
+/// Missing or unreadable files are regarded as empty.
+pub fn read_ignore_files(dir: &Path) -> Vec<String> {
+    let mut lines = Vec::new();
+    for name in &IGNORE_FILE_NAMES {
+        if let Ok(text) = fs::read_to_string(dir.join(name)) {
+            lines.extend(text.lines().map(|line| line.to_owned()));
+        }
+    }
+    lines
+}
+
+/// A pattern in the syntax of `.gitignore`.
+#[derive(Debug)]
+struct Glob {
+    regex: Regex,
+    is_negated: bool,
+    is_dir_only: bool,
+}
+impl Glob {
+    fn new(pattern: &str) -> Result<Self> {
+        let mut pattern = pattern.trim_end();
+        track_assert!(
+            !pattern.is_empty() && !pattern.starts_with('#'),
+            ErrorKind::InvalidInput,
+            "empty pattern: {:?}",
+            pattern
+        );
+
+        let is_negated = pattern.starts_with('!');
+        if is_negated {
+            pattern = &pattern[1..];
+        }
+        let is_dir_only = pattern.ends_with('/');
+        let pattern = pattern.trim_end_matches('/');
+        track_assert!(
+            !pattern.is_empty(),
+            ErrorKind::InvalidInput,
+            "empty pattern"
+        );
+
+        let is_anchored = pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+        let mut regex = String::from(if is_anchored { "^" } else { "^(?:.*/)?" });
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        // "**/" matches zero or more directories
+                        chars.next();
+                        regex.push_str("(?:.*/)?");
+                    } else {
+                        regex.push_str(".*");
+                    }
+                }
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                '[' => {
+                    let mut class = String::from("[");
+                    if chars.peek() == Some(&'!') || chars.peek() == Some(&'^') {
+                        chars.next();
+                        class.push('^');
+                    }
+                    let mut is_closed = false;
+                    for c in chars.by_ref() {
+                        if c == ']' {
+                            is_closed = true;
+                            break;
+                        }
+                        if c == '\\' || c == '[' {
+                            class.push('\\');
+                        }
+                        class.push(c);
+                    }
+                    track_assert!(
+                        is_closed,
+                        ErrorKind::InvalidInput,
+                        "unclosed character class: {:?}",
+                        pattern
+                    );
+                    class.push(']');
+                    regex.push_str(&class);
+                }
+                '\\' => {
+                    if let Some(c) = chars.next() {
+                        regex.push_str(&regex::escape(&c.to_string()));
+                    }
+                }
+                _ => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        // A pattern matching a directory also matches the entries under it
+        regex.push_str("(?:/.*)?$");
+
+        let regex = track!(Regex::new(&regex).map_err(Error::from), "pattern={:?}", pattern)?;
+        Ok(Glob {
+            regex,
+            is_negated,
+            is_dir_only,
+        })
+    }
+
+    fn is_match(&self, relative_path: &Path, is_dir: bool) -> bool {
+        if self.is_dir_only && !is_dir {
+            return false;
+        }
+        relative_path
+            .to_str()
+            .is_some_and(|path| self.regex.is_match(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_match(pattern: &str, path: &str, is_dir: bool) -> bool {
+        let glob = Glob::new(pattern).expect("Never fails");
+        glob.is_match(Path::new(path), is_dir)
+    }
+
+    fn filter(options: &WatchOptions) -> PathFilter {
+        PathFilter::new("/root", options).expect("Never fails")
+    }
+
+    #[test]
+    fn glob_anchoring() {
+        // No slashes: matches the names at any depth
+        assert!(is_match("*.log", "a.log", false));
+        assert!(is_match("*.log", "x/y/a.log", false));
+        assert!(!is_match("*.log", "a.log.gz", false));
+        assert!(is_match("tmp", "x/tmp", true));
+
+        // Slashes: anchored to the root directory
+        assert!(is_match("x/*.log", "x/a.log", false));
+        assert!(!is_match("x/*.log", "y/x/a.log", false));
+        assert!(!is_match("x/*.log", "x/y/a.log", false));
+        assert!(is_match("/a.log", "a.log", false));
+        assert!(!is_match("/a.log", "x/a.log", false));
+
+        // The entries under a matched directory
+        assert!(is_match("tmp", "x/tmp/a.log", false));
+        assert!(is_match("/x", "x/y/a.log", false));
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(is_match("a?.log", "ab.log", false));
+        assert!(!is_match("a?.log", "a/.log", false));
+        assert!(is_match("[ab].log", "b.log", false));
+        assert!(!is_match("[!ab].log", "b.log", false));
+        assert!(is_match("\\*.log", "*.log", false));
+        assert!(!is_match("\\*.log", "a.log", false));
+        assert!(Glob::new("[ab.log").is_err());
+        assert!(Glob::new("# comment").is_err());
+        assert!(Glob::new("/").is_err());
+    }
+
+    #[test]
+    fn glob_double_asterisks() {
+        assert!(is_match("**/a.log", "a.log", false));
+        assert!(is_match("**/a.log", "x/y/a.log", false));
+        assert!(is_match("x/**/a.log", "x/a.log", false));
+        assert!(is_match("x/**/a.log", "x/y/z/a.log", false));
+        assert!(!is_match("x/**/a.log", "y/a.log", false));
+        assert!(is_match("x/**", "x/y/a.log", false));
+        assert!(is_match("x/a**", "x/ab/c.log", false));
+    }
+
+    #[test]
+    fn glob_dir_only() {
+        assert!(is_match("tmp/", "x/tmp", true));
+        assert!(!is_match("tmp/", "x/tmp", false));
+        assert!(!is_match("x/tmp/", "x/tmp", false));
+    }
+
+    #[test]
+    fn glob_negation() {
+        let glob = Glob::new("!keep.log").expect("Never fails");
+        assert!(glob.is_negated);
+        assert!(glob.is_match(Path::new("x/keep.log"), false));
+
+        let options = WatchOptions {
+            exclude: vec!["!keep.log".to_owned()],
+            ..WatchOptions::default()
+        };
+        assert!(PathFilter::new("/root", &options).is_err());
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let options = WatchOptions {
+            include: vec!["*.log".to_owned()],
+            exclude: vec!["tmp/".to_owned(), "/old.log".to_owned()],
+            ..WatchOptions::default()
+        };
+        let filter = filter(&options);
+        assert!(!filter.is_filtered(Path::new("/root/a.log"), false));
+        assert!(filter.is_filtered(Path::new("/root/a.txt"), false));
+        assert!(!filter.is_filtered(Path::new("/root/x"), true));
+        assert!(filter.is_filtered(Path::new("/root/x/tmp"), true));
+        assert!(filter.is_filtered(Path::new("/root/old.log"), false));
+        assert!(!filter.is_filtered(Path::new("/root/x/old.log"), false));
+        assert!(filter.is_filtered(Path::new("/other/a.log"), false));
+    }
+
+    #[test]
+    fn ignore_rules() {
+        let options = WatchOptions {
+            ignore_files: true,
+            ..WatchOptions::default()
+        };
+        let mut filter = filter(&options);
+        let lines = |lines: &[&str]| lines.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+        filter.set_ignore_rules(Path::new("/root"), &lines(&["*.log", "!keep.log", "# x"]));
+        filter.set_ignore_rules(Path::new("/root/x"), &lines(&["!a.log", "/keep.log"]));
+
+        assert!(filter.is_filtered(Path::new("/root/a.log"), false));
+        assert!(!filter.is_filtered(Path::new("/root/keep.log"), false));
+        assert!(!filter.is_filtered(Path::new("/root/a.txt"), false));
+        assert!(filter.is_filtered(Path::new("/root/.git"), true));
+
+        // Rules in deeper directories take precedence
+        assert!(!filter.is_filtered(Path::new("/root/x/a.log"), false));
+        assert!(filter.is_filtered(Path::new("/root/x/keep.log"), false));
+        assert!(!filter.is_filtered(Path::new("/root/x/y/keep.log"), false));
+
+        filter.set_ignore_rules(Path::new("/root"), &[]);
+        assert!(!filter.is_filtered(Path::new("/root/a.log"), false));
+        assert!(filter.is_ignore_file(Path::new("/root/x/.gitignore")));
+        assert!(!filter.is_ignore_file(Path::new("/root/x/gitignore")));
+    }
+}
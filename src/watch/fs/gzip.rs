@@ -0,0 +1,316 @@
+use fibers::sync::mpsc;
+use fibers_tasque::{AsyncCall, DefaultIoTaskQueue, TaskQueueExt};
+use flate2::read::MultiGzDecoder;
+use futures::{Async, Future, Poll, Stream};
+use std;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use trackable::error::ErrorKindExt;
+
+use watch::fs::{FileContent, FileUpdated};
+use {Error, ErrorKind, Result};
+
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// A decompressing reader of a gzip file.
+pub type GzipReader = MultiGzDecoder<File>;
+
+/// A watcher of a gzip compressed file.
+///
+/// This yields the decompressed contents, and their offsets are in the decompressed space.
+/// Files consisting of multiple gzip members (e.g., concatenated by `cat`) are supported.
+///
+/// If a file is truncated or corrupted, it is read up to the broken part,
+/// which is reported by `FileContent::error`.
+/// When the file is modified after it has been read to the end (or to the broken part),
+/// it is decompressed again from the beginning and the contents beyond the last offset
+/// are yielded, because compressed streams can not be resumed at their ends.
+/// If the decompressed content has become shorter, the file is regarded as replaced
+/// and read again from the beginning (see `FileContent::reset`).
+#[derive(Debug)]
+pub struct GzipFileWatcher {
+    path: PathBuf,
+
+    // `None` means the one-shot mode
+    event_rx: Option<mpsc::Receiver<FileUpdated>>,
+
+    offset: u64,
+    reader: Option<GzipReader>,
+    read: Option<AsyncCall<Result<(GzipReader, FileContent)>>>,
+    is_eof: bool,
+    is_modified: bool,
+    is_broken: bool,
+}
+impl GzipFileWatcher {
+    pub fn new<P: AsRef<Path>>(path: P, event_rx: mpsc::Receiver<FileUpdated>) -> Self {
+        Self::with_event_rx(path, Some(event_rx))
+    }
+
+    /// Makes a `GzipFileWatcher` which terminates after reading the file to the end.
+    pub fn oneshot<P: AsRef<Path>>(path: P) -> Self {
+        Self::with_event_rx(path, None)
+    }
+    fn with_event_rx<P: AsRef<Path>>(
+        path: P,
+        event_rx: Option<mpsc::Receiver<FileUpdated>>,
+    ) -> Self {
+        GzipFileWatcher {
+            path: path.as_ref().to_path_buf(),
+            event_rx,
+            offset: 0,
+            reader: None,
+            read: None,
+            is_eof: false,
+            is_modified: false,
+            is_broken: false,
+        }
+    }
+    pub fn path(&self) -> &Path {
+        self.path.as_ref()
+    }
+    fn start_read(&mut self) {
+        let path = self.path.clone();
+        let offset = self.offset;
+        let reader = self.reader.take();
+        self.read = Some(DefaultIoTaskQueue.async_call(move || {
+            let (mut reader, offset, reset) = match reader {
+                Some(reader) => (reader, offset, false),
+                None => track!(reopen(&path, offset))?,
+            };
+            let mut buf = vec![0; READ_BUFFER_SIZE];
+            let (read_size, error) = read_full(&mut reader, &mut buf);
+            let eof = read_size < buf.len() || error.is_some();
+            buf.truncate(read_size);
+            let error = error.map(|e| {
+                let e = ErrorKind::InvalidInput.cause(format!("broken gzip stream: {}", e));
+                track!(Error::from(e), "path={:?}", path)
+            });
+            let content = FileContent {
+                path,
+                offset,
+                data: buf,
+                eof,
+                reset,
+                error,
+            };
+            Ok((reader, content))
+        }));
+    }
+}
+impl Stream for GzipFileWatcher {
+    type Item = FileContent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(ref mut event_rx) = self.event_rx {
+            while let Async::Ready(event) = event_rx.poll().expect("Never fails") {
+                match event {
+                    None => return Ok(Async::Ready(None)),
+                    Some(FileUpdated::Modified) => self.is_modified = true,
+                    Some(FileUpdated::Renamed(path)) => self.path = path,
+                }
+            }
+        }
+        loop {
+            if self.is_eof {
+                if !self.is_modified {
+                    break;
+                }
+                self.is_eof = false;
+                self.is_modified = false;
+                self.reader = None;
+            }
+            if self.read.is_none() {
+                self.start_read();
+            }
+            if let Async::Ready(Some(result)) = track!(self.read.poll().map_err(Error::from))? {
+                self.read = None;
+                let (reader, content) = track!(result)?;
+                self.reader = Some(reader);
+                self.offset = content.offset + content.data.len() as u64;
+                self.is_eof = content.eof;
+
+                // A content without an error is yielded to tell that the broken part is readable
+                let was_broken = std::mem::replace(&mut self.is_broken, content.error.is_some());
+                if !content.data.is_empty() || content.reset || was_broken || self.is_broken {
+                    return Ok(Async::Ready(Some(content)));
+                }
+            } else {
+                return Ok(Async::NotReady);
+            }
+        }
+        if self.event_rx.is_none() {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Opens a gzip file for reading its decompressed content.
+pub fn open<P: AsRef<Path>>(path: P) -> Result<GzipReader> {
+    let file = track!(File::open(&path).map_err(Error::from))?;
+    Ok(MultiGzDecoder::new(file))
+}
+
+/// Opens a gzip file, and skips the first `offset` bytes of its decompressed content.
+///
+/// If the content is shorter than `offset`, the file is regarded as replaced,
+/// and the returned reader is at the beginning (with the offset `0` and the reset flag).
+fn reopen(path: &Path, offset: u64) -> Result<(GzipReader, u64, bool)> {
+    let mut reader = track!(open(path))?;
+    if skip(&mut reader, offset) == offset {
+        Ok((reader, offset, false))
+    } else {
+        let reader = track!(open(path))?;
+        Ok((reader, 0, true))
+    }
+}
+
+/// Returns `true` if `path` is regarded as a gzip file.
+pub fn is_gzip_path<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().extension().is_some_and(|e| e == "gz")
+}
+
+/// Reads bytes until `buf` is filled or the end of the stream is reached.
+///
+/// Returns the number of the bytes read, and the error which stopped the reading if any
+/// (e.g., a truncated stream or a CRC mismatch).
+pub(super) fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> (usize, Option<io::Error>) {
+    let mut read_size = 0;
+    while read_size < buf.len() {
+        match reader.read(&mut buf[read_size..]) {
+            Ok(0) => break,
+            Ok(n) => read_size += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return (read_size, Some(e)),
+        }
+    }
+    (read_size, None)
+}
+
+/// Reads and discards up to `size` bytes, and returns the number of the discarded bytes.
+///
+/// This stops at the end of the stream or at a broken part of it.
+pub(super) fn skip<R: Read>(reader: &mut R, size: u64) -> u64 {
+    let mut buf = vec![0; READ_BUFFER_SIZE];
+    let mut skipped = 0;
+    while skipped < size {
+        let n = (size - skipped).min(buf.len() as u64) as usize;
+        let (read_size, error) = read_full(reader, &mut buf[..n]);
+        skipped += read_size as u64;
+        if read_size < n || error.is_some() {
+            break;
+        }
+    }
+    skipped
+}
+
+#[cfg(test)]
+mod tests {
+    use fibers::{Executor, InPlaceExecutor, Spawn};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs;
+    use std::io::Write;
+    use std::process;
+
+    use super::*;
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).expect("Never fails");
+        encoder.finish().expect("Never fails")
+    }
+
+    fn lines(first: usize, last: usize) -> Vec<u8> {
+        (first..=last)
+            .map(|i| format!("line {}\n", i))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("dg-gzip-test-{}-{}.gz", name, process::id()))
+    }
+
+    fn next(
+        executor: &mut InPlaceExecutor,
+        watcher: GzipFileWatcher,
+    ) -> (Option<FileContent>, GzipFileWatcher) {
+        let monitor = executor.spawn_monitor(watcher.into_future().map_err(|(e, _)| e));
+        let result = executor.run_fiber(monitor).expect("Never fails");
+        result.expect("Never fails")
+    }
+
+    #[test]
+    fn multiple_members() {
+        let path = temp_path("members");
+        let mut data = compress(&lines(1, 100));
+        data.extend(compress(&lines(101, 200)));
+        fs::write(&path, &data).expect("Never fails");
+
+        let mut executor = InPlaceExecutor::new().expect("Never fails");
+        let (content, watcher) = next(&mut executor, GzipFileWatcher::oneshot(&path));
+        let (end, _) = next(&mut executor, watcher);
+        let _ = fs::remove_file(&path);
+
+        let content = content.expect("Never fails");
+        assert_eq!(content.data, lines(1, 200));
+        assert!(content.eof);
+        assert!(content.error.is_none());
+        assert!(end.is_none());
+    }
+
+    #[test]
+    fn partial_stream_is_read_again_when_modified() {
+        let path = temp_path("partial");
+        let data = lines(1, 10_000);
+        let compressed = compress(&data);
+        fs::write(&path, &compressed[..compressed.len() / 2]).expect("Never fails");
+
+        let mut executor = InPlaceExecutor::new().expect("Never fails");
+        let (tx, rx) = mpsc::channel();
+        let watcher = GzipFileWatcher::new(&path, rx);
+
+        // The first half of the stream
+        let (first, watcher) = next(&mut executor, watcher);
+        let first = first.expect("Never fails");
+        assert_eq!(first.offset, 0);
+        assert!(first.eof);
+        assert!(first.error.is_some());
+        assert!(!first.data.is_empty());
+        assert!(data.starts_with(&first.data));
+
+        // The rest of the stream
+        fs::write(&path, &compressed).expect("Never fails");
+        tx.send(FileUpdated::Modified).expect("Never fails");
+        let (rest, watcher) = next(&mut executor, watcher);
+        let rest = rest.expect("Never fails");
+        assert_eq!(rest.offset, first.data.len() as u64);
+        assert_eq!(rest.data, &data[first.data.len()..]);
+        assert!(rest.eof);
+        assert!(!rest.reset);
+        assert!(rest.error.is_none());
+
+        // An appended member
+        let mut appended = compressed.clone();
+        appended.extend(compress(b"appended\n"));
+        fs::write(&path, &appended).expect("Never fails");
+        tx.send(FileUpdated::Modified).expect("Never fails");
+        let (content, watcher) = next(&mut executor, watcher);
+        let content = content.expect("Never fails");
+        assert_eq!(content.offset, data.len() as u64);
+        assert_eq!(content.data, b"appended\n");
+
+        // A replacement with a shorter content
+        fs::write(&path, compress(b"replaced\n")).expect("Never fails");
+        tx.send(FileUpdated::Modified).expect("Never fails");
+        let (content, _) = next(&mut executor, watcher);
+        let _ = fs::remove_file(&path);
+        let content = content.expect("Never fails");
+        assert_eq!(content.offset, 0);
+        assert_eq!(content.data, b"replaced\n");
+        assert!(content.reset);
+    }
+}
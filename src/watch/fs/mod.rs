@@ -1,7 +1,15 @@
-pub use self::directory::{DirectoryEvent, DirectoryWatcher};
-pub use self::file::{FileContent, FileUpdated, FileWatcher, PlainFileWatcher};
-pub use self::file_system::FileSystemWatcher;
+pub use self::directory::{DirectoryEvent, DirectoryWatcher, ListedEntry};
+pub use self::file::{BinaryFileWatcher, FileContent, FileUpdated, FileWatcher, PlainFileWatcher};
+pub use self::file_system::{FileSystemEvent, FileSystemWatcher};
+pub use self::file_type::FileType;
+pub use self::filter::WatchOptions;
+pub use self::gzip::GzipFileWatcher;
+pub use self::tar_gzip::TarGzipFileWatcher;
 
 mod directory;
 mod file;
 mod file_system;
+mod file_type;
+mod filter;
+pub mod gzip;
+pub mod tar_gzip;
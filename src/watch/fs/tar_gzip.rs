@@ -0,0 +1,754 @@
+use fibers::sync::mpsc;
+use fibers_tasque::{AsyncCall, DefaultIoTaskQueue, TaskQueueExt};
+use futures::{Async, Future, Poll, Stream};
+use std;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str;
+use trackable::error::ErrorKindExt;
+
+use watch::fs::gzip::{self, GzipReader};
+use watch::fs::{FileContent, FileUpdated};
+use {Error, ErrorKind, Result};
+
+const READ_BUFFER_SIZE: usize = 1024 * 1024;
+const BLOCK_SIZE: u64 = 512;
+
+/// The maximum size of the extended headers (GNU long names and pax headers) read into memory.
+const MAX_EXTENDED_HEADER_SIZE: u64 = 1024 * 1024;
+
+/// The separator between the path of an archive and the path of a member in a virtual path.
+pub const MEMBER_SEPARATOR: &str = "!/";
+
+type ReadResult = Result<(TarReader<GzipReader>, Option<FileContent>, bool)>;
+
+/// A watcher of a gzip compressed tar archive.
+///
+/// Each regular file in the archive is yielded as a separate file,
+/// whose path is a virtual path made by `member_path` (e.g., `bundle.tar.gz!/var/log/app.log`).
+///
+/// If an archive is broken, the members are read up to the broken part,
+/// which is reported by `FileContent::error` of the member being read
+/// (or the archive itself if it is broken between members).
+/// Like `GzipFileWatcher`, an archive modified after it has been read to the end
+/// (or to the broken part) is decompressed again from the beginning,
+/// and the members and their contents which have already been yielded are skipped.
+/// If the archive has become shorter, it is regarded as replaced, and the first content
+/// of every member read again is marked as `FileContent::reset`.
+#[derive(Debug)]
+pub struct TarGzipFileWatcher {
+    path: PathBuf,
+
+    // `None` means the one-shot mode
+    event_rx: Option<mpsc::Receiver<FileUpdated>>,
+
+    reader: Option<TarReader<GzipReader>>,
+    read: Option<AsyncCall<ReadResult>>,
+    is_eof: bool,
+    is_modified: bool,
+
+    // The number of the members read to the end, and the offset in the current member
+    members_done: usize,
+    member_offset: u64,
+
+    // The path of the last content if it has reported an error
+    broken_path: Option<PathBuf>,
+
+    pending: Option<FileContent>,
+    is_reset: bool,
+}
+impl TarGzipFileWatcher {
+    pub fn new<P: AsRef<Path>>(path: P, event_rx: mpsc::Receiver<FileUpdated>) -> Self {
+        Self::with_event_rx(path, Some(event_rx))
+    }
+
+    /// Makes a `TarGzipFileWatcher` which terminates after reading the archive to the end.
+    pub fn oneshot<P: AsRef<Path>>(path: P) -> Self {
+        Self::with_event_rx(path, None)
+    }
+    fn with_event_rx<P: AsRef<Path>>(
+        path: P,
+        event_rx: Option<mpsc::Receiver<FileUpdated>>,
+    ) -> Self {
+        TarGzipFileWatcher {
+            path: path.as_ref().to_path_buf(),
+            event_rx,
+            reader: None,
+            read: None,
+            is_eof: false,
+            is_modified: false,
+            members_done: 0,
+            member_offset: 0,
+            broken_path: None,
+            pending: None,
+            is_reset: false,
+        }
+    }
+    pub fn path(&self) -> &Path {
+        self.path.as_ref()
+    }
+    fn start_read(&mut self) {
+        let path = self.path.clone();
+        let reader = self.reader.take();
+        let (members_done, member_offset) = (self.members_done, self.member_offset);
+        self.read = Some(DefaultIoTaskQueue.async_call(move || {
+            let (mut reader, is_reset) = match reader {
+                Some(reader) => (reader, false),
+                None => track!(reopen(&path, members_done, member_offset))?,
+            };
+            let content = match read_content(&path, &mut reader) {
+                Ok(content) => content,
+                Err(e) => Some(broken_content(&path, &reader, track!(e, "path={:?}", path))),
+            };
+            Ok((reader, content, is_reset))
+        }));
+    }
+    fn handle_content(&mut self, mut content: FileContent) -> Option<FileContent> {
+        if self.is_reset && content.offset == 0 {
+            content.reset = true;
+        }
+        if content.error.is_some() {
+            self.is_eof = true;
+        }
+        if content.path != self.path {
+            if content.eof && content.error.is_none() {
+                self.members_done += 1;
+                self.member_offset = 0;
+            } else {
+                self.member_offset = content.offset + content.data.len() as u64;
+            }
+        }
+
+        // The error of the previous content is cleared by the next content of the same path
+        let broken_path = if content.error.is_some() {
+            self.broken_path.replace(content.path.clone())
+        } else {
+            self.broken_path.take()
+        };
+        match broken_path {
+            Some(path) if path != content.path => {
+                self.pending = Some(content);
+                Some(cleared_content(path))
+            }
+            _ => Some(content),
+        }
+    }
+}
+impl Stream for TarGzipFileWatcher {
+    type Item = FileContent;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(ref mut event_rx) = self.event_rx {
+            while let Async::Ready(event) = event_rx.poll().expect("Never fails") {
+                match event {
+                    None => return Ok(Async::Ready(None)),
+                    Some(FileUpdated::Modified) => self.is_modified = true,
+                    Some(FileUpdated::Renamed(path)) => self.path = path,
+                }
+            }
+        }
+        loop {
+            if let Some(content) = self.pending.take() {
+                return Ok(Async::Ready(Some(content)));
+            }
+            if self.is_eof {
+                if !self.is_modified {
+                    break;
+                }
+                self.is_eof = false;
+                self.is_modified = false;
+                self.reader = None;
+            }
+            if self.read.is_none() {
+                self.start_read();
+            }
+            if let Async::Ready(Some(result)) = track!(self.read.poll().map_err(Error::from))? {
+                self.read = None;
+                let (reader, content, is_reset) = track!(result)?;
+                self.reader = Some(reader);
+                if is_reset {
+                    self.is_reset = true;
+                    self.members_done = 0;
+                    self.member_offset = 0;
+                }
+                match content {
+                    None => {
+                        self.is_eof = true;
+                        self.is_reset = false;
+                        if let Some(path) = self.broken_path.take() {
+                            return Ok(Async::Ready(Some(cleared_content(path))));
+                        }
+                    }
+                    Some(content) => return Ok(Async::Ready(self.handle_content(content))),
+                }
+            } else {
+                return Ok(Async::NotReady);
+            }
+        }
+        if self.event_rx.is_none() {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Opens an archive, and skips the first `members` members
+/// and the first `offset` bytes of the next member.
+///
+/// If the archive does not have them, it is regarded as replaced,
+/// and the returned reader is at the beginning (with the reset flag).
+fn reopen(path: &Path, members: usize, offset: u64) -> Result<(TarReader<GzipReader>, bool)> {
+    let mut reader = TarReader::new(track!(gzip::open(path))?);
+    let mut is_skipped = true;
+    for _ in 0..members + usize::from(offset > 0) {
+        if !matches!(reader.next_member(), Ok(Some(_))) {
+            is_skipped = false;
+            break;
+        }
+    }
+    if offset == 0 {
+        // The rest of the last member is skipped by the next `next_member`
+        reader.member = None;
+    }
+    if is_skipped && gzip::skip(&mut reader, offset) == offset {
+        Ok((reader, false))
+    } else {
+        let reader = TarReader::new(track!(gzip::open(path))?);
+        Ok((reader, true))
+    }
+}
+
+/// Reads the next chunk of the members of the archive.
+///
+/// Returns `None` at the end of the archive.
+fn read_content<R: Read>(archive: &Path, reader: &mut TarReader<R>) -> Result<Option<FileContent>> {
+    loop {
+        if reader.member.is_none() && track!(reader.next_member())?.is_none() {
+            return Ok(None);
+        }
+        let (path, offset) = {
+            let member = reader.member.as_ref().expect("Never fails");
+            (member_path(archive, &member.path), member.size - reader.remaining)
+        };
+
+        let size = std::cmp::min(reader.remaining, READ_BUFFER_SIZE as u64) as usize;
+        let mut buf = vec![0; size];
+        let (read_size, error) = gzip::read_full(reader, &mut buf);
+        buf.truncate(read_size);
+        let error = error.map(|e| {
+            let e = ErrorKind::InvalidInput.cause(format!("broken archive: {}", e));
+            track!(Error::from(e), "path={:?}", archive)
+        });
+        let eof = reader.remaining == 0 || error.is_some();
+        if eof && error.is_none() {
+            reader.member = None;
+        }
+        if !buf.is_empty() || offset == 0 || error.is_some() {
+            let content = FileContent {
+                path,
+                offset,
+                data: buf,
+                eof,
+                reset: false,
+                error,
+            };
+            return Ok(Some(content));
+        }
+    }
+}
+
+/// Makes the last content of a broken archive, which reports `error`.
+fn broken_content<R>(archive: &Path, reader: &TarReader<R>, error: Error) -> FileContent {
+    let (path, offset) = match reader.member {
+        Some(ref member) => (
+            member_path(archive, &member.path),
+            member.size - reader.remaining,
+        ),
+        None => (archive.to_path_buf(), 0),
+    };
+    FileContent {
+        path,
+        offset,
+        data: Vec::new(),
+        eof: true,
+        reset: false,
+        error: Some(error),
+    }
+}
+
+/// Makes an empty content which tells that the error reported for `path` has been resolved.
+fn cleared_content(path: PathBuf) -> FileContent {
+    FileContent {
+        path,
+        offset: 0,
+        data: Vec::new(),
+        eof: true,
+        reset: false,
+        error: None,
+    }
+}
+
+/// Returns the virtual path of `member` in `archive`.
+pub fn member_path<P: AsRef<Path>, Q: AsRef<Path>>(archive: P, member: Q) -> PathBuf {
+    let member = member.as_ref().to_string_lossy();
+    let member = member.trim_start_matches("./").trim_start_matches('/');
+    PathBuf::from(format!(
+        "{}{}{}",
+        archive.as_ref().display(),
+        MEMBER_SEPARATOR,
+        member
+    ))
+}
+
+/// Splits a virtual path made by `member_path` into the paths of the archive and the member.
+///
+/// The path is split at the first separator preceded by the path of an existing regular file.
+/// Returns `None` if `path` is not a virtual path.
+pub fn split_member_path<P: AsRef<Path>>(path: P) -> Option<(PathBuf, PathBuf)> {
+    let path = path.as_ref().to_str()?;
+    path.match_indices(MEMBER_SEPARATOR)
+        .map(|(i, _)| (&path[..i], &path[i + MEMBER_SEPARATOR.len()..]))
+        .find(|&(archive, _)| Path::new(archive).is_file())
+        .map(|(archive, member)| (PathBuf::from(archive), PathBuf::from(member)))
+}
+
+/// Returns `true` if `path` is a virtual path of a member of `archive`.
+pub fn is_member_path<P: AsRef<Path>, Q: AsRef<Path>>(path: P, archive: Q) -> bool {
+    let prefix = format!("{}{}", archive.as_ref().display(), MEMBER_SEPARATOR);
+    path.as_ref()
+        .to_str()
+        .is_some_and(|path| path.starts_with(&prefix))
+}
+
+/// Returns `true` if `path` is regarded as a gzip compressed tar archive.
+pub fn is_tar_gzip_path<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(".tar.gz") || name.ends_with(".tgz"))
+}
+
+/// A regular file in a tar archive.
+#[derive(Debug, Clone)]
+pub struct TarMember {
+    /// The path of the file in the archive.
+    pub path: PathBuf,
+
+    /// The position of the content of the file in the archive.
+    pub position: u64,
+
+    /// The size of the file.
+    pub size: u64,
+}
+
+/// A sequential reader of the regular files in a tar archive.
+///
+/// The ustar format, and the long names of GNU and pax extensions are supported.
+#[derive(Debug)]
+pub struct TarReader<R> {
+    reader: R,
+    position: u64,
+    member: Option<TarMember>,
+
+    // The number of the bytes of the current entry which have not been read yet
+    remaining: u64,
+}
+impl<R: Read> TarReader<R> {
+    pub fn new(reader: R) -> Self {
+        TarReader {
+            reader,
+            position: 0,
+            member: None,
+            remaining: 0,
+        }
+    }
+
+    /// Moves to the next regular file, and returns it.
+    ///
+    /// The rest of the current file is skipped.
+    /// Returns `None` at the end of the archive.
+    pub fn next_member(&mut self) -> Result<Option<TarMember>> {
+        self.member = None;
+        let mut long_path = None;
+        loop {
+            track!(self.skip_entry())?;
+            let mut header = [0; BLOCK_SIZE as usize];
+            match self.reader.read_exact(&mut header) {
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof && self.position == 0 => {
+                    // An empty archive
+                    return Ok(None);
+                }
+                result => track!(result.map_err(Error::from))?,
+            }
+            self.position += BLOCK_SIZE;
+            if header.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+            track!(check_header(&header))?;
+
+            let size = track!(parse_number(&header[124..136]))?;
+            self.remaining = size;
+            match header[156] {
+                b'0' | b'\0' | b'7' => {
+                    let path = match long_path.take() {
+                        Some(path) => path,
+                        None => header_path(&header),
+                    };
+                    let member = TarMember {
+                        path,
+                        position: self.position,
+                        size,
+                    };
+                    self.member = Some(member.clone());
+                    return Ok(Some(member));
+                }
+                b'L' => {
+                    let data = track!(self.read_entry())?;
+                    let name = data.split(|&b| b == 0).next().unwrap_or(&[]);
+                    long_path = Some(PathBuf::from(String::from_utf8_lossy(name).into_owned()));
+                }
+                b'x' => {
+                    let data = track!(self.read_entry())?;
+                    if let Some(path) = pax_path(&data) {
+                        long_path = Some(path);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Returns the underlying reader, which is positioned at the current position of the archive.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    fn read_entry(&mut self) -> Result<Vec<u8>> {
+        track_assert!(
+            self.remaining <= MAX_EXTENDED_HEADER_SIZE,
+            ErrorKind::InvalidInput,
+            "too large extended header: {} bytes",
+            self.remaining
+        );
+        let mut data = vec![0; self.remaining as usize];
+        track!(self.read_exact(&mut data).map_err(Error::from))?;
+        Ok(data)
+    }
+    fn skip_entry(&mut self) -> Result<()> {
+        // Entries are padded to the block boundary
+        let end = self.position + self.remaining;
+        let size = self.remaining + (BLOCK_SIZE - end % BLOCK_SIZE) % BLOCK_SIZE;
+        let skipped = track!(
+            io::copy(&mut (&mut self.reader).take(size), &mut io::sink()).map_err(Error::from)
+        )?;
+        self.position += skipped;
+        self.remaining = 0;
+        track_assert_eq!(skipped, size, ErrorKind::InvalidInput, "truncated archive");
+        Ok(())
+    }
+}
+impl<R: Read> Read for TarReader<R> {
+    /// Reads the content of the current entry.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = std::cmp::min(buf.len() as u64, self.remaining) as usize;
+        let read_size = self.reader.read(&mut buf[..size])?;
+        if read_size == 0 && size != 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.position += read_size as u64;
+        self.remaining -= read_size as u64;
+        Ok(read_size)
+    }
+}
+
+fn check_header(header: &[u8]) -> Result<()> {
+    let expected = track!(parse_number(&header[148..156]))?;
+    let sum = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { u64::from(b' ') } else { u64::from(b) })
+        .sum::<u64>();
+    track_assert_eq!(sum, expected, ErrorKind::InvalidInput, "broken tar header");
+    Ok(())
+}
+
+fn header_path(header: &[u8]) -> PathBuf {
+    let name = cstr(&header[0..100]);
+    let path = if &header[257..262] == b"ustar" && header[345] != 0 {
+        format!(
+            "{}/{}",
+            String::from_utf8_lossy(cstr(&header[345..500])),
+            String::from_utf8_lossy(name)
+        )
+    } else {
+        String::from_utf8_lossy(name).into_owned()
+    };
+    PathBuf::from(path)
+}
+
+/// Returns the `path` record of pax extended headers.
+fn pax_path(data: &[u8]) -> Option<PathBuf> {
+    let mut data = data;
+    while !data.is_empty() {
+        // Each record is of the form "LENGTH KEY=VALUE\n"
+        let space = data.iter().position(|&b| b == b' ')?;
+        let len = str::from_utf8(&data[..space]).ok()?.parse::<usize>().ok()?;
+        if len <= space || len > data.len() {
+            return None;
+        }
+        let record = &data[space + 1..len];
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(value) = record.strip_prefix(b"path=") {
+            return Some(PathBuf::from(String::from_utf8_lossy(value).into_owned()));
+        }
+        data = &data[len..];
+    }
+    None
+}
+
+/// Parses a numeric field (octal, or base-256 if the highest bit is set).
+fn parse_number(field: &[u8]) -> Result<u64> {
+    if field[0] & 0x80 != 0 {
+        let n = field[1..]
+            .iter()
+            .fold(u64::from(field[0] & 0x7f), |n, &b| (n << 8) | u64::from(b));
+        return Ok(n);
+    }
+    let s = track!(str::from_utf8(cstr(field)).map_err(Error::from))?;
+    let s = s.trim_matches(|c| c == ' ' || c == '\0');
+    if s.is_empty() {
+        return Ok(0);
+    }
+    let n = track!(u64::from_str_radix(s, 8).map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+    Ok(n)
+}
+
+fn cstr(field: &[u8]) -> &[u8] {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    &field[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use fibers::{Executor, InPlaceExecutor, Spawn};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs;
+    use std::io::Write;
+    use std::process;
+
+    use super::*;
+
+    fn header(name: &str, type_flag: u8, size: u64) -> Vec<u8> {
+        let mut header = vec![0; BLOCK_SIZE as usize];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+        header[156] = type_flag;
+        header[257..265].copy_from_slice(b"ustar\x0000");
+        set_checksum(&mut header);
+        header
+    }
+
+    fn set_checksum(header: &mut [u8]) {
+        let sum = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| if (148..156).contains(&i) { u64::from(b' ') } else { u64::from(b) })
+            .sum::<u64>();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    }
+
+    fn entry(archive: &mut Vec<u8>, name: &str, type_flag: u8, data: &[u8]) {
+        archive.extend(header(name, type_flag, data.len() as u64));
+        archive.extend_from_slice(data);
+        let padding = (BLOCK_SIZE - data.len() as u64 % BLOCK_SIZE) % BLOCK_SIZE;
+        archive.extend(vec![0; padding as usize]);
+    }
+
+    fn finish(archive: &mut Vec<u8>) {
+        archive.extend(vec![0; BLOCK_SIZE as usize * 2]);
+    }
+
+    fn read_members(archive: &[u8]) -> Result<Vec<(PathBuf, Vec<u8>)>> {
+        let mut reader = TarReader::new(archive);
+        let mut members = Vec::new();
+        while let Some(member) = track!(reader.next_member())? {
+            let mut data = Vec::new();
+            track!(reader.read_to_end(&mut data).map_err(Error::from))?;
+            members.push((member.path, data));
+        }
+        Ok(members)
+    }
+
+    #[test]
+    fn regular_files() {
+        let mut archive = Vec::new();
+        entry(&mut archive, "dir/", b'5', b"");
+        entry(&mut archive, "dir/a.log", b'0', b"foo\n");
+        entry(&mut archive, "link", b'2', b"");
+        entry(&mut archive, "dir/b.log", b'0', &vec![b'x'; 1000]);
+        finish(&mut archive);
+
+        let members = read_members(&archive).expect("Never fails");
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0], (PathBuf::from("dir/a.log"), b"foo\n".to_vec()));
+        assert_eq!(members[1], (PathBuf::from("dir/b.log"), vec![b'x'; 1000]));
+
+        assert!(read_members(&[]).expect("Never fails").is_empty());
+    }
+
+    #[test]
+    fn gnu_long_name() {
+        let long_name = format!("{}/app.log", "d".repeat(150));
+        let mut archive = Vec::new();
+        entry(
+            &mut archive,
+            "././@LongLink",
+            b'L',
+            format!("{}\0", long_name).as_bytes(),
+        );
+        entry(&mut archive, &long_name[..100], b'0', b"foo\n");
+        entry(&mut archive, "short.log", b'0', b"bar\n");
+        finish(&mut archive);
+
+        let members = read_members(&archive).expect("Never fails");
+        assert_eq!(members[0], (PathBuf::from(long_name), b"foo\n".to_vec()));
+        assert_eq!(members[1], (PathBuf::from("short.log"), b"bar\n".to_vec()));
+    }
+
+    #[test]
+    fn pax_path() {
+        let long_name = format!("{}/app.log", "d".repeat(150));
+        let records = format!(
+            "20 mtime=1234567890\n{} path={}\n",
+            long_name.len() + 10,
+            long_name
+        );
+        let mut archive = Vec::new();
+        entry(&mut archive, "PaxHeaders/app.log", b'x', records.as_bytes());
+        entry(&mut archive, "app.log", b'0', b"foo\n");
+        finish(&mut archive);
+
+        let members = read_members(&archive).expect("Never fails");
+        assert_eq!(members, vec![(PathBuf::from(long_name), b"foo\n".to_vec())]);
+    }
+
+    #[test]
+    fn truncated_archive() {
+        let mut archive = Vec::new();
+        entry(&mut archive, "a.log", b'0', b"foo\n");
+        entry(&mut archive, "b.log", b'0', &vec![b'x'; 1000]);
+        finish(&mut archive);
+
+        // In the content of a member
+        let mut reader = TarReader::new(&archive[..BLOCK_SIZE as usize * 3 + 100]);
+        assert!(reader.next_member().expect("Never fails").is_some());
+        assert!(reader.next_member().expect("Never fails").is_some());
+        let mut data = Vec::new();
+        let e = reader.read_to_end(&mut data).expect_err("Never succeeds");
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(data, vec![b'x'; 100]);
+
+        // In the padding of a member
+        let mut reader = TarReader::new(&archive[..BLOCK_SIZE as usize + 100]);
+        assert!(reader.next_member().expect("Never fails").is_some());
+        assert!(reader.next_member().is_err());
+
+        // In a header
+        assert!(read_members(&archive[..BLOCK_SIZE as usize * 2 + 100]).is_err());
+    }
+
+    #[test]
+    fn too_large_extended_header() {
+        let mut archive = header("PaxHeaders/app.log", b'x', 0);
+        archive[124..136].copy_from_slice(&[0x80, 0, 0, 0, 0x10, 0, 0, 0, 0, 0, 0, 0]);
+        set_checksum(&mut archive);
+
+        let e = TarReader::new(&archive[..])
+            .next_member()
+            .expect_err("Never succeeds");
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+    }
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).expect("Never fails");
+        encoder.finish().expect("Never fails")
+    }
+
+    fn next(
+        executor: &mut InPlaceExecutor,
+        watcher: TarGzipFileWatcher,
+    ) -> (FileContent, TarGzipFileWatcher) {
+        let monitor = executor.spawn_monitor(watcher.into_future().map_err(|(e, _)| e));
+        let result = executor.run_fiber(monitor).expect("Never fails");
+        let (content, watcher) = result.expect("Never fails");
+        (content.expect("Never fails"), watcher)
+    }
+
+    #[test]
+    fn modified_archives_are_read_again() {
+        let path = std::env::temp_dir().join(format!("dg-tar-test-{}.tar.gz", process::id()));
+        let lines = (1..=10_000)
+            .map(|i| format!("line {}\n", i))
+            .collect::<String>()
+            .into_bytes();
+        let mut archive = Vec::new();
+        entry(&mut archive, "a.log", b'0', b"foo\n");
+        let a_end = archive.len();
+        entry(&mut archive, "b.log", b'0', &lines);
+        finish(&mut archive);
+        let compressed = compress(&archive);
+
+        let mut executor = InPlaceExecutor::new().expect("Never fails");
+        let (tx, rx) = mpsc::channel();
+        let watcher = TarGzipFileWatcher::new(&path, rx);
+        let a_path = member_path(&path, "a.log");
+        let b_path = member_path(&path, "b.log");
+
+        // Broken between the members
+        fs::write(&path, compress(&archive[..a_end + 100])).expect("Never fails");
+        let (a, watcher) = next(&mut executor, watcher);
+        assert_eq!((&a.path, &a.data[..], a.eof), (&a_path, &b"foo\n"[..], true));
+        let (broken, watcher) = next(&mut executor, watcher);
+        assert_eq!(broken.path, path);
+        assert!(broken.error.is_some());
+
+        // Broken in the stream of the second member
+        fs::write(&path, &compressed[..compressed.len() / 2]).expect("Never fails");
+        tx.send(FileUpdated::Modified).expect("Never fails");
+        let (cleared, watcher) = next(&mut executor, watcher);
+        assert_eq!(cleared.path, path);
+        assert!(cleared.error.is_none());
+        let (first, watcher) = next(&mut executor, watcher);
+        assert_eq!(first.path, b_path);
+        assert_eq!(first.offset, 0);
+        assert!(first.error.is_some());
+        assert!(!first.data.is_empty() && lines.starts_with(&first.data));
+
+        // Completed
+        fs::write(&path, &compressed).expect("Never fails");
+        tx.send(FileUpdated::Modified).expect("Never fails");
+        let (rest, watcher) = next(&mut executor, watcher);
+        assert_eq!(rest.path, b_path);
+        assert_eq!(rest.offset, first.data.len() as u64);
+        assert_eq!(rest.data, &lines[first.data.len()..]);
+        assert!(rest.eof);
+        assert!(!rest.reset);
+        assert!(rest.error.is_none());
+
+        // Replaced by a shorter archive
+        let mut archive = Vec::new();
+        entry(&mut archive, "c.log", b'0', b"bar\n");
+        finish(&mut archive);
+        fs::write(&path, compress(&archive)).expect("Never fails");
+        tx.send(FileUpdated::Modified).expect("Never fails");
+        let (c, _) = next(&mut executor, watcher);
+        let _ = fs::remove_file(&path);
+        assert_eq!(c.path, member_path(&path, "c.log"));
+        assert_eq!(c.data, b"bar\n");
+        assert!(c.reset);
+    }
+}